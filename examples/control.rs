@@ -1,6 +1,8 @@
 use clap::Parser;
 
+use gateman::config::Hardware;
 use gateman::drive::Drive;
+use gateman::motion::MotionProfile;
 use gateman::Result;
 
 use git_version::git_version;
@@ -40,13 +42,14 @@ struct Opts {
 async fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    let mut driver = Drive::new(
-        opts.at,
-        opts.en_pin,
-        opts.dir_pin,
-        opts.clock_pin,
-        opts.data_pin,
-    )?;
+    let hardware = Hardware {
+        en_pin: opts.en_pin,
+        dir_pin: opts.dir_pin,
+        clock_pin: opts.clock_pin,
+        data_pin: opts.data_pin,
+        ..Default::default()
+    };
+    let mut driver = Drive::new(opts.at, &hardware, MotionProfile::constant(opts.frequency))?;
 
     // todo;; this is not wired on the other end yet
     ctrlc::set_handler(|| {
@@ -0,0 +1,444 @@
+//! Mapping from the openings clients ask for to encoder counts.
+//!
+//! A calibration run measures the travel from the closed stop to full open and
+//! saves it, so openings can be given in percent or millimetres as well as in
+//! raw counts and the legacy units. Gates whose opening is not linear in counts
+//! can map percent, or opening area, through a piecewise-linear curve instead.
+
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::journal;
+use crate::Error::CalibrationError;
+use crate::Result;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    /// Encoder counts per unit of the legacy open command
+    pub counts_per_unit: isize,
+    /// Encoder counts from the closed stop to full open, replaced by a calibration run
+    pub travel: Option<isize>,
+    /// Millimetres the gate opens at full travel
+    pub stroke: Option<f64>,
+    /// Step rate in Hz during a calibration run
+    pub rate: f64,
+    /// Seconds allowed for a calibration run
+    pub timeout: u64,
+    /// File a calibration run is saved to, and loaded from at startup
+    pub path: Option<PathBuf>,
+    /// Counts at points of percent open, in place of a linear share of the travel
+    pub percent_curve: Option<Curve>,
+    /// Counts at points of opening area
+    pub area_curve: Option<Curve>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            counts_per_unit: 35,
+            travel: None,
+            stroke: None,
+            rate: 200.0,
+            timeout: 600,
+            path: None,
+            percent_curve: None,
+            area_curve: None,
+        }
+    }
+}
+
+/// What a calibration run saves
+#[derive(Serialize, Deserialize, Debug)]
+struct Saved {
+    travel: isize,
+}
+
+impl Calibration {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// Replace the configured travel with the one saved by the last calibration run, if any
+    pub fn load(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let saved: Saved = match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| self.error(e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(self.error(e)),
+        };
+        eprintln!("loaded calibration, travel {}", saved.travel);
+        self.travel = Some(saved.travel);
+        Ok(())
+    }
+
+    /// Use the travel measured by a calibration run, saving it if a path is configured
+    pub fn save(&mut self, travel: isize) -> Result<()> {
+        self.travel = Some(travel);
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let json = serde_json::to_vec(&Saved { travel }).map_err(|e| self.error(e))?;
+        journal::replace(path, &json).map_err(|e| self.error(e))
+    }
+
+    /// Encoder counts for an opening, None when the gate is not calibrated for its unit
+    pub fn counts(&self, opening: Opening) -> Option<isize> {
+        let counts = match opening {
+            Opening::Units(n) => (n as isize * self.counts_per_unit) as f64,
+            Opening::Counts(c) => c as f64,
+            Opening::Percent(p) => match &self.percent_curve {
+                Some(curve) => curve.counts(p),
+                None => self.travel? as f64 * p / 100.0,
+            },
+            Opening::Millimetres(mm) => self.travel? as f64 * mm / self.stroke?,
+            Opening::Area(a) => self.area_curve.as_ref()?.counts(a),
+        };
+        Some(counts.round() as isize)
+    }
+
+    /// Percent open at a position, None when the gate is not calibrated
+    pub fn percent(&self, position: isize) -> Option<f64> {
+        match &self.percent_curve {
+            Some(curve) => Some(curve.opening(position)),
+            None => Some(position as f64 * 100.0 / self.travel? as f64),
+        }
+    }
+
+    /// Millimetres open at a position, None without a travel and stroke
+    pub fn millimetres(&self, position: isize) -> Option<f64> {
+        Some(position as f64 * self.stroke? / self.travel? as f64)
+    }
+
+    /// Opening area at a position, None without an area curve
+    pub fn area(&self, position: isize) -> Option<f64> {
+        Some(self.area_curve.as_ref()?.opening(position))
+    }
+
+    fn error(&self, e: impl Display) -> crate::Error {
+        let path = self.path.as_deref().unwrap_or_else(|| "".as_ref());
+        CalibrationError(format!("{}: {}", path.display(), e))
+    }
+}
+
+/// Piecewise-linear mapping between an opening and encoder counts,
+/// increasing in both so it can be applied in either direction
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Vec<Point>")]
+pub struct Curve {
+    points: Vec<Point>,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Point {
+    pub opening: f64,
+    pub counts: isize,
+}
+
+impl Curve {
+    /// Encoder counts at an opening, clamped to the ends of the curve
+    pub fn counts(&self, opening: f64) -> f64 {
+        interpolate(&self.points, opening, |p| p.opening, |p| p.counts as f64)
+    }
+
+    /// Opening at a position, clamped to the ends of the curve
+    pub fn opening(&self, counts: isize) -> f64 {
+        interpolate(
+            &self.points,
+            counts as f64,
+            |p| p.counts as f64,
+            |p| p.opening,
+        )
+    }
+
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+}
+
+impl TryFrom<Vec<Point>> for Curve {
+    type Error = String;
+
+    fn try_from(points: Vec<Point>) -> std::result::Result<Self, String> {
+        if points.len() < 2 {
+            return Err("a curve needs at least two points".to_string());
+        }
+        for pair in points.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if !(b.opening > a.opening && b.counts > a.counts) {
+                return Err(format!(
+                    "curve is not increasing from {} at {} counts to {} at {} counts",
+                    a.opening, a.counts, b.opening, b.counts
+                ));
+            }
+        }
+        Ok(Curve { points })
+    }
+}
+
+// y at x on the line through the pair of points around x
+fn interpolate(
+    points: &[Point],
+    x: f64,
+    fx: impl Fn(&Point) -> f64,
+    fy: impl Fn(&Point) -> f64,
+) -> f64 {
+    let i = points
+        .partition_point(|p| fx(p) < x)
+        .clamp(1, points.len() - 1);
+    let (a, b) = (&points[i - 1], &points[i]);
+    let t = ((x - fx(a)) / (fx(b) - fx(a))).clamp(0.0, 1.0);
+    fy(a) + t * (fy(b) - fy(a))
+}
+
+/// How far to open the gate
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(try_from = "Target", into = "Target")]
+pub enum Opening {
+    /// Units of `counts_per_unit`, as sent by legacy clients
+    Units(u8),
+    Percent(f64),
+    Millimetres(f64),
+    /// Opening area, in the units of the area curve
+    Area(f64),
+    Counts(isize),
+}
+
+impl Display for Opening {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Opening::Units(n) => write!(f, "{}", n),
+            Opening::Percent(p) => write!(f, "{}%", p),
+            Opening::Millimetres(mm) => write!(f, "{}mm", mm),
+            Opening::Area(a) => write!(f, "area {}", a),
+            Opening::Counts(c) => write!(f, "{} counts", c),
+        }
+    }
+}
+
+/// An opening as it appears in requests
+#[derive(Serialize, Deserialize)]
+struct Target {
+    target: f64,
+    #[serde(default)]
+    unit: Unit,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum Unit {
+    #[default]
+    Units,
+    Percent,
+    Mm,
+    Area,
+    Counts,
+}
+
+impl From<Opening> for Target {
+    fn from(opening: Opening) -> Self {
+        let (target, unit) = match opening {
+            Opening::Units(n) => (n as f64, Unit::Units),
+            Opening::Percent(p) => (p, Unit::Percent),
+            Opening::Millimetres(mm) => (mm, Unit::Mm),
+            Opening::Area(a) => (a, Unit::Area),
+            Opening::Counts(c) => (c as f64, Unit::Counts),
+        };
+        Target { target, unit }
+    }
+}
+
+impl TryFrom<Target> for Opening {
+    type Error = String;
+
+    fn try_from(t: Target) -> std::result::Result<Self, String> {
+        let whole = t.target.fract() == 0.0;
+        match t.unit {
+            Unit::Units if whole && (0.0..=u8::MAX as f64).contains(&t.target) => {
+                Ok(Opening::Units(t.target as u8))
+            }
+            Unit::Units => Err(format!("{} is not a whole number of units", t.target)),
+            Unit::Percent if (0.0..=100.0).contains(&t.target) => Ok(Opening::Percent(t.target)),
+            Unit::Percent => Err(format!("{}% is outside 0..100", t.target)),
+            Unit::Mm if t.target >= 0.0 => Ok(Opening::Millimetres(t.target)),
+            Unit::Mm => Err(format!("{}mm is negative", t.target)),
+            Unit::Area if t.target >= 0.0 => Ok(Opening::Area(t.target)),
+            Unit::Area => Err(format!("area {} is negative", t.target)),
+            Unit::Counts if whole => Ok(Opening::Counts(t.target as isize)),
+            Unit::Counts => Err(format!("{} is not a whole number of counts", t.target)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an opening as it appears in the config
+    #[derive(Deserialize)]
+    struct Section {
+        opening: Opening,
+    }
+
+    fn curve(points: &[(f64, isize)]) -> std::result::Result<Curve, String> {
+        let points = points
+            .iter()
+            .map(|&(opening, counts)| Point { opening, counts });
+        Curve::try_from(points.collect::<Vec<_>>())
+    }
+
+    fn opening(json: &str) -> std::result::Result<Opening, String> {
+        serde_json::from_str(json).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn curve_must_increase_in_both() {
+        assert!(curve(&[(0.0, 0), (50.0, 1000), (100.0, 4000)]).is_ok());
+        assert!(curve(&[(0.0, 0)]).is_err());
+        assert!(curve(&[]).is_err());
+        // flat or falling in either opening or counts
+        assert!(curve(&[(0.0, 0), (50.0, 1000), (50.0, 2000)]).is_err());
+        assert!(curve(&[(0.0, 0), (50.0, 1000), (100.0, 1000)]).is_err());
+        assert!(curve(&[(0.0, 0), (60.0, 1000), (50.0, 2000)]).is_err());
+        assert_eq!(
+            curve(&[(0.0, 0), (50.0, 1000), (100.0, 900)]).unwrap_err(),
+            "curve is not increasing from 50 at 1000 counts to 100 at 900 counts"
+        );
+        let error = toml::from_str::<Calibration>(
+            "percent_curve = [{ opening = 0, counts = 10 }, { opening = 10, counts = 5 }]",
+        );
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn curve_interpolates_both_ways() {
+        let c = curve(&[(0.0, 0), (50.0, 1000), (100.0, 4000)]).unwrap();
+        // at the points
+        assert_eq!(c.counts(0.0), 0.0);
+        assert_eq!(c.counts(50.0), 1000.0);
+        assert_eq!(c.counts(100.0), 4000.0);
+        assert_eq!(c.opening(1000), 50.0);
+        // between them
+        assert_eq!(c.counts(25.0), 500.0);
+        assert_eq!(c.counts(75.0), 2500.0);
+        assert_eq!(c.opening(2500), 75.0);
+        assert_eq!(c.opening(c.counts(62.5).round() as isize), 62.5);
+        // past either end
+        assert_eq!(c.counts(-10.0), 0.0);
+        assert_eq!(c.counts(120.0), 4000.0);
+        assert_eq!(c.opening(-100), 0.0);
+        assert_eq!(c.opening(5000), 100.0);
+    }
+
+    #[test]
+    fn curve_need_not_start_at_zero() {
+        let c = curve(&[(10.0, 200), (20.0, 400)]).unwrap();
+        assert_eq!(c.counts(0.0), 200.0);
+        assert_eq!(c.counts(15.0), 300.0);
+        assert_eq!(c.opening(100), 10.0);
+    }
+
+    #[test]
+    fn openings_by_unit() {
+        assert_eq!(
+            opening(r#"{"target": 20, "unit": "percent"}"#),
+            Ok(Opening::Percent(20.0))
+        );
+        assert_eq!(
+            opening(r#"{"target": 12.5, "unit": "mm"}"#),
+            Ok(Opening::Millimetres(12.5))
+        );
+        assert_eq!(
+            opening(r#"{"target": 0.3, "unit": "area"}"#),
+            Ok(Opening::Area(0.3))
+        );
+        assert_eq!(
+            opening(r#"{"target": 1200, "unit": "counts"}"#),
+            Ok(Opening::Counts(1200))
+        );
+        assert_eq!(
+            opening(r#"{"target": -50, "unit": "counts"}"#),
+            Ok(Opening::Counts(-50))
+        );
+        // a bare number is in legacy units
+        assert_eq!(opening(r#"{"target": 40}"#), Ok(Opening::Units(40)));
+        assert_eq!(
+            opening(r#"{"target": 40, "unit": "units"}"#),
+            Ok(Opening::Units(40))
+        );
+        let toml: Section = toml::from_str("opening = { target = 50, unit = \"percent\" }")
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(toml.opening, Opening::Percent(50.0));
+    }
+
+    #[test]
+    fn openings_out_of_range_are_refused() {
+        for json in [
+            r#"{"target": 101, "unit": "percent"}"#,
+            r#"{"target": -1, "unit": "percent"}"#,
+            r#"{"target": -1, "unit": "mm"}"#,
+            r#"{"target": -1, "unit": "area"}"#,
+            r#"{"target": 12.5, "unit": "counts"}"#,
+            r#"{"target": 2.5}"#,
+            r#"{"target": 256}"#,
+            r#"{"target": -1}"#,
+            r#"{"target": 20, "unit": "inches"}"#,
+            r#"{"target": "20%"}"#,
+            r#"{"unit": "percent"}"#,
+        ] {
+            assert!(opening(json).is_err(), "{} parsed", json);
+        }
+        let error = opening(r#"{"target": 120, "unit": "percent"}"#).unwrap_err();
+        assert!(error.starts_with("120% is outside 0..100"), "{}", error);
+    }
+
+    #[test]
+    fn openings_round_trip() {
+        for o in [
+            Opening::Units(7),
+            Opening::Percent(33.5),
+            Opening::Millimetres(80.0),
+            Opening::Area(1.25),
+            Opening::Counts(-12),
+        ] {
+            assert_eq!(opening(&serde_json::to_string(&o).unwrap()), Ok(o));
+        }
+    }
+
+    #[test]
+    fn counts_for_each_unit() {
+        let mut calibration = Calibration::default();
+        assert_eq!(calibration.counts(Opening::Units(4)), Some(140));
+        assert_eq!(calibration.counts(Opening::Counts(1234)), Some(1234));
+        // the rest need a calibration
+        assert_eq!(calibration.counts(Opening::Percent(50.0)), None);
+        assert_eq!(calibration.counts(Opening::Millimetres(10.0)), None);
+        assert_eq!(calibration.counts(Opening::Area(1.0)), None);
+        assert_eq!(calibration.percent(100), None);
+
+        calibration.travel = Some(4000);
+        assert_eq!(calibration.counts(Opening::Percent(25.0)), Some(1000));
+        assert_eq!(calibration.percent(3000), Some(75.0));
+        assert_eq!(calibration.counts(Opening::Millimetres(10.0)), None);
+        calibration.stroke = Some(200.0);
+        assert_eq!(calibration.counts(Opening::Millimetres(50.0)), Some(1000));
+        assert_eq!(calibration.millimetres(2000), Some(100.0));
+
+        // a percent curve takes over from the travel
+        calibration.percent_curve = Some(curve(&[(0.0, 0), (50.0, 1000), (100.0, 4000)]).unwrap());
+        assert_eq!(calibration.counts(Opening::Percent(25.0)), Some(500));
+        assert_eq!(calibration.percent(2500), Some(75.0));
+        calibration.area_curve = Some(curve(&[(0.0, 0), (2.0, 4000)]).unwrap());
+        assert_eq!(calibration.counts(Opening::Area(0.5)), Some(1000));
+        assert_eq!(calibration.area(3000), Some(1.5));
+    }
+}
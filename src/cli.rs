@@ -1,41 +1,111 @@
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use clap::Parser;
 use git_version::git_version;
+use serde::Deserialize;
+
+use crate::motion::Ramp;
 
 const GIT_VERSION: &str = git_version!();
 
 /// Websocket controller for gate.
+///
+/// Options given here override the configuration file.
 #[derive(Parser)]
 #[clap(name = "Gateman", version = GIT_VERSION)]
 pub struct Opts {
-    #[clap(long, default_value = "127.0.0.1")]
-    pub address: NetInterface,
+    /// TOML configuration file
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    #[clap(long)]
+    pub address: Option<NetInterface>,
+
+    #[clap(long)]
+    pub port: Option<u16>,
+
+    /// Cruising step rate in Hz
+    #[clap(short, long)]
+    pub frequency: Option<f64>,
+
+    /// Step rate in Hz at the start and end of a move
+    #[clap(long)]
+    pub start_frequency: Option<f64>,
+
+    /// Step acceleration in steps/s²
+    #[clap(long)]
+    pub acceleration: Option<f64>,
+
+    /// Motor steps per encoder count
+    #[clap(long)]
+    pub steps_per_count: Option<f64>,
 
-    #[clap(long, default_value = "9000")]
-    pub port: u16,
+    /// Ramp shape, trapezoidal or s-curve
+    #[clap(long)]
+    pub ramp: Option<Ramp>,
 
-    #[clap(short, long, default_value = "500")]
-    pub frequency: f64,
+    #[clap(long)]
+    pub clock_pin: Option<u8>,
 
-    #[clap(long, default_value = "23")]
-    pub clock_pin: u8,
+    #[clap(long)]
+    pub data_pin: Option<u8>,
 
-    #[clap(long, default_value = "24")]
-    pub data_pin: u8,
+    #[clap(long)]
+    pub en_pin: Option<u8>,
 
-    #[clap(long, default_value = "5")]
-    pub en_pin: u8,
+    #[clap(long)]
+    pub dir_pin: Option<u8>,
 
-    #[clap(long, default_value = "6")]
-    pub dir_pin: u8,
+    /// Used to zero the encoder of a single gate, overriding the position journal
+    #[clap(long)]
+    pub at: Option<isize>,
 
-    /// Used to zero the encoder
-    #[clap(long, default_value = "0")]
-    pub at: isize,
+    /// File the position of a single gate is journaled to
+    #[clap(long)]
+    pub journal: Option<PathBuf>,
+
+    /// Run against a simulated gate instead of the GPIO hardware
+    #[clap(long)]
+    pub simulate: bool,
+
+    /// Simulated travel from closed to fully open, in encoder counts
+    #[clap(long)]
+    pub sim_travel: Option<isize>,
+
+    /// Simulated encoder counts per motor step
+    #[clap(long)]
+    pub sim_counts_per_step: Option<f64>,
+
+    /// Fraction of simulated steps lost to slip
+    #[clap(long)]
+    pub sim_slip: Option<f64>,
+
+    /// Simulated position where the gate jams
+    #[clap(long)]
+    pub sim_stall_at: Option<isize>,
 }
 
-#[derive(Debug)]
+impl Opts {
+    /// The options given that name the hardware, position or files of one gate,
+    /// which cannot be right for every gate when there are several
+    pub fn single_gate(&self) -> Vec<&'static str> {
+        [
+            ("--clock-pin", self.clock_pin.is_some()),
+            ("--data-pin", self.data_pin.is_some()),
+            ("--en-pin", self.en_pin.is_some()),
+            ("--dir-pin", self.dir_pin.is_some()),
+            ("--at", self.at.is_some()),
+            ("--journal", self.journal.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(option, given)| given.then_some(option))
+        .collect()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(try_from = "String")]
 pub enum NetInterface {
     Loopback,
     OOOO,
@@ -53,6 +123,14 @@ impl FromStr for NetInterface {
     }
 }
 
+impl TryFrom<String> for NetInterface {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
 impl From<NetInterface> for [u8; 4] {
     fn from(i: NetInterface) -> Self {
         match i {
@@ -61,3 +139,27 @@ impl From<NetInterface> for [u8; 4] {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single_gate(args: &[&str]) -> Vec<&'static str> {
+        Opts::try_parse_from([&["gateman"], args].concat())
+            .unwrap()
+            .single_gate()
+    }
+
+    #[test]
+    fn options_for_one_gate_are_named() {
+        assert!(single_gate(&["--simulate", "--port", "9000", "-f", "800"]).is_empty());
+        assert_eq!(
+            single_gate(&["--journal", "/tmp/gate", "--clock-pin", "5", "--at=-3"]),
+            ["--clock-pin", "--at", "--journal"]
+        );
+        assert_eq!(
+            single_gate(&["--data-pin", "6", "--en-pin", "7", "--dir-pin", "8"]),
+            ["--data-pin", "--en-pin", "--dir-pin"]
+        );
+    }
+}
@@ -0,0 +1,1203 @@
+//! Daemon configuration, loaded from a TOML file and overridden from the command line.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use toml::value::Table;
+use toml::Value;
+
+use crate::calibration::Calibration;
+use crate::cli::{NetInterface, Opts};
+use crate::delivery::DeliveryConfig;
+use crate::drive::SoftLimits;
+use crate::flow::FlowConfig;
+use crate::hal::sim::SimConfig;
+use crate::hydraulics::{GateGeometry, HydraulicsConfig};
+use crate::interlock::Rule;
+use crate::journal::Journal;
+use crate::level::{LevelConfig, LevelInput};
+use crate::motion::MotionProfile;
+use crate::recipe::Recipe;
+use crate::schedule::ScheduleConfig;
+use crate::Error::ConfigError;
+use crate::Result;
+
+/// Highest BCM GPIO number on the Pi header
+const MAX_GPIO: u8 = 27;
+
+/// Highest step rate the driver is expected to take
+const MAX_STEP_RATE: f64 = 100_000.0;
+
+/// Name of the gate when no `[gates]` are configured
+pub const DEFAULT_GATE: &str = "gate";
+
+/// Sections of the file that belong to the daemon, every other section is shared by the gates
+const DAEMON_SECTIONS: [&str; 4] = ["network", "schedule", "recipes", "interlocks"];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: Network,
+    pub schedule: ScheduleConfig,
+    /// Recipes that can be run by name
+    pub recipes: BTreeMap<String, Recipe>,
+    /// Rules between the gates, checked before each gate moves
+    pub interlocks: Vec<Rule>,
+    /// Gates by name, read from the shared sections and each `[gates.<name>]`
+    #[serde(skip)]
+    pub gates: BTreeMap<String, GateConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: Network::default(),
+            schedule: ScheduleConfig::default(),
+            recipes: BTreeMap::new(),
+            interlocks: Vec::new(),
+            gates: BTreeMap::from([(DEFAULT_GATE.to_string(), GateConfig::default())]),
+        }
+    }
+}
+
+/// Pins, calibration and control of one gate
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GateConfig {
+    pub hardware: Hardware,
+    pub motion: MotionProfile,
+    pub calibration: Calibration,
+    pub safety: Safety,
+    pub limits: SoftLimits,
+    pub stall: StallDetection,
+    pub homing: Homing,
+    pub flow: FlowConfig,
+    pub delivery: DeliveryConfig,
+    pub level: LevelConfig,
+    pub hydraulics: HydraulicsConfig,
+    pub journal: JournalConfig,
+    pub simulation: SimConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Network {
+    pub address: NetInterface,
+    pub port: u16,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            address: NetInterface::Loopback,
+            port: 9000,
+        }
+    }
+}
+
+/// GPIO pins (BCM numbering) and PWM channel wired to the driver and encoder
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hardware {
+    pub en_pin: u8,
+    pub dir_pin: u8,
+    pub clock_pin: u8,
+    pub data_pin: u8,
+    /// PWM channel, 0 or 1
+    pub pwm_channel: u8,
+    /// Pin the PWM channel is routed to by the boot overlay
+    pub pwm_pin: u8,
+    /// Home switch at the closed end of travel
+    pub home_pin: Option<u8>,
+    /// Home switch reads high when active, otherwise it pulls the pin low
+    pub home_active_high: bool,
+    /// Normally closed end-stop at the open end of travel
+    pub open_limit_pin: Option<u8>,
+    /// Normally closed end-stop at the closed end of travel
+    pub close_limit_pin: Option<u8>,
+}
+
+impl Default for Hardware {
+    fn default() -> Self {
+        Self {
+            en_pin: 5,
+            dir_pin: 6,
+            clock_pin: 23,
+            data_pin: 24,
+            pwm_channel: 0,
+            pwm_pin: 12,
+            home_pin: None,
+            home_active_high: false,
+            open_limit_pin: None,
+            close_limit_pin: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Safety {
+    /// Seconds without a command before the failsafe fires
+    pub keepalive_timeout: u64,
+    /// Seconds without a message before a websocket client is dropped, which fires the
+    /// failsafe once no other client is connected
+    pub client_timeout: u64,
+    /// What the gate does when the failsafe fires
+    pub failsafe: Failsafe,
+}
+
+impl Default for Safety {
+    fn default() -> Self {
+        Self {
+            keepalive_timeout: 5,
+            client_timeout: 50,
+            failsafe: Failsafe::Close,
+        }
+    }
+}
+
+/// Action taken when the link to the clients of a gate is lost
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case", try_from = "FailsafeSetting")]
+pub enum Failsafe {
+    /// Stay where the gate is
+    Hold,
+    Close,
+    /// Open to the soft limit of travel
+    OpenFully,
+    /// Go to a position in encoder counts
+    Position(isize),
+}
+
+// a failsafe as written in the file, read without serde's enum support as the
+// gate sections are read from a value, which cannot hold an enum as a table
+#[derive(Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum FailsafeSetting {
+    Action(String),
+    Position { position: isize },
+}
+
+impl TryFrom<FailsafeSetting> for Failsafe {
+    type Error = String;
+
+    fn try_from(setting: FailsafeSetting) -> std::result::Result<Self, String> {
+        match setting {
+            FailsafeSetting::Action(action) => match action.as_str() {
+                "hold" => Ok(Failsafe::Hold),
+                "close" => Ok(Failsafe::Close),
+                "open-fully" => Ok(Failsafe::OpenFully),
+                other => Err(format!(
+                    "unknown failsafe `{}`, expected hold, close, open-fully or {{ position = <counts> }}",
+                    other
+                )),
+            },
+            FailsafeSetting::Position { position } => Ok(Failsafe::Position(position)),
+        }
+    }
+}
+
+impl Display for Failsafe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failsafe::Hold => f.write_str("hold"),
+            Failsafe::Close => f.write_str("close"),
+            Failsafe::OpenFully => f.write_str("open-fully"),
+            Failsafe::Position(p) => write!(f, "position:{}", p),
+        }
+    }
+}
+
+impl Safety {
+    pub fn keepalive(&self) -> Duration {
+        Duration::from_secs(self.keepalive_timeout)
+    }
+
+    pub fn client(&self) -> Duration {
+        Duration::from_secs(self.client_timeout)
+    }
+}
+
+/// Watchdog comparing encoder progress against the steps sent during a move
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct StallDetection {
+    pub enabled: bool,
+    /// Milliseconds of motion compared at a time
+    pub window: u64,
+    /// Fraction of the expected encoder counts that must arrive within a window
+    pub min_ratio: f64,
+}
+
+impl Default for StallDetection {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: 500,
+            min_ratio: 0.5,
+        }
+    }
+}
+
+impl StallDetection {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window)
+    }
+}
+
+/// Parameters of the homing routine
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Homing {
+    /// Step rate in Hz while seeking and backing off the switch
+    pub seek_rate: f64,
+    /// Step rate in Hz for the final approach
+    pub creep_rate: f64,
+    /// Encoder counts to back off past the switch release
+    pub backoff: isize,
+    /// Position set when the switch is found
+    pub offset: isize,
+    /// Home when the daemon starts
+    pub on_startup: bool,
+    /// Seconds allowed for the whole routine
+    pub timeout: u64,
+}
+
+impl Default for Homing {
+    fn default() -> Self {
+        Self {
+            seek_rate: 200.0,
+            creep_rate: 50.0,
+            backoff: 100,
+            offset: 0,
+            on_startup: false,
+            timeout: 300,
+        }
+    }
+}
+
+impl Homing {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalConfig {
+    /// File the position is journaled to, disabled when not set
+    pub path: Option<PathBuf>,
+    /// Milliseconds between journal writes during a move
+    pub interval: u64,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            interval: 1000,
+        }
+    }
+}
+
+impl JournalConfig {
+    pub fn journal(&self) -> Option<Journal> {
+        self.path
+            .as_ref()
+            .map(|p| Journal::new(p, Duration::from_millis(self.interval)))
+    }
+}
+
+impl Config {
+    /// Load the configuration file if one is given, otherwise the defaults
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))?;
+                Self::parse(&text).map_err(|e| ConfigError(format!("{}: {}", path.display(), e)))
+            }
+            None => Ok(Self::default()),
+        }
+    }
+
+    // the daemon sections, then each gate from the shared sections with its own
+    // `[gates.<name>]` keys laid over them, or a single gate from the shared sections
+    fn parse(text: &str) -> std::result::Result<Self, String> {
+        let mut table: Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        let gates = table.remove("gates");
+        let (daemon, shared): (Table, Table) = table
+            .into_iter()
+            .partition(|(section, _)| DAEMON_SECTIONS.contains(&section.as_str()));
+
+        let mut config: Config = Value::Table(daemon).try_into().map_err(|e| e.to_string())?;
+        config.gates = match gates {
+            Some(gates) => {
+                let gates: BTreeMap<String, Table> =
+                    gates.try_into().map_err(|e| format!("gates: {}", e))?;
+                gates
+                    .into_iter()
+                    .map(|(name, own)| {
+                        let gate = Value::Table(overlay(&shared, own))
+                            .try_into()
+                            .map_err(|e| format!("gate {}: {}", name, e))?;
+                        Ok((name, gate))
+                    })
+                    .collect::<std::result::Result<_, String>>()?
+            }
+            None => {
+                let gate = Value::Table(shared).try_into().map_err(|e| e.to_string())?;
+                BTreeMap::from([(DEFAULT_GATE.to_string(), gate)])
+            }
+        };
+        Ok(config)
+    }
+
+    /// Apply the options given on the command line, the gate options to every gate
+    pub fn merge(mut self, opts: &Opts) -> Self {
+        set(&mut self.network.address, &opts.address);
+        set(&mut self.network.port, &opts.port);
+        self.gates = self
+            .gates
+            .into_iter()
+            .map(|(name, gate)| (name, gate.merge(opts)))
+            .collect();
+        self
+    }
+
+    /// Check the configuration of every gate, and that the gates do not share
+    /// hardware or files, and when running on hardware that the PWM channels are available
+    pub fn validate(&self, simulate: bool) -> Result<()> {
+        if self.gates.is_empty() {
+            return invalid("no gates are configured".to_string());
+        }
+        for name in self.gates.keys() {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if name.is_empty() || !name.chars().all(valid) {
+                return invalid(format!(
+                    "gate name `{}` may only use letters, digits, - and _",
+                    name
+                ));
+            }
+        }
+
+        // simulated gates have no hardware to share
+        if !simulate {
+            let mut pins = HashMap::new();
+            let mut channels = HashMap::new();
+            let mut adc = HashMap::new();
+            for (gate, config) in &self.gates {
+                for (name, pin) in config.pins() {
+                    match pins.insert(pin, (gate, name)) {
+                        // the ADC is shared by the sensors on it
+                        Some((_, other)) if other == name && name.starts_with("adc ") => {}
+                        Some((other, _)) if other != gate => {
+                            return invalid(format!(
+                                "gates {} and {} both use pin {}",
+                                other, gate, pin
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+                let channel = config.hardware.pwm_channel;
+                if let Some(other) = channels.insert(channel, gate) {
+                    return invalid(format!(
+                        "gates {} and {} both use PWM{}, the Pi has PWM0 and PWM1",
+                        other, gate, channel
+                    ));
+                }
+                for sensor in [config.level.sensor, config.hydraulics.downstream]
+                    .iter()
+                    .flatten()
+                {
+                    if let LevelInput::Adc { channel, .. } = sensor {
+                        match adc.insert(*channel, gate) {
+                            Some(other) if other != gate => {
+                                return invalid(format!(
+                                    "gates {} and {} both use adc channel {}",
+                                    other, gate, channel
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        for (name, gate) in &self.gates {
+            gate.validate(simulate).map_err(|e| match e {
+                ConfigError(message) if self.gates.len() > 1 => {
+                    ConfigError(format!("gate {}: {}", name, message))
+                }
+                e => e,
+            })?;
+        }
+
+        for (name, recipe) in &self.recipes {
+            if let Err(e) = recipe.check() {
+                return invalid(format!("recipe {}: {}", name, e));
+            }
+        }
+
+        for (i, rule) in self.interlocks.iter().enumerate() {
+            let unknown = [&rule.gate, &rule.requires]
+                .into_iter()
+                .find(|gate| !self.gates.contains_key(*gate));
+            if let Some(gate) = unknown {
+                return invalid(format!("interlock {}: no gate named {}", i, gate));
+            }
+            if let Err(e) = rule.check() {
+                return invalid(format!("interlock {}: {}", i, e));
+            }
+        }
+
+        let mut files = HashMap::new();
+        let paths = self.gates.iter().flat_map(|(gate, config)| {
+            [
+                ("journal", &config.journal.path),
+                ("delivery", &config.delivery.path),
+                ("calibration", &config.calibration.path),
+            ]
+            .map(|(name, path)| (format!("{} {}", gate, name), path))
+        });
+        for (name, path) in paths.chain([("schedule".to_string(), &self.schedule.path)]) {
+            let path = match path {
+                Some(path) => path,
+                None => continue,
+            };
+            if let Some(other) = files.insert(path, name.clone()) {
+                return invalid(format!(
+                    "{} and {} both use {}",
+                    other,
+                    name,
+                    path.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl GateConfig {
+    /// Apply the gate options given on the command line
+    pub fn merge(mut self, opts: &Opts) -> Self {
+        set(&mut self.hardware.en_pin, &opts.en_pin);
+        set(&mut self.hardware.dir_pin, &opts.dir_pin);
+        set(&mut self.hardware.clock_pin, &opts.clock_pin);
+        set(&mut self.hardware.data_pin, &opts.data_pin);
+        set(&mut self.motion.max_rate, &opts.frequency);
+        // moves start, and homing runs, no faster than a lower cruising rate
+        if let Some(max) = opts.frequency {
+            self.motion.start_rate = self.motion.start_rate.min(max);
+            self.homing.seek_rate = self.homing.seek_rate.min(max);
+            self.homing.creep_rate = self.homing.creep_rate.min(max);
+        }
+        set(&mut self.motion.start_rate, &opts.start_frequency);
+        set(&mut self.motion.acceleration, &opts.acceleration);
+        set(&mut self.motion.steps_per_count, &opts.steps_per_count);
+        set(&mut self.motion.ramp, &opts.ramp);
+        if opts.journal.is_some() {
+            self.journal.path = opts.journal.clone();
+        }
+        set(&mut self.simulation.max, &opts.sim_travel);
+        set(
+            &mut self.simulation.counts_per_step,
+            &opts.sim_counts_per_step,
+        );
+        set(&mut self.simulation.slip, &opts.sim_slip);
+        if opts.sim_stall_at.is_some() {
+            self.simulation.stall_at = opts.sim_stall_at;
+        }
+        self
+    }
+
+    /// GPIO pins taken by the gate
+    pub fn pins(&self) -> Vec<(&'static str, u8)> {
+        let hw = &self.hardware;
+        let optional = [
+            ("home_pin", hw.home_pin),
+            ("open_limit_pin", hw.open_limit_pin),
+            ("close_limit_pin", hw.close_limit_pin),
+            ("flow pin", self.flow.pin),
+        ];
+        [
+            ("en_pin", hw.en_pin),
+            ("dir_pin", hw.dir_pin),
+            ("clock_pin", hw.clock_pin),
+            ("data_pin", hw.data_pin),
+            ("pwm_pin", hw.pwm_pin),
+        ]
+        .into_iter()
+        .chain(optional.into_iter().filter_map(|(n, p)| Some((n, p?))))
+        .chain(self.level.pins())
+        .chain(self.hydraulics.pins(&self.level))
+        .collect()
+    }
+
+    /// Check the configuration for conflicts and out of range values,
+    /// and when running on hardware that the PWM channel is available
+    pub fn validate(&self, simulate: bool) -> Result<()> {
+        let hw = &self.hardware;
+        let mut pins = HashMap::new();
+        for (name, pin) in self.pins() {
+            if pin > MAX_GPIO {
+                return invalid(format!("{} {} is not a GPIO pin", name, pin));
+            }
+            if let Some(other) = pins.insert(pin, name) {
+                return invalid(format!("{} and {} both use pin {}", other, name, pin));
+            }
+        }
+
+        let channel_pins: &[u8] = match hw.pwm_channel {
+            0 => &[12, 18],
+            1 => &[13, 19],
+            n => return invalid(format!("pwm_channel {} does not exist, use 0 or 1", n)),
+        };
+        if !channel_pins.contains(&hw.pwm_pin) {
+            return invalid(format!(
+                "pwm_pin {} cannot carry PWM{}, use one of {:?}",
+                hw.pwm_pin, hw.pwm_channel, channel_pins
+            ));
+        }
+
+        let m = &self.motion;
+        if !(m.start_rate > 0.0 && m.start_rate <= m.max_rate) {
+            return invalid(format!(
+                "start_rate {} must be above 0 and no more than max_rate {}",
+                m.start_rate, m.max_rate
+            ));
+        }
+        if m.max_rate > MAX_STEP_RATE {
+            return invalid(format!(
+                "max_rate {} is above the limit of {}",
+                m.max_rate, MAX_STEP_RATE
+            ));
+        }
+        if m.acceleration < 0.0 {
+            return invalid(format!("acceleration {} is negative", m.acceleration));
+        }
+        if m.steps_per_count <= 0.0 {
+            return invalid(format!(
+                "steps_per_count {} must be above 0",
+                m.steps_per_count
+            ));
+        }
+
+        let calibration = &self.calibration;
+        if calibration.counts_per_unit <= 0 {
+            return invalid(format!(
+                "counts_per_unit {} must be above 0",
+                calibration.counts_per_unit
+            ));
+        }
+        if calibration.travel.is_some_and(|t| t <= 0) {
+            return invalid("calibration travel must be above 0".to_string());
+        }
+        if calibration.stroke.is_some_and(|s| s <= 0.0) {
+            return invalid("calibration stroke must be above 0".to_string());
+        }
+        if !(calibration.rate > 0.0 && calibration.rate <= MAX_STEP_RATE) {
+            return invalid(format!(
+                "calibration rate {} must be above 0 and at most {}",
+                calibration.rate, MAX_STEP_RATE
+            ));
+        }
+        if let Some(curve) = &calibration.percent_curve {
+            let points = curve.points();
+            let (first, last) = (points[0].opening, points[points.len() - 1].opening);
+            if first < 0.0 || last > 100.0 {
+                return invalid(format!(
+                    "percent curve {}..{} is outside 0..100",
+                    first, last
+                ));
+            }
+        }
+        if calibration.timeout == 0 {
+            return invalid("calibration timeout must be at least 1 second".to_string());
+        }
+
+        if self.safety.keepalive_timeout == 0 || self.safety.client_timeout == 0 {
+            return invalid("safety timeouts must be at least 1 second".to_string());
+        }
+
+        if let SoftLimits {
+            min: Some(min),
+            max: Some(max),
+        } = self.limits
+        {
+            if min >= max {
+                return invalid(format!("soft limits {}..{} are empty", min, max));
+            }
+        }
+        match self.safety.failsafe {
+            Failsafe::OpenFully
+                if self.limits.max.is_none()
+                    && calibration.travel.is_none()
+                    && calibration.path.is_none() =>
+            {
+                return invalid(
+                    "failsafe open-fully needs a [limits] max or a calibration".to_string(),
+                );
+            }
+            Failsafe::Position(p)
+                if self.limits.min.is_some_and(|min| p < min)
+                    || self.limits.max.is_some_and(|max| p > max) =>
+            {
+                return invalid(format!(
+                    "failsafe position {} is outside the soft limits",
+                    p
+                ));
+            }
+            _ => {}
+        }
+
+        let stall = &self.stall;
+        if stall.window == 0 {
+            return invalid("stall window must be above 0".into());
+        }
+        if !(stall.min_ratio > 0.0 && stall.min_ratio <= 1.0) {
+            return invalid(format!(
+                "stall min_ratio {} must be above 0 and at most 1",
+                stall.min_ratio
+            ));
+        }
+
+        let homing = &self.homing;
+        if !(homing.creep_rate > 0.0 && homing.creep_rate <= homing.seek_rate) {
+            return invalid(format!(
+                "homing creep_rate {} must be above 0 and no more than seek_rate {}",
+                homing.creep_rate, homing.seek_rate
+            ));
+        }
+        if homing.seek_rate > m.max_rate {
+            return invalid(format!(
+                "homing seek_rate {} is above max_rate {}",
+                homing.seek_rate, m.max_rate
+            ));
+        }
+        if homing.backoff < 0 || homing.timeout == 0 {
+            return invalid("homing backoff and timeout must not be negative or zero".to_string());
+        }
+        if homing.on_startup && hw.home_pin.is_none() && !simulate {
+            return invalid("homing on_startup requires a home_pin".to_string());
+        }
+
+        let flow = &self.flow;
+        if flow.k_factor <= 0.0 {
+            return invalid(format!("flow k_factor {} must be above 0", flow.k_factor));
+        }
+        if flow.interval == 0 {
+            return invalid("flow interval must be above 0".to_string());
+        }
+        if flow.kp < 0.0 || flow.ki < 0.0 || flow.kd < 0.0 {
+            return invalid("flow gains must not be negative".to_string());
+        }
+        if flow.max_slew <= 0.0 || flow.deadband < 0 {
+            return invalid(format!(
+                "flow max_slew {} must be above 0 and deadband {} not negative",
+                flow.max_slew, flow.deadband
+            ));
+        }
+
+        let level = &self.level;
+        if level.interval == 0 || level.samples == 0 {
+            return invalid("level interval and samples must be above 0".to_string());
+        }
+        if level.deadband < 0.0 || level.min_move < 0 {
+            return invalid(format!(
+                "level deadband {} and min_move {} must not be negative",
+                level.deadband, level.min_move
+            ));
+        }
+        if !(level.gain.is_finite() && level.gain != 0.0) {
+            return invalid(format!("level gain {} must not be 0", level.gain));
+        }
+        if !level.damping.is_finite() || level.damping * level.gain < 0.0 {
+            return invalid(format!(
+                "level damping {} must have the sign of gain {}",
+                level.damping, level.gain
+            ));
+        }
+        for sensor in [level.sensor, self.hydraulics.downstream].iter().flatten() {
+            if let LevelInput::Adc { channel, .. } = sensor {
+                if *channel > 7 {
+                    return invalid(format!("level adc channel {} must be 0 to 7", channel));
+                }
+            }
+        }
+        if let (
+            Some(LevelInput::Adc { channel: a, .. }),
+            Some(LevelInput::Adc { channel: b, .. }),
+        ) = (level.sensor, self.hydraulics.downstream)
+        {
+            if a == b {
+                return invalid(format!("level and downstream both use adc channel {}", a));
+            }
+        }
+
+        let hydraulics = &self.hydraulics;
+        if let Some(gate) = hydraulics.gate {
+            if gate.width() <= 0.0 {
+                return invalid(format!("gate width {} must be above 0", gate.width()));
+            }
+            let cd = gate.discharge_coefficient();
+            if !(cd > 0.0 && cd <= 1.0) {
+                return invalid(format!(
+                    "discharge coefficient {} must be above 0 and at most 1",
+                    cd
+                ));
+            }
+            if let GateGeometry::Overflow { height, .. } = gate {
+                if height <= 0.0 {
+                    return invalid(format!("overflow gate height {} must be above 0", height));
+                }
+            }
+            if calibration.stroke.is_none() {
+                return invalid("flow estimate needs a calibration stroke".to_string());
+            }
+            if hydraulics.upstream_head.is_none() && level.sensor.is_none() && !simulate {
+                return invalid(
+                    "flow estimate needs a [level] sensor or an upstream_head".to_string(),
+                );
+            }
+        }
+
+        if self.journal.interval == 0 {
+            return invalid("journal interval must be above 0".to_string());
+        }
+
+        let sim = &self.simulation;
+        if sim.max <= sim.min {
+            return invalid(format!(
+                "simulated travel {}..{} is empty",
+                sim.min, sim.max
+            ));
+        }
+        if !(0.0..=1.0).contains(&sim.slip) {
+            return invalid(format!("simulated slip {} must be from 0 to 1", sim.slip));
+        }
+
+        if !simulate {
+            check_pwm_overlay(hw)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn set<T: Clone>(field: &mut T, opt: &Option<T>) {
+    if let Some(v) = opt {
+        *field = v.clone();
+    }
+}
+
+// the shared sections with the keys a gate sets in each replacing theirs
+fn overlay(shared: &Table, own: Table) -> Table {
+    let mut merged = shared.clone();
+    for (section, value) in own {
+        match (merged.get_mut(&section), value) {
+            (Some(Value::Table(base)), Value::Table(keys)) => base.extend(keys),
+            (_, value) => {
+                merged.insert(section, value);
+            }
+        }
+    }
+    merged
+}
+
+// the pwm overlay exposes its channels through sysfs
+fn check_pwm_overlay(hw: &Hardware) -> Result<()> {
+    let chip = Path::new("/sys/class/pwm/pwmchip0");
+    let channels: u8 = std::fs::read_to_string(chip.join("npwm"))
+        .ok()
+        .and_then(|n| n.trim().parse().ok())
+        .unwrap_or(0);
+
+    if channels <= hw.pwm_channel {
+        let overlay = match hw.pwm_channel {
+            0 => format!(
+                "dtoverlay=pwm,pin={},func={}",
+                hw.pwm_pin,
+                pwm_func(hw.pwm_pin)
+            ),
+            _ => "dtoverlay=pwm-2chan".to_string(),
+        };
+        return invalid(format!(
+            "PWM{} is not available, add `{}` to /boot/config.txt",
+            hw.pwm_channel, overlay
+        ));
+    }
+    Ok(())
+}
+
+// alternate function that routes PWM to a pin
+fn pwm_func(pin: u8) -> u8 {
+    match pin {
+        18 | 19 => 2,
+        _ => 4,
+    }
+}
+
+fn invalid(message: String) -> Result<()> {
+    Err(ConfigError(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    // the hardware of a second gate, clear of the default pins and PWM channel
+    const SPILL: &str = concat!(
+        "hardware = { en_pin = 16, dir_pin = 17, clock_pin = 20, data_pin = 21, ",
+        "pwm_channel = 1, pwm_pin = 13 }"
+    );
+
+    // an analog level sensor on a channel of the ADC
+    fn adc(channel: u8) -> String {
+        format!(
+            "{{ kind = \"adc\", channel = {}, scale = 1.0, offset = 0.0 }}",
+            channel
+        )
+    }
+
+    // a flow estimate for a sluice gate with the keys given
+    fn sluice(keys: &str) -> String {
+        format!(
+            "[calibration]\nstroke = 1.0\n[hydraulics]\ngate = {{ kind = \"sluice\", {} }}",
+            keys
+        )
+    }
+
+    // parse and validate a file, with the simulator unless on hardware
+    fn check(text: &str, simulate: bool) -> std::result::Result<Config, String> {
+        let config = Config::parse(text)?;
+        config.validate(simulate).map_err(|e| e.to_string())?;
+        Ok(config)
+    }
+
+    fn assert_rejected(text: &str, simulate: bool, error: &str) {
+        let result = check(text, simulate).map(|_| ());
+        assert!(
+            result.as_ref().is_err_and(|e| e.contains(error)),
+            "{}\n{:?}",
+            text,
+            result
+        );
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = check("", true).unwrap();
+        assert_eq!(config.gates.keys().collect::<Vec<_>>(), [DEFAULT_GATE]);
+    }
+
+    #[test]
+    fn a_slow_frequency_lowers_the_start_and_homing_rates() {
+        let merged = |args: &[&str]| {
+            let opts = Opts::try_parse_from([&["gateman"], args].concat()).unwrap();
+            let config = Config::parse("").unwrap().merge(&opts);
+            config.validate(true).map_err(|e| e.to_string())?;
+            let gate = &config.gates[DEFAULT_GATE];
+            Ok::<_, String>((gate.motion.clone(), gate.homing.clone()))
+        };
+        let (motion, homing) = merged(&["-f", "40"]).unwrap();
+        assert_eq!((motion.start_rate, motion.max_rate), (40.0, 40.0));
+        assert_eq!((homing.seek_rate, homing.creep_rate), (40.0, 40.0));
+        let (motion, homing) = merged(&["-f", "800"]).unwrap();
+        assert_eq!((motion.start_rate, motion.max_rate), (100.0, 800.0));
+        assert_eq!((homing.seek_rate, homing.creep_rate), (200.0, 50.0));
+        // a start rate given along with it is taken as it is
+        let result = merged(&["-f", "50", "--start-frequency", "80"]);
+        assert!(result.is_err_and(|e| e.contains("start_rate 80")));
+    }
+
+    #[test]
+    fn gates_lay_their_keys_over_the_shared_sections() {
+        let text = format!(
+            r#"
+            [motion]
+            max_rate = 800.0
+            acceleration = 1000.0
+
+            [safety]
+            failsafe = {{ position = 100 }}
+
+            [gates.head]
+            motion.max_rate = 600.0
+
+            [gates.spill]
+            {}
+            "#,
+            SPILL
+        );
+        let config = check(&text, true).unwrap();
+        let (head, spill) = (&config.gates["head"], &config.gates["spill"]);
+        assert_eq!(head.motion.max_rate, 600.0);
+        assert_eq!(head.motion.acceleration, 1000.0);
+        assert_eq!(spill.motion.max_rate, 800.0);
+        assert_eq!(spill.hardware.en_pin, 16);
+        assert_eq!(head.hardware.en_pin, 5);
+        assert_eq!(spill.safety.failsafe, Failsafe::Position(100));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for text in [
+            "[motion]\nmax_speed = 100.0",
+            "[network]\nhost = \"a\"",
+            "[gates.a.hardware]\nen = 1",
+            "[safety]\nfailsafe = \"open\"",
+            "[safety]\nfailsafe = { position = 1, speed = 2 }",
+            "[stall]\nratio = 0.5",
+        ] {
+            assert!(Config::parse(text).is_err(), "{}", text);
+        }
+        let sensor = adc(0).replace(" }", ", gain = 1.0 }");
+        assert!(Config::parse(&format!("[level]\nsensor = {}", sensor)).is_err());
+    }
+
+    #[test]
+    fn a_percent_curve_must_increase() {
+        let curve = |last: &str| {
+            format!(
+                "[calibration]\npercent_curve = [{{ opening = 0, counts = 0 }}, {}]",
+                last
+            )
+        };
+        assert_rejected(
+            &curve("{ opening = 50, counts = 0 }"),
+            true,
+            "curve is not increasing",
+        );
+        assert_rejected(
+            &curve("{ opening = 150, counts = 10 }"),
+            true,
+            "percent curve 0..150 is outside 0..100",
+        );
+    }
+
+    #[test]
+    fn gates_must_not_share_hardware_or_files() {
+        let same_pwm = "[gates.b]\nhardware = { en_pin = 16, dir_pin = 17, clock_pin = 20, \
+                        data_pin = 21, pwm_pin = 18 }";
+        let same_adc = format!(
+            "[gates.a]\nlevel.sensor = {}\n[gates.b]\n{}\nlevel.sensor = {}",
+            adc(2),
+            SPILL,
+            adc(2)
+        );
+        let rejected = [
+            (
+                "[gates.a]\n[gates.b]".to_string(),
+                "gates a and b both use pin 5",
+            ),
+            (
+                format!("[gates.a]\n{}", same_pwm),
+                "gates a and b both use PWM0",
+            ),
+            (same_adc, "gates a and b both use adc channel 2"),
+        ];
+        for (text, error) in rejected {
+            assert_rejected(&text, false, error);
+        }
+        // files are not shared even by simulated gates
+        let journal = format!(
+            "[journal]\npath = \"/tmp/gateman\"\n[gates.a]\n[gates.b]\n{}",
+            SPILL
+        );
+        assert_rejected(
+            &journal,
+            true,
+            "a journal and b journal both use /tmp/gateman",
+        );
+        // nor pins within a gate
+        assert_rejected(
+            "[hardware]\nhome_pin = 6",
+            true,
+            "dir_pin and home_pin both use pin 6",
+        );
+    }
+
+    #[test]
+    fn every_rule_rejects_a_config() {
+        let rejected = [
+            ("[gates.\"a b\"]", "may only use letters, digits, - and _"),
+            ("[hardware]\nen_pin = 30", "en_pin 30 is not a GPIO pin"),
+            (
+                "[hardware]\npwm_channel = 2",
+                "pwm_channel 2 does not exist",
+            ),
+            ("[hardware]\npwm_pin = 13", "pwm_pin 13 cannot carry PWM0"),
+            ("[motion]\nstart_rate = 0.0", "start_rate 0 must be above 0"),
+            (
+                "[motion]\nmax_rate = 200000.0",
+                "max_rate 200000 is above the limit",
+            ),
+            (
+                "[motion]\nacceleration = -1.0",
+                "acceleration -1 is negative",
+            ),
+            (
+                "[motion]\nsteps_per_count = 0.0",
+                "steps_per_count 0 must be above 0",
+            ),
+            (
+                "[calibration]\ncounts_per_unit = 0",
+                "counts_per_unit 0 must be above 0",
+            ),
+            (
+                "[calibration]\ntravel = 0",
+                "calibration travel must be above 0",
+            ),
+            (
+                "[calibration]\nstroke = 0.0",
+                "calibration stroke must be above 0",
+            ),
+            (
+                "[calibration]\nrate = 0.0",
+                "calibration rate 0 must be above 0",
+            ),
+            (
+                "[calibration]\ntimeout = 0",
+                "calibration timeout must be at least 1 second",
+            ),
+            (
+                "[safety]\nkeepalive_timeout = 0",
+                "safety timeouts must be at least 1 second",
+            ),
+            (
+                "[safety]\nclient_timeout = 0",
+                "safety timeouts must be at least 1 second",
+            ),
+            (
+                "[limits]\nmin = 100\nmax = 100",
+                "soft limits 100..100 are empty",
+            ),
+            (
+                "[safety]\nfailsafe = \"open-fully\"",
+                "failsafe open-fully needs a [limits] max",
+            ),
+            (
+                "[limits]\nmax = 1000\n[safety]\nfailsafe = { position = 2000 }",
+                "failsafe position 2000 is outside",
+            ),
+            ("[stall]\nwindow = 0", "stall window must be above 0"),
+            (
+                "[stall]\nmin_ratio = 1.5",
+                "stall min_ratio 1.5 must be above 0 and at most 1",
+            ),
+            (
+                "[homing]\ncreep_rate = 300.0",
+                "homing creep_rate 300 must be above 0",
+            ),
+            (
+                "[homing]\nseek_rate = 2000.0\n[motion]\nmax_rate = 1000.0",
+                "homing seek_rate 2000 is above max_rate 1000",
+            ),
+            (
+                "[homing]\ntimeout = 0",
+                "homing backoff and timeout must not be",
+            ),
+            ("[flow]\nk_factor = 0.0", "flow k_factor 0 must be above 0"),
+            ("[flow]\ninterval = 0", "flow interval must be above 0"),
+            ("[flow]\nki = -1.0", "flow gains must not be negative"),
+            ("[flow]\nmax_slew = 0.0", "flow max_slew 0 must be above 0"),
+            (
+                "[level]\nsamples = 0",
+                "level interval and samples must be above 0",
+            ),
+            (
+                "[level]\nmin_move = -1",
+                "and min_move -1 must not be negative",
+            ),
+            ("[level]\ngain = 0.0", "level gain 0 must not be 0"),
+            (
+                "[level]\ndamping = -1.0",
+                "level damping -1 must have the sign of gain",
+            ),
+            (
+                "[journal]\ninterval = 0",
+                "journal interval must be above 0",
+            ),
+            ("[simulation]\nmax = 0", "simulated travel 0..0 is empty"),
+            (
+                "[simulation]\nslip = 2.0",
+                "simulated slip 2 must be from 0 to 1",
+            ),
+            (
+                "[recipes.empty]\nsteps = []",
+                "recipe empty: recipe has no steps",
+            ),
+        ];
+        for (text, error) in rejected {
+            assert_rejected(text, true, error);
+        }
+
+        let interlock = |requires: &str| {
+            format!(
+                "[[interlocks]]\ngate = \"gate\"\nrequires = \"{}\"\nabove = 10\nat_least = 10",
+                requires
+            )
+        };
+        let rejected = [
+            (
+                format!("[level]\nsensor = {}", adc(8)),
+                "level adc channel 8 must be 0 to 7",
+            ),
+            (
+                format!(
+                    "[level]\nsensor = {}\n[hydraulics]\ndownstream = {}",
+                    adc(1),
+                    adc(1)
+                ),
+                "level and downstream both use adc channel 1",
+            ),
+            (sluice("width = 0.0"), "gate width 0 must be above 0"),
+            (
+                sluice("width = 1.0, discharge_coefficient = 1.5"),
+                "discharge coefficient 1.5 must be above 0 and at most 1",
+            ),
+            (
+                sluice("width = 1.0")
+                    .replace("sluice", "overflow")
+                    .replace(" }", ", height = 0.0 }"),
+                "overflow gate height 0 must be above 0",
+            ),
+            (
+                "[hydraulics]\ngate = { kind = \"sluice\", width = 1.0 }".to_string(),
+                "flow estimate needs a calibration stroke",
+            ),
+            (interlock("spill"), "interlock 0: no gate named spill"),
+            (interlock("gate"), "interlock 0: gate cannot require itself"),
+        ];
+        for (text, error) in rejected {
+            assert_rejected(&text, true, error);
+        }
+
+        // on hardware only
+        assert_rejected(
+            "[homing]\non_startup = true",
+            false,
+            "homing on_startup requires a home_pin",
+        );
+        assert_rejected(
+            &sluice("width = 1.0"),
+            false,
+            "flow estimate needs a [level] sensor or an upstream_head",
+        );
+    }
+
+    #[test]
+    fn a_gate_error_names_the_gate() {
+        let text = format!("[gates.a]\n[gates.b]\n{}\nflow.interval = 0", SPILL);
+        assert_rejected(&text, true, "gate b: flow interval must be above 0");
+    }
+}
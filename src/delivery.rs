@@ -0,0 +1,292 @@
+//! Delivery of a volume through the gate, totalized from the flow meter.
+//!
+//! A delivery opens the gate to a configured opening and closes it once the
+//! requested volume has passed the meter. Progress is saved every few seconds
+//! as it goes, so a delivery interrupted by a restart carries on from about
+//! where it was.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::Opening;
+use crate::gate::Mode;
+use crate::journal::Writer;
+use crate::Error::JournalError;
+use crate::Result;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeliveryConfig {
+    /// How far the gate opens during a delivery
+    pub opening: Opening,
+    /// File the progress of a delivery is saved to, not resumed after a restart when not set
+    pub path: Option<PathBuf>,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            opening: Opening::Percent(100.0),
+            path: None,
+        }
+    }
+}
+
+impl DeliveryConfig {
+    pub fn totalizer(&self) -> Option<Totalizer> {
+        self.path.as_ref().map(Totalizer::new)
+    }
+}
+
+/// Litres delivered toward a volume
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Progress {
+    pub volume: f64,
+    pub delivered: f64,
+}
+
+impl Progress {
+    pub fn remaining(&self) -> f64 {
+        (self.volume - self.delivered).max(0.0)
+    }
+
+    pub fn complete(&self) -> bool {
+        self.delivered >= self.volume
+    }
+}
+
+/// How often the progress of a running delivery is saved
+const SAVE_EVERY: Duration = Duration::from_secs(5);
+
+/// Saved progress of the delivery in progress, replaced atomically like the position journal
+pub struct Totalizer {
+    path: PathBuf,
+    writer: Writer,
+}
+
+impl Totalizer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self {
+            writer: Writer::new(path.clone()),
+            path,
+        }
+    }
+
+    /// The delivery in progress when the daemon stopped, if any
+    pub fn load(&self) -> Result<Option<Progress>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|e| self.error(e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    /// Hand the progress to the writer, replacing any it has not written yet
+    pub fn record(&self, progress: Progress) -> Result<()> {
+        let json = serde_json::to_vec(&progress).map_err(|e| self.error(e))?;
+        self.writer.replace(json);
+        Ok(())
+    }
+
+    /// Forget the delivery once it has ended
+    pub fn clear(&self) {
+        self.writer.remove();
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> crate::Error {
+        JournalError(format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Counts the litres through the meter toward the delivery the gate is making
+pub struct Delivery {
+    totalizer: Option<Totalizer>,
+    progress: Option<Progress>,
+    // a resumed delivery is kept until the first update, which finds the gate
+    // delivering it again or drops it
+    resuming: bool,
+    finished: bool,
+    // when the progress was last saved
+    saved: Option<Instant>,
+}
+
+impl Delivery {
+    pub fn new(totalizer: Option<Totalizer>) -> Self {
+        let progress = match totalizer.as_ref().map(Totalizer::load).transpose() {
+            Ok(progress) => progress.flatten(),
+            Err(e) => {
+                eprintln!("not resuming delivery: {}", e);
+                None
+            }
+        };
+        if let Some(p) = progress {
+            eprintln!("resuming delivery, {} of {} l", p.delivered, p.volume);
+        }
+        Self {
+            totalizer,
+            resuming: progress.is_some(),
+            progress,
+            finished: false,
+            saved: None,
+        }
+    }
+
+    /// The delivery interrupted by a restart, to be started again
+    pub fn resumed(&self) -> Option<Progress> {
+        self.progress.filter(|_| self.resuming)
+    }
+
+    /// Count litres through the meter in the current mode, returning the
+    /// progress while a delivery is running. A new volume while delivering
+    /// replaces the old one, keeping what has been delivered so far, and a
+    /// delivery started in any other mode counts from nothing.
+    pub fn update(&mut self, mode: Mode, litres: f64) -> Option<Progress> {
+        let volume = match mode {
+            Mode::Deliver { volume } => volume,
+            _ if self.resuming => {
+                if let Some(p) = self.progress.take() {
+                    eprintln!("not resuming delivery of {} l", p.volume);
+                }
+                self.resuming = false;
+                self.save(None);
+                return None;
+            }
+            _ => {
+                if let Some(p) = self.progress.take() {
+                    if !self.finished {
+                        eprintln!("delivery ended after {} of {} l", p.delivered, p.volume);
+                    }
+                    self.save(None);
+                }
+                self.finished = false;
+                return None;
+            }
+        };
+        // a different volume is a new delivery, not the one resumed
+        if std::mem::take(&mut self.resuming) && self.progress.is_some_and(|p| p.volume != volume) {
+            self.progress = None;
+        }
+        if self.finished {
+            return None;
+        }
+
+        let progress = self.progress.get_or_insert(Progress {
+            volume,
+            delivered: 0.0,
+        });
+        progress.volume = volume;
+        progress.delivered += litres;
+        let progress = *progress;
+        if progress.complete() {
+            eprintln!("delivered {} l", progress.delivered);
+            self.finished = true;
+            self.save(None);
+        } else if self.saved.is_none_or(|at| at.elapsed() >= SAVE_EVERY) {
+            self.save(Some(progress));
+        }
+        Some(progress)
+    }
+
+    fn save(&mut self, progress: Option<Progress>) {
+        let totalizer = match &self.totalizer {
+            Some(t) => t,
+            None => return,
+        };
+        match progress {
+            Some(p) => {
+                if let Err(e) = totalizer.record(p) {
+                    eprintln!("totalizer error: {}", e);
+                }
+                self.saved = Some(Instant::now());
+            }
+            None => {
+                totalizer.clear();
+                self.saved = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a delivery of 100 l saved 40 l in before a restart, under a path of its own
+    fn interrupted(name: &str) -> (Delivery, PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("gateman-delivery-{}-{}", name, std::process::id()));
+        let saved = Progress {
+            volume: 100.0,
+            delivered: 40.0,
+        };
+        fs::write(&path, serde_json::to_vec(&saved).unwrap()).unwrap();
+        (Delivery::new(Some(Totalizer::new(&path))), path)
+    }
+
+    // forget the delivery once its writer has finished with the file
+    fn clean_up((delivery, path): (Delivery, PathBuf)) {
+        drop(delivery);
+        let _ = fs::remove_file(path);
+    }
+
+    fn delivering(volume: f64) -> Mode {
+        Mode::Deliver { volume }
+    }
+
+    #[test]
+    fn counts_toward_the_volume() {
+        let mut delivery = Delivery::new(None);
+        assert_eq!(delivery.update(Mode::Manual, 5.0), None);
+        let progress = delivery.update(delivering(10.0), 4.0).unwrap();
+        assert_eq!(progress.delivered, 4.0);
+        assert!(!progress.complete());
+        assert!(delivery.update(delivering(10.0), 6.0).unwrap().complete());
+        // nothing more is counted once it is complete
+        assert_eq!(delivery.update(delivering(10.0), 1.0), None);
+    }
+
+    #[test]
+    fn a_new_volume_keeps_what_was_delivered() {
+        let mut delivery = Delivery::new(None);
+        delivery.update(delivering(10.0), 4.0);
+        let progress = delivery.update(delivering(20.0), 1.0).unwrap();
+        assert_eq!(progress.volume, 20.0);
+        assert_eq!(progress.delivered, 5.0);
+    }
+
+    #[test]
+    fn a_resumed_delivery_carries_on() {
+        let (mut delivery, path) = interrupted("resumed");
+        assert_eq!(delivery.resumed().map(|p| p.delivered), Some(40.0));
+        let progress = delivery.update(delivering(100.0), 5.0).unwrap();
+        assert_eq!(progress.delivered, 45.0);
+        assert_eq!(delivery.resumed(), None);
+        clean_up((delivery, path));
+    }
+
+    #[test]
+    fn a_refused_resume_is_dropped() {
+        let (mut delivery, path) = interrupted("refused");
+        // the gate never went back to delivering
+        assert_eq!(delivery.update(Mode::Manual, 5.0), None);
+        assert_eq!(delivery.resumed(), None);
+        // so a delivery asked for later counts from nothing
+        let progress = delivery.update(delivering(100.0), 5.0).unwrap();
+        assert_eq!(progress.delivered, 5.0);
+        clean_up((delivery, path));
+    }
+
+    #[test]
+    fn a_different_volume_is_a_new_delivery() {
+        let (mut delivery, path) = interrupted("replaced");
+        let progress = delivery.update(delivering(50.0), 5.0).unwrap();
+        assert_eq!(progress.delivered, 5.0);
+        clean_up((delivery, path));
+    }
+}
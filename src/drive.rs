@@ -1,27 +1,45 @@
 use std::fmt::{Debug, Display, Formatter};
 use std::sync::atomic::{AtomicIsize, Ordering};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use rppal::gpio::Level::*;
-use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
-use rppal::pwm::{Channel, Polarity, Pwm};
+use serde::{Deserialize, Serialize};
 use tokio::select;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc, watch};
 
 use Direction::*;
-use EncoderSpin::*;
 
-use crate::Error::{DriverThreadError, EncoderThreadError, EncoderTxError};
+use crate::config::{Hardware, Homing, StallDetection};
+use crate::hal::sim::SimGate;
+use crate::hal::EncoderSpin::*;
+use crate::hal::{rpi, Encoder, EncoderSpin, OutputLine, StepGenerator, Switch};
+use crate::journal::{Entry, Journal};
+use crate::motion::MotionProfile;
+use crate::status::{Status, StatusBus};
+use crate::Error::{CalibrationError, EncoderThreadError, HomingError, LimitError, Stall};
 use crate::Result;
 
+/// Relative change in step rate that triggers a PWM retune
+const RETUNE_THRESHOLD: f64 = 0.01;
+
+/// Interval between reads of switch inputs during a move
+const SWITCH_POLL: Duration = Duration::from_millis(1);
+
 pub struct Drive {
-    en: OutputPin,
-    dir: OutputPin,
-    clock: Arc<InputPin>,
-    data: Arc<InputPin>,
-    pwm: Arc<Pwm>,
+    en: Box<dyn OutputLine>,
+    dir: Box<dyn OutputLine>,
+    encoder: Arc<dyn Encoder>,
+    pwm: Arc<dyn StepGenerator>,
     pos: Arc<AtomicIsize>,
+    profile: MotionProfile,
+    journal: Option<Journal>,
+    verified: bool,
+    home: Option<Switch>,
+    open_limit: Option<Switch>,
+    close_limit: Option<Switch>,
+    soft_limits: SoftLimits,
+    stall: StallDetection,
 }
 
 impl Drop for Drive {
@@ -32,44 +50,147 @@ impl Drop for Drive {
 }
 
 impl Drive {
-    pub fn new(at: isize, en: u8, dir: u8, clock: u8, data: u8) -> Result<Self> {
-        let en = Gpio::new()?.get(en)?.into_output_low();
-        let dir = Gpio::new()?.get(dir)?.into_output_low();
-        let clock = Arc::new(Gpio::new()?.get(clock)?.into_input_pullup());
-        let data = Arc::new(Gpio::new()?.get(data)?.into_input_pullup());
-        let pwm = Arc::new(Pwm::with_frequency(
-            Channel::Pwm0,
-            500f64,
-            0.5,
-            Polarity::Normal,
-            false,
-        )?);
-
-        Ok(Self {
+    /// Drive backed by the Raspberry Pi GPIO and PWM
+    pub fn new(at: isize, hardware: &Hardware, profile: MotionProfile) -> Result<Self> {
+        let mut drive = Self::with_hardware(
+            at,
+            Box::new(rpi::output(hardware.en_pin)?),
+            Box::new(rpi::output(hardware.dir_pin)?),
+            Arc::new(rpi::PinEncoder::new(hardware.clock_pin, hardware.data_pin)?),
+            Arc::new(rpi::pwm(hardware.pwm_channel, profile.start_rate)?),
+            profile,
+        );
+        if let Some(pin) = hardware.home_pin {
+            let line = Box::new(rpi::input(pin)?);
+            drive.set_home_switch(Switch::new(line, hardware.home_active_high));
+        }
+        // normally closed end-stops read high when tripped or disconnected
+        let open = hardware.open_limit_pin.map(rpi::input).transpose()?;
+        let close = hardware.close_limit_pin.map(rpi::input).transpose()?;
+        drive.set_end_stops(
+            open.map(|line| Switch::new(Box::new(line), true)),
+            close.map(|line| Switch::new(Box::new(line), true)),
+        );
+        Ok(drive)
+    }
+
+    /// Drive backed by a simulated gate
+    pub fn simulated(gate: &SimGate, profile: MotionProfile) -> Self {
+        let mut drive = Self::with_hardware(
+            gate.position(),
+            Box::new(gate.enable_line()),
+            Box::new(gate.direction_line()),
+            Arc::new(gate.clone()),
+            Arc::new(gate.clone()),
+            profile,
+        );
+        drive.set_home_switch(Switch::new(Box::new(gate.home_switch()), true));
+        drive.set_end_stops(
+            gate.open_limit().map(|s| Switch::new(Box::new(s), true)),
+            gate.close_limit().map(|s| Switch::new(Box::new(s), true)),
+        );
+        drive
+    }
+
+    /// Drive backed by any implementation of the [`hal`](crate::hal) traits
+    pub fn with_hardware(
+        at: isize,
+        en: Box<dyn OutputLine>,
+        dir: Box<dyn OutputLine>,
+        encoder: Arc<dyn Encoder>,
+        pwm: Arc<dyn StepGenerator>,
+        profile: MotionProfile,
+    ) -> Self {
+        Self {
             en,
             dir,
-            clock,
-            data,
+            encoder,
             pwm,
             pos: Arc::new(AtomicIsize::new(at)),
-        })
+            profile,
+            journal: None,
+            verified: true,
+            home: None,
+            open_limit: None,
+            close_limit: None,
+            soft_limits: SoftLimits::default(),
+            stall: StallDetection::default(),
+        }
     }
 
-    // fn set_killer(&mut self, tx: Sender<()>, rx: Receiver<()>) {
-    //     self.kill = Some((tx, rx));
-    // }
-
-    fn position(&self) -> isize {
+    pub fn position(&self) -> isize {
         self.pos.load(Ordering::Relaxed)
     }
 
-    // fn killer(self) -> Sender<()> {
-    //     self.kill.0.clone()
-    // }
-    //
-    // fn stop(self) {
-    //     self.killer().send(());
-    // }s
+    /// False when the position is not known to match the gate
+    pub fn verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn set_verified(&mut self, verified: bool) {
+        self.verified = verified;
+    }
+
+    /// Switch found by the homing routine at the closed end of travel
+    pub fn set_home_switch(&mut self, switch: Switch) {
+        self.home = Some(switch);
+    }
+
+    /// Switches that cut the step generator when tripped at either end of travel
+    pub fn set_end_stops(&mut self, open: Option<Switch>, close: Option<Switch>) {
+        self.open_limit = open;
+        self.close_limit = close;
+    }
+
+    /// Positions a move may not go beyond
+    pub fn set_soft_limits(&mut self, limits: SoftLimits) {
+        self.soft_limits = limits;
+    }
+
+    /// How moves detect a jammed gate
+    pub fn set_stall_detection(&mut self, stall: StallDetection) {
+        self.stall = stall;
+    }
+
+    // the end-stop tripped in the direction of travel
+    fn end_stop(&self, dir: Direction) -> Option<Limit> {
+        let (switch, limit) = match dir {
+            Open => (&self.open_limit, Limit::HardOpen),
+            Close => (&self.close_limit, Limit::HardClose),
+        };
+        switch.as_ref().filter(|s| s.active()).map(|_| limit)
+    }
+
+    // a position past a soft limit in the direction of travel, so a gate left
+    // outside them can still be moved back
+    fn check_soft_limits(&self, position: isize, dir: Direction) -> Result<()> {
+        match (self.soft_limits, dir) {
+            (SoftLimits { max: Some(max), .. }, Open) if position > max => {
+                Err(LimitError(Limit::SoftOpen))
+            }
+            (SoftLimits { min: Some(min), .. }, Close) if position < min => {
+                Err(LimitError(Limit::SoftClose))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Journal the position during and after every move
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    fn record(&self, moving: bool) {
+        if let Some(journal) = self.journal.as_ref() {
+            let entry = Entry {
+                position: self.position(),
+                moving,
+            };
+            if let Err(e) = journal.record(entry) {
+                eprintln!("{}", e);
+            }
+        }
+    }
 
     pub fn enable(&mut self) {
         self.en.set_low()
@@ -79,173 +200,560 @@ impl Drive {
         self.en.set_high()
     }
 
-    pub async fn move_to(
+    /// Stop the step generator and disable the driver, whatever state a failed move left them in
+    pub fn halt(&mut self) {
+        if let Err(e) = self.pwm.disable() {
+            eprintln!("failed to disable step generator: {}", e);
+        }
+        self.disable();
+    }
+
+    fn set_direction(&mut self, dir: Direction) {
+        match dir {
+            Open => self.dir.set_low(),
+            Close => self.dir.set_high(),
+        }
+    }
+
+    pub async fn move_to(&mut self, target_pos: isize, statbus: Option<StatusBus>) -> Result<()> {
+        let (_control, setpoint) = watch::channel(Setpoint::Target(target_pos));
+        self.follow(setpoint, statbus).await
+    }
+
+    /// Move toward the setpoint until it is reached or a stop is requested.
+    /// The setpoint can be changed while moving, reversing if needed.
+    pub async fn follow(
         &mut self,
-        target_pos: isize,
-        statbus: Option<UnboundedSender<String>>,
+        mut setpoint: watch::Receiver<Setpoint>,
+        statbus: Option<StatusBus>,
     ) -> Result<()> {
-        let (steps_needed, dir) = steps_in_right_direction(self.position(), target_pos);
+        let mut target_pos = match *setpoint.borrow() {
+            Setpoint::Target(t) => t,
+            Setpoint::Stop => return Ok(()),
+        };
+        let (mut steps_needed, mut dir) = steps_in_right_direction(self.position(), target_pos);
         println!("steps needed: {}", steps_needed);
 
-        if steps_needed > 0 {
-            let starting_position = self.pos.load(Ordering::Relaxed);
-            let mut current_position = starting_position;
-            let mut encoder_steps: usize = 0;
+        if steps_needed == 0 {
+            return Ok(());
+        }
+        if let Some(limit) = self.end_stop(dir) {
+            return Err(LimitError(limit));
+        }
+
+        let mut starting_position = self.position();
+        let mut current_position = starting_position;
+        let mut encoder_steps: usize = 0;
 
-            println!(
-                "thread: moving {} => {} ({})",
-                starting_position, target_pos, dir
-            );
+        println!(
+            "thread: moving {} => {} ({})",
+            starting_position, target_pos, dir
+        );
 
-            // start reading encoder in native thread, provie a kill channel
-            let clock = self.clock.clone();
-            let data = self.data.clone();
-            let (enc_tx, mut enc_rx) = mpsc::channel(1);
-            let (enc_kill_tx, enc_kill_rx) = mpsc::channel(1);
+        // set the inferred direction
+        self.set_direction(dir);
 
-            let h = std::thread::spawn(move || read_encoder(clock, data, enc_tx, enc_kill_rx));
+        // pulse steps while reading from the encoder, starting at the bottom of the ramp
+        let mut rate = self.profile.rate(0, steps_needed);
+        let mut session = self.start(rate)?;
+        let mut poll = tokio::time::interval(SWITCH_POLL);
+        let mut watchdog = Watchdog::new(self.stall, self.profile.steps_per_count);
 
-            // set the inferred direction
-            self.dir.write(dir.into());
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Begin);
+        }
 
-            // pulse steps while reading from the encoder
-            let position = self.pos.clone();
-            self.pwm.enable()?;
+        // a move that is still journaled as moving at startup did not finish
+        self.record(true);
+        let mut recorded = Instant::now();
 
-            if let Some(tx) = statbus.as_ref() {
-                tx.send("begin".to_string()).unwrap();
+        let result = loop {
+            if let Some(limit) = self.end_stop(dir) {
+                // cut the steps before anything else
+                let _ = self.pwm.disable();
+                println!("{} limit tripped at {}", limit, current_position);
+                break Err(LimitError(limit));
             }
+            if let Err(e) = watchdog.check(rate, current_position) {
+                break Err(e);
+            }
+
+            select! {
+                e = session.next() => {
+                    let e = match e {
+                        Ok(e) => e,
+                        Err(e) => break Err(e),
+                    };
+                    current_position = self.track(e);
+                    encoder_steps += 1;
+                    watchdog.count();
+
+                    if let Err(e) = self.check_soft_limits(current_position, dir) {
+                        break Err(e);
+                    }
 
-            let statbuscopy = statbus.clone();
-            tokio::spawn(async move {
-                loop {
-                    select! {
-                        Some(e) = enc_rx.recv() => {
-                            match e {
-                                Ccw => current_position += 1,
-                                Cw => current_position -= 1,
-                            };
-                            encoder_steps += 1;
-
-                            position.store(current_position, Ordering::Relaxed);
-                            println!("position: {}", current_position);
-
-                            if let Some(tx) = statbuscopy.as_ref() {
-                                match tx.send(current_position.to_string()) {
-                                    Ok(_) => {},
-                                    Err(_) => eprintln!("failed to stat current position")
-                                }
-                            }
-
-                            if target_is_met(current_position, target_pos, dir) {
-                                println!(
-                                    "Executed {} of projected {} steps to move to position {}",
-                                    encoder_steps, steps_needed, target_pos
-                                );
-                                enc_kill_tx.send(()).await.expect("Failed to send encoder kill");
-                            }
+                    if self.journal.as_ref().is_some_and(|j| recorded.elapsed() >= j.interval()) {
+                        self.record(true);
+                        recorded = Instant::now();
+                    }
+
+                    // retune the step rate along the ramp
+                    let next_rate = self.profile.rate(
+                        current_position - starting_position,
+                        target_pos - current_position,
+                    );
+                    if (next_rate - rate).abs() >= RETUNE_THRESHOLD * rate {
+                        rate = next_rate;
+                        if let Err(e) = self.pwm.set_frequency(rate) {
+                            eprintln!("failed to retune step rate: {}", e);
                         }
-                        else => {
-                            println!("exiting movement loop...");
-                            break;
+                    }
+
+                    if let Some(bus) = statbus.as_ref() {
+                        bus.publish(Status::Position(current_position));
+                    }
+
+                    if target_is_met(current_position, target_pos, dir) {
+                        println!(
+                            "Executed {} of projected {} steps to move to position {}",
+                            encoder_steps, steps_needed, target_pos
+                        );
+                        break Ok(());
+                    }
+                }
+                _ = poll.tick() => {}
+                changed = setpoint.changed() => {
+                    let next = match changed {
+                        Ok(_) => *setpoint.borrow(),
+                        Err(_) => Setpoint::Stop,
+                    };
+                    let t = match next {
+                        Setpoint::Target(t) => t,
+                        Setpoint::Stop => {
+                            println!("stopping at {}", current_position);
+                            break Ok(());
+                        }
+                    };
+
+                    let (steps, d) = steps_in_right_direction(current_position, t);
+                    println!("retargeting {} => {} ({})", current_position, t, d);
+                    target_pos = t;
+                    if steps == 0 {
+                        break Ok(());
+                    }
+
+                    // reversing starts a fresh leg from the bottom of the ramp
+                    if d != dir {
+                        rate = self.profile.rate(0, steps);
+                        if let Err(e) = self.reverse(d, rate) {
+                            break Err(e);
                         }
+                        dir = d;
+                        watchdog.reset();
+                        starting_position = current_position;
+                        steps_needed = steps;
+                        encoder_steps = 0;
                     }
                 }
-            })
-            .await
-            .map_err(|_| DriverThreadError("Join failed".to_string()))?;
+            }
+        };
+
+        let stopped = self.finish(session).await;
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Position(self.position()));
+            bus.publish(Status::Done);
+        }
+
+        result?;
+        stopped
+    }
 
-            // stop pwm
-            self.pwm.disable()?;
+    /// Find the home switch: seek toward the closed end, back off the switch,
+    /// approach it again at creep speed and set the position to the home offset.
+    /// Returns false if a stop was requested before homing completed.
+    pub async fn home(
+        &mut self,
+        homing: &Homing,
+        mut stop: watch::Receiver<Setpoint>,
+        statbus: Option<StatusBus>,
+    ) -> Result<bool> {
+        if self.home.is_none() {
+            return Err(HomingError("no home switch configured".to_string()));
+        }
+        let deadline = Instant::now() + homing.timeout();
 
-            if let Some(tx) = statbus.as_ref() {
-                tx.send("done".to_string()).unwrap();
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Begin);
+        }
+
+        let phases = [
+            ("seek", Close, homing.seek_rate),
+            ("back off", Open, homing.seek_rate),
+            ("creep", Close, homing.creep_rate),
+        ];
+
+        let mut result = Ok(true);
+        for (name, dir, rate) in phases {
+            println!("homing: {}", name);
+            let done = |d: &Self, travelled: isize| {
+                let on_switch = d.home.as_ref().is_some_and(Switch::active);
+                match dir {
+                    Open => !on_switch && travelled >= homing.backoff,
+                    Close => on_switch,
+                }
+            };
+            result = match self.jog(dir, rate, &mut stop, deadline, &done).await {
+                Ok(Jog::Done) => Ok(true),
+                Ok(Jog::Stopped) => Ok(false),
+                Ok(Jog::TimedOut) => Err(HomingError(format!("timed out during {}", name))),
+                Err(e) => Err(e),
+            };
+            if let Some(bus) = statbus.as_ref() {
+                bus.publish(Status::Position(self.position()));
+            }
+            if !matches!(result, Ok(true)) {
+                break;
             }
+        }
 
-            h.join()
-                .map_err(|_| EncoderThreadError("Join failed".to_string()))??;
+        if let Ok(true) = result {
+            println!("homed, position {} => {}", self.position(), homing.offset);
+            self.pos.store(homing.offset, Ordering::Relaxed);
+            self.verified = true;
+            self.record(false);
+        }
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Position(self.position()));
+            bus.publish(Status::Done);
         }
+        result
+    }
 
-        Ok(())
+    /// Measure the travel of the gate: run to the closed stop and zero the position there,
+    /// then run to the open stop. A stop is an end-stop, the home switch when closing,
+    /// or the gate stalling against the end of travel.
+    /// Returns the travel in counts, or None if a stop was requested first.
+    pub async fn calibrate(
+        &mut self,
+        rate: f64,
+        timeout: Duration,
+        mut stop: watch::Receiver<Setpoint>,
+        statbus: Option<StatusBus>,
+    ) -> Result<Option<isize>> {
+        let deadline = Instant::now() + timeout;
+
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Begin);
+        }
+
+        let mut result = Ok(None);
+        for dir in [Close, Open] {
+            println!("calibrating: seeking {} stop", dir);
+            let at_home = |d: &Self, _| dir == Close && d.home.as_ref().is_some_and(Switch::active);
+            let reached = match self.jog(dir, rate, &mut stop, deadline, &at_home).await {
+                Ok(Jog::Done) | Err(LimitError(_)) | Err(Stall { .. }) => Ok(true),
+                Ok(Jog::Stopped) => Ok(false),
+                Ok(Jog::TimedOut) => Err(CalibrationError(format!(
+                    "timed out seeking the {} stop",
+                    dir
+                ))),
+                Err(e) => Err(e),
+            };
+            if let Some(bus) = statbus.as_ref() {
+                bus.publish(Status::Position(self.position()));
+            }
+            result = match (reached, dir) {
+                (Ok(true), Close) => {
+                    println!("closed stop at {}", self.position());
+                    self.pos.store(0, Ordering::Relaxed);
+                    self.verified = true;
+                    Ok(None)
+                }
+                (Ok(true), Open) => Ok(Some(self.position())),
+                (Ok(false), _) => break,
+                (Err(e), _) => Err(e),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.record(false);
+        if let Some(bus) = statbus.as_ref() {
+            bus.publish(Status::Position(self.position()));
+            bus.publish(Status::Done);
+        }
+        match result {
+            Ok(Some(travel)) if travel <= 0 => Err(CalibrationError(format!(
+                "open stop found at {}, no travel",
+                travel
+            ))),
+            result => result,
+        }
+    }
+
+    // run at a constant rate in one direction until `done` holds for the position
+    // and the counts travelled, a stop is requested or the deadline passes
+    async fn jog(
+        &mut self,
+        dir: Direction,
+        rate: f64,
+        stop: &mut watch::Receiver<Setpoint>,
+        deadline: Instant,
+        done: &(dyn Fn(&Self, isize) -> bool + Sync),
+    ) -> Result<Jog> {
+        let from = self.position();
+        self.set_direction(dir);
+        let mut session = self.start(rate)?;
+        let mut poll = tokio::time::interval(SWITCH_POLL);
+        let mut watchdog = Watchdog::new(self.stall, self.profile.steps_per_count);
+
+        self.record(true);
+        let result = loop {
+            if let Some(limit) = self.end_stop(dir) {
+                let _ = self.pwm.disable();
+                break Err(LimitError(limit));
+            }
+            if let Err(e) = watchdog.check(rate, self.position()) {
+                break Err(e);
+            }
+            if done(self, (self.position() - from).abs()) {
+                break Ok(Jog::Done);
+            }
+            select! {
+                e = session.next() => match e {
+                    Ok(e) => {
+                        self.track(e);
+                        watchdog.count();
+                    }
+                    Err(e) => break Err(e),
+                },
+                _ = poll.tick() => {}
+                changed = stop.changed() => {
+                    if changed.is_err() || matches!(*stop.borrow(), Setpoint::Stop) {
+                        break Ok(Jog::Stopped);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.into()) => break Ok(Jog::TimedOut),
+            }
+        };
+
+        let stopped = self.finish(session).await;
+        let result = result?;
+        stopped.map(|_| result)
+    }
+
+    // start the encoder thread and the step generator
+    fn start(&mut self, rate: f64) -> Result<EncoderSession> {
+        let session = EncoderSession::start(self.encoder.clone());
+        let started = self.pwm.set_frequency(rate).and_then(|_| self.pwm.enable());
+        match started {
+            Ok(_) => Ok(session),
+            Err(e) => {
+                session.kill();
+                Err(e)
+            }
+        }
+    }
+
+    // stop pwm, then let the encoder catch up with where the gate actually came to rest
+    async fn finish(&mut self, mut session: EncoderSession) -> Result<()> {
+        let disabled = self.pwm.disable();
+        session.kill();
+        while let Ok(e) = session.next().await {
+            self.track(e);
+        }
+        println!("exiting movement loop at {}...", self.position());
+        self.record(false);
+
+        session.join()?;
+        disabled
     }
+
+    fn reverse(&mut self, dir: Direction, rate: f64) -> Result<()> {
+        self.pwm.disable()?;
+        self.set_direction(dir);
+        self.pwm.set_frequency(rate)?;
+        self.pwm.enable()
+    }
+
+    // apply an encoder tick to the position
+    fn track(&self, e: EncoderSpin) -> isize {
+        let delta = match e {
+            Ccw => 1,
+            Cw => -1,
+        };
+        let p = self.pos.fetch_add(delta, Ordering::Relaxed) + delta;
+        println!("position: {}", p);
+        p
+    }
+}
+
+/// Compares the encoder counts of a move against those the steps sent should have produced
+struct Watchdog {
+    config: StallDetection,
+    steps_per_count: f64,
+    since: Instant,
+    last: Instant,
+    expected: f64,
+    counts: usize,
 }
 
-// todo;; should encoder always read
-// todo;; should encoder maintain its position
-fn read_encoder(
-    clock: Arc<InputPin>,
-    data: Arc<InputPin>,
-    tx: mpsc::Sender<EncoderSpin>,
-    mut kill: mpsc::Receiver<()>,
-) -> Result<()> {
-    let mut state: u16 = 0;
-    while kill.try_recv().is_err() {
-        let c = clock.read() as u16;
-        let d = data.read() as Level;
-
-        state = (&state << 1) | c | 0xe000;
-        if state == 0xf000 {
-            tx.blocking_send(d.into()).map_err(|_| EncoderTxError)?;
-            state = 0;
+impl Watchdog {
+    fn new(config: StallDetection, steps_per_count: f64) -> Self {
+        let now = Instant::now();
+        Watchdog {
+            config,
+            steps_per_count,
+            since: now,
+            last: now,
+            expected: 0.0,
+            counts: 0,
         }
     }
-    println!("encoder thread existing...");
 
-    Ok(())
+    fn reset(&mut self) {
+        *self = Watchdog::new(self.config, self.steps_per_count);
+    }
+
+    fn count(&mut self) {
+        self.counts += 1;
+    }
+
+    // account for the steps sent at rate since the last check,
+    // failing when a full window fell short of the expected counts
+    fn check(&mut self, rate: f64, position: isize) -> Result<()> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        let now = Instant::now();
+        self.expected += rate * (now - self.last).as_secs_f64() / self.steps_per_count;
+        self.last = now;
+        if now - self.since < self.config.window() {
+            return Ok(());
+        }
+
+        let (counts, expected) = (self.counts, self.expected);
+        self.reset();
+        if (counts as f64) < expected * self.config.min_ratio {
+            println!("stalled, {} of {:.0} expected counts", counts, expected);
+            return Err(Stall {
+                position,
+                counts,
+                expected: expected as usize,
+            });
+        }
+        Ok(())
+    }
 }
 
-fn steps_in_right_direction(current: isize, target: isize) -> (isize, Direction) {
-    let dir = if current < target { Open } else { Close };
-    let num = (current - target).abs();
-    (num, dir)
+/// Encoder read in a native thread for the duration of a move
+struct EncoderSession {
+    rx: mpsc::Receiver<EncoderSpin>,
+    kill: mpsc::Sender<()>,
+    handle: JoinHandle<Result<()>>,
 }
 
-fn target_is_met(current: isize, target: isize, dir: Direction) -> bool {
-    match dir {
-        Open => current >= target,
-        Close => current <= target,
+impl EncoderSession {
+    fn start(encoder: Arc<dyn Encoder>) -> Self {
+        let (tx, rx) = mpsc::channel(1);
+        let (kill, kill_rx) = mpsc::channel(1);
+        let handle = std::thread::spawn(move || encoder.read(tx, kill_rx));
+        Self { rx, kill, handle }
+    }
+
+    async fn next(&mut self) -> Result<EncoderSpin> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| EncoderThreadError("Encoder stopped".to_string()))
+    }
+
+    fn kill(&self) {
+        let _ = self.kill.try_send(());
+    }
+
+    fn join(self) -> Result<()> {
+        self.handle
+            .join()
+            .map_err(|_| EncoderThreadError("Join failed".to_string()))?
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-enum Direction {
-    Open,
-    Close,
+enum Jog {
+    Done,
+    Stopped,
+    TimedOut,
 }
 
-#[derive(Copy, Clone, Debug)]
-enum EncoderSpin {
-    Cw,
-    Ccw,
+/// Soft limits of travel in encoder counts
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SoftLimits {
+    pub min: Option<isize>,
+    pub max: Option<isize>,
 }
 
-impl From<Direction> for Level {
-    fn from(d: Direction) -> Self {
-        match d {
-            Open => Low,
-            Close => High,
-        }
+impl SoftLimits {
+    /// Whether a position is within the limits
+    pub fn contains(&self, position: isize) -> bool {
+        self.min.is_none_or(|min| position >= min) && self.max.is_none_or(|max| position <= max)
+    }
+
+    /// The position within the limits nearest to a position
+    pub fn clamp(&self, position: isize) -> isize {
+        let position = self.max.map_or(position, |max| position.min(max));
+        self.min.map_or(position, |min| position.max(min))
     }
 }
 
-impl From<Level> for Direction {
-    fn from(l: Level) -> Self {
-        match l {
-            Low => Open,
-            High => Close,
+/// A limit of travel that stopped the gate
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    SoftOpen,
+    SoftClose,
+    HardOpen,
+    HardClose,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::SoftOpen => f.write_str("soft open"),
+            Limit::SoftClose => f.write_str("soft close"),
+            Limit::HardOpen => f.write_str("hard open"),
+            Limit::HardClose => f.write_str("hard close"),
         }
     }
 }
 
-impl From<Level> for EncoderSpin {
-    fn from(l: Level) -> Self {
-        match l {
-            Low => Cw,
-            High => Ccw,
-        }
+/// Where a move in progress is headed
+#[derive(Copy, Clone, Debug)]
+pub enum Setpoint {
+    Target(isize),
+    Stop,
+}
+
+fn steps_in_right_direction(current: isize, target: isize) -> (isize, Direction) {
+    let dir = if current < target { Open } else { Close };
+    let num = (current - target).abs();
+    (num, dir)
+}
+
+fn target_is_met(current: isize, target: isize, dir: Direction) -> bool {
+    match dir {
+        Open => current >= target,
+        Close => current <= target,
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Direction {
+    Open,
+    Close,
+}
+
 impl Display for Direction {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
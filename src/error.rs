@@ -2,6 +2,8 @@ use core::result;
 
 use thiserror::Error;
 
+use crate::drive::Limit;
+
 pub type Result<T> = result::Result<T, Error>;
 
 /// An Error that can occur in this crate
@@ -13,6 +15,9 @@ pub enum Error {
     #[error("{0}")]
     PwmError(#[from] rppal::pwm::Error),
 
+    #[error("{0}")]
+    SpiError(#[from] rppal::spi::Error),
+
     #[error("Failed to send encoder reading")]
     EncoderTxError,
 
@@ -21,4 +26,32 @@ pub enum Error {
 
     #[error("Driver thread error: {0}")]
     DriverThreadError(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Position journal error: {0}")]
+    JournalError(String),
+
+    #[error("Homing failed: {0}")]
+    HomingError(String),
+
+    #[error("Calibration failed: {0}")]
+    CalibrationError(String),
+
+    #[error("Level sensor error: {0}")]
+    LevelError(String),
+
+    #[error("Schedule error: {0}")]
+    ScheduleError(String),
+
+    #[error("Stopped at {0} limit")]
+    LimitError(Limit),
+
+    #[error("Stalled at {position}, {counts} of {expected} expected encoder counts")]
+    Stall {
+        position: isize,
+        counts: usize,
+        expected: usize,
+    },
 }
@@ -0,0 +1,355 @@
+//! Flow measured by a pulse output meter, and closed-loop control of the gate to a flow rate.
+//!
+//! The control loop runs beside the gate actor. While the gate is in flow mode
+//! it moves the gate target toward the opening that gives the requested rate,
+//! through the same [`GatemanRef`] as any other client.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::delivery::{Delivery, Progress};
+use crate::gate::{Command, GatemanRef, Mode};
+use crate::hal::PulseCounter;
+use crate::protocol;
+use crate::status::Status;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowConfig {
+    /// Pulse input of the flow meter, no meter when not set
+    pub pin: Option<u8>,
+    /// Meter pulses per litre
+    pub k_factor: f64,
+    /// Milliseconds between flow samples and control updates
+    pub interval: u64,
+    /// Proportional gain, encoder counts per litre per second of error
+    pub kp: f64,
+    /// Integral gain, encoder counts per litre of accumulated error
+    pub ki: f64,
+    /// Derivative gain, encoder counts per litre per second squared
+    pub kd: f64,
+    /// Most encoder counts per second the control loop moves the target
+    pub max_slew: f64,
+    /// Smallest change of target in encoder counts the control loop sends
+    pub deadband: isize,
+}
+
+impl Default for FlowConfig {
+    fn default() -> Self {
+        Self {
+            pin: None,
+            k_factor: 10.0,
+            interval: 1000,
+            kp: 400.0,
+            ki: 200.0,
+            kd: 0.0,
+            max_slew: 400.0,
+            deadband: 5,
+        }
+    }
+}
+
+impl FlowConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+}
+
+/// How long a delivery resumed after a restart waits for the gate to take it
+/// up, which may first wait on another gate an interlock needs moved
+const RESUME_WITHIN: Duration = Duration::from_secs(60);
+
+/// Flow meter reading litres from a pulse counter
+pub struct FlowMeter {
+    counter: Arc<dyn PulseCounter>,
+    k_factor: f64,
+    last: (Instant, u64),
+}
+
+impl FlowMeter {
+    pub fn new(counter: Arc<dyn PulseCounter>, k_factor: f64) -> Self {
+        let last = (Instant::now(), counter.count());
+        Self {
+            counter,
+            k_factor,
+            last,
+        }
+    }
+
+    /// Litres per second since the last sample
+    pub fn sample(&mut self) -> f64 {
+        let now = (Instant::now(), self.counter.count());
+        let dt = now.0.duration_since(self.last.0).as_secs_f64();
+        let pulses = now.1.saturating_sub(self.last.1);
+        self.last = now;
+        if dt > 0.0 {
+            pulses as f64 / self.k_factor / dt
+        } else {
+            0.0
+        }
+    }
+
+    /// Litres since the meter started
+    pub fn volume(&self) -> f64 {
+        self.counter.count() as f64 / self.k_factor
+    }
+}
+
+/// Position form PID, from an error to an offset of the gate target in counts
+struct Pid {
+    kp: f64,
+    ki: f64,
+    kd: f64,
+    integral: f64,
+    last: Option<f64>,
+}
+
+impl Pid {
+    fn new(config: &FlowConfig) -> Self {
+        Self {
+            kp: config.kp,
+            ki: config.ki,
+            kd: config.kd,
+            integral: 0.0,
+            last: None,
+        }
+    }
+
+    fn update(&mut self, error: f64, dt: f64) -> f64 {
+        self.integral += error * dt;
+        let derivative = match self.last {
+            Some(last) if dt > 0.0 => (error - last) / dt,
+            _ => 0.0,
+        };
+        self.last = Some(error);
+        self.kp * error + self.ki * self.integral + self.kd * derivative
+    }
+
+    // take back the last integration, when its output could not be applied
+    fn unwind(&mut self, error: f64, dt: f64) {
+        self.integral -= error * dt;
+    }
+}
+
+/// Sample the flow meter for as long as the gate is up, publishing the rate.
+/// While the gate is in flow mode the target is moved toward the setpoint,
+/// limited to `max_slew` and kept within the bounds of the gate. While it is delivering
+/// the flow is totalized, and the gate closed once the volume has passed.
+pub async fn regulate(
+    gm: GatemanRef,
+    mut meter: FlowMeter,
+    config: FlowConfig,
+    mut delivery: Delivery,
+) {
+    let mut interval = tokio::time::interval(config.interval());
+    let mut pid = Pid::new(&config);
+    // the target the loop is moving and the position it started from
+    let mut control: Option<(f64, f64)> = None;
+    let mut sent = 0;
+    let mut last = Instant::now();
+    let mut volume = meter.volume();
+
+    if let Some(progress) = delivery.resumed() {
+        resume(&gm, progress).await;
+    }
+
+    while !gm.sender.is_closed() {
+        interval.tick().await;
+        let rate = meter.sample();
+        let dt = last.elapsed().as_secs_f64();
+        last = Instant::now();
+        let litres = meter.volume() - volume;
+        volume += litres;
+        gm.publish(Status::Flow(rate));
+
+        let snapshot = gm.snapshot();
+        if let Some(progress) = delivery.update(snapshot.mode, litres) {
+            gm.publish(Status::Delivery(progress));
+            if progress.complete() && gm.sender.send(Command::Close).await.is_err() {
+                break;
+            }
+        }
+
+        let (setpoint, (lo, hi)) = match (snapshot.mode, gm.bounds()) {
+            (Mode::Flow { setpoint }, Some(bounds)) => (setpoint, bounds),
+            _ => {
+                control = None;
+                continue;
+            }
+        };
+
+        // take over from wherever the gate is when flow mode starts
+        let (target, base) = control.get_or_insert_with(|| {
+            pid = Pid::new(&config);
+            sent = snapshot.position;
+            (snapshot.position as f64, snapshot.position as f64)
+        });
+
+        let error = setpoint - rate;
+        let desired = *base + pid.update(error, dt);
+        let (lo, hi) = (lo as f64, hi as f64);
+        if (desired > hi && error > 0.0) || (desired < lo && error < 0.0) {
+            pid.unwind(error, dt);
+        }
+
+        let slew = config.max_slew * dt;
+        *target = (*target + (desired - *target).clamp(-slew, slew)).clamp(lo, hi);
+        let next = target.round() as isize;
+        if (next - sent).abs() >= config.deadband {
+            sent = next;
+            if gm.sender.send(Command::Adjust(next)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+// start a delivery interrupted by a restart again, unless the gate would refuse
+// it, and wait a while for the gate to take it up, as the first update drops
+// the delivery when the gate is not delivering
+async fn resume(gm: &GatemanRef, progress: Progress) {
+    let cmd = Command::Deliver(progress.volume);
+    if let Some((_, reason)) = protocol::refusal(&cmd, gm) {
+        eprintln!("cannot resume delivery, {}", reason);
+        return;
+    }
+    let mut status = gm.subscribe();
+    if gm.sender.send(cmd).await.is_err() {
+        return;
+    }
+    let delivering = async {
+        while let Some(status) = status.recv().await {
+            if let Status::Snapshot(snapshot) = status {
+                if matches!(snapshot.mode, Mode::Deliver { .. }) {
+                    return;
+                }
+            }
+        }
+    };
+    if tokio::time::timeout(RESUME_WITHIN, delivering)
+        .await
+        .is_err()
+    {
+        eprintln!("gate did not take up the resumed delivery");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::calibration::Opening;
+    use crate::gate::{Sensors, State};
+    use crate::status::Snapshot;
+    use crate::testing::{self, until};
+
+    struct Pulses(AtomicU64);
+
+    impl PulseCounter for Pulses {
+        fn count(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    // a closed gate of 4000 counts passing 1 l/s per 1000 counts open, its
+    // meter sampled every 100 ms and the loop tuned to settle in a few seconds
+    fn start() -> GatemanRef {
+        let mut config = testing::config();
+        config.flow.interval = 100;
+        config.flow.k_factor = 1000.0;
+        config.flow.kp = 500.0;
+        config.flow.ki = 1000.0;
+        config.flow.max_slew = 2000.0;
+        let (gm, gate) = testing::start(0, &config, &BTreeMap::new());
+        let meter = FlowMeter::new(Arc::new(gate.flow_meter(1000.0)), 1000.0);
+        let sensors = Sensors {
+            flow: Some(meter),
+            ..Default::default()
+        };
+        gm.control(sensors, &config);
+        gm
+    }
+
+    #[test]
+    fn pid_responds_to_the_error() {
+        let config = FlowConfig {
+            kp: 2.0,
+            ki: 1.0,
+            kd: 0.5,
+            ..Default::default()
+        };
+        let mut pid = Pid::new(&config);
+        assert_eq!(pid.update(1.0, 1.0), 3.0);
+        // the integral builds while the error lasts
+        assert_eq!(pid.update(1.0, 1.0), 4.0);
+        // and holds the output once it is gone, less the fall in the error
+        assert_eq!(pid.update(0.0, 1.0), 1.5);
+    }
+
+    #[test]
+    fn pid_unwinds_an_integration_it_could_not_apply() {
+        let config = FlowConfig {
+            kp: 0.0,
+            ki: 1.0,
+            ..Default::default()
+        };
+        let mut pid = Pid::new(&config);
+        assert_eq!(pid.update(1.0, 1.0), 1.0);
+        assert_eq!(pid.update(1.0, 1.0), 2.0);
+        pid.unwind(1.0, 1.0);
+        assert_eq!(pid.update(0.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn meter_reads_litres_and_their_rate() {
+        let pulses = Arc::new(Pulses(AtomicU64::new(500)));
+        let mut meter = FlowMeter::new(pulses.clone(), 100.0);
+        assert_eq!(meter.volume(), 5.0);
+        std::thread::sleep(Duration::from_millis(200));
+        pulses.0.store(700, Ordering::Relaxed);
+        // 2 l over a little more than 200 ms
+        let rate = meter.sample();
+        assert!(rate > 8.0 && rate <= 10.0, "{}", rate);
+        assert_eq!(meter.volume(), 7.0);
+        assert_eq!(meter.sample(), 0.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn holds_the_flow_at_the_setpoint() {
+        let gm = start();
+        gm.sender.send(Command::SetFlow(2.0)).await.unwrap();
+        let near = |s: &Snapshot| s.flow.is_some_and(|r| (r - 2.0).abs() < 0.1);
+        until(&gm, |s| near(s) && s.mode == (Mode::Flow { setpoint: 2.0 })).await;
+        // and stays there
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let snapshot = gm.snapshot();
+        assert!(near(&snapshot), "{:?}", snapshot);
+        assert!((snapshot.position - 2000).abs() < 200, "{:?}", snapshot);
+
+        // a manual command takes the gate out of flow mode
+        gm.sender.send(Command::Close).await.unwrap();
+        let snapshot = until(&gm, |s| matches!(s.state, State::Stopped(0))).await;
+        assert_eq!(snapshot.mode, Mode::Manual);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delivers_a_volume_and_closes() {
+        let gm = start();
+        assert_eq!(gm.delivery_opening(), Opening::Percent(100.0));
+        gm.sender.send(Command::Deliver(3.0)).await.unwrap();
+        let delivered = |s: &Snapshot| s.delivery.is_some_and(|p| p.complete());
+        let snapshot = until(&gm, |s| {
+            delivered(s) && matches!(s.state, State::Stopped(0))
+        })
+        .await;
+        assert_eq!(snapshot.mode, Mode::Manual);
+        let progress = snapshot.delivery.unwrap();
+        assert_eq!(progress.volume, 3.0);
+        // closed within an interval or so of the volume passing
+        assert!(progress.delivered < 3.5, "{:?}", progress);
+    }
+}
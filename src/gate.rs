@@ -1,99 +1,1091 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::UnboundedSender;
+use serde::Serialize;
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
 
-use crate::drive::Drive;
+use crate::calibration::{Calibration, Opening};
+use crate::config::{Failsafe, GateConfig, Homing, Safety};
+use crate::delivery::Delivery;
+use crate::drive::{Drive, Limit, Setpoint, SoftLimits};
+use crate::flow::{self, FlowMeter};
 use crate::gate::State::*;
-use crate::Result;
+use crate::hydraulics::HydraulicsConfig;
+use crate::interlock::{Check, Interlock};
+use crate::level::{self, LevelGauge};
+use crate::recipe::{self, Recipe};
+use crate::status::{Snapshot, Status, StatusBus, Subscriber};
+use crate::{Error, Result};
 
 #[derive(Debug, Clone)]
 pub enum Command {
     Close,
-    Open(u8),
-    Connect(UnboundedSender<String>),
+    Open(Opening),
+    Stop,
+    Home,
+    Calibrate,
+    ResetFault,
+    /// The link to the clients was lost for the given reason
+    Failsafe(&'static str),
+    /// Hold a flow rate in litres per second
+    SetFlow(f64),
+    /// Open to the delivery opening until a volume in litres has passed
+    Deliver(f64),
+    /// Hold the upstream level in metres
+    HoldLevel(f64),
+    /// Run a recipe, or the configured recipe named when it has no steps
+    RunRecipe(Recipe),
+    PauseRecipe,
+    ResumeRecipe,
+    AbortRecipe,
+    /// The recipe run with the given id ended on its own
+    EndRecipe(u64),
+    /// Move to a target in counts set by a control loop, without leaving its mode
+    Adjust(isize),
+    /// Move to an opening, or stop where the gate is when None, and hold there unattended
+    Hold(Option<Opening>),
     Nop,
 }
 
+impl Command {
+    // commands from clients that take the gate out of any control loop
+    fn takes_control(&self) -> bool {
+        !matches!(
+            self,
+            Command::ResetFault
+                | Command::Failsafe(_)
+                | Command::SetFlow(_)
+                | Command::Deliver(_)
+                | Command::HoldLevel(_)
+                | Command::RunRecipe(_)
+                | Command::PauseRecipe
+                | Command::ResumeRecipe
+                | Command::AbortRecipe
+                | Command::EndRecipe(_)
+                | Command::Adjust(_)
+                | Command::Hold(_)
+                | Command::Nop
+        )
+    }
+
+    // commands that show a client is still there, control loops run on their own
+    fn keeps_alive(&self) -> bool {
+        !matches!(self, Command::Adjust(_) | Command::EndRecipe(_))
+    }
+}
+
+/// Who sets the gate target: clients directly, or a control loop toward a setpoint
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mode {
+    Manual,
+    /// Litres per second
+    Flow {
+        setpoint: f64,
+    },
+    /// Litres
+    Deliver {
+        volume: f64,
+    },
+    /// Metres
+    HoldLevel {
+        setpoint: f64,
+    },
+    /// Stepping through a recipe
+    Recipe {
+        paused: bool,
+    },
+    /// Held where the schedule set the gate
+    Scheduled,
+}
+
+impl Mode {
+    // whether control loops may move the gate
+    fn adjustable(&self) -> bool {
+        !matches!(
+            self,
+            Mode::Manual | Mode::Recipe { paused: true } | Mode::Scheduled
+        )
+    }
+
+    // whether the gate runs on its own, without a client, so the failsafe leaves it be
+    fn unattended(&self) -> bool {
+        *self != Mode::Manual
+    }
+}
+
+/// What a command asks of the motion of the gate, once the actor has taken it
+#[derive(Debug)]
+enum Motion {
+    /// Leave the gate as it is, moving or not
+    Keep,
+    MoveTo(isize),
+    Stop,
+    Home,
+    Calibrate,
+    ResetFault,
+}
+
+/// Sensors attached to a gate
+#[derive(Default)]
+pub struct Sensors {
+    pub flow: Option<FlowMeter>,
+    pub level: Option<LevelGauge>,
+    /// Level downstream of the gate, for the flow estimate
+    pub downstream: Option<LevelGauge>,
+}
+
+/// Gate state, positions are in encoder counts
 #[derive(Debug, Clone)]
-enum State {
-    Stopped(u8),
-    Moving(u8),
+pub enum State {
+    Stopped(isize),
+    Moving(isize),
+    Homing,
+    Calibrating,
+    /// Stopped by a limit of travel, moves away from it are allowed once clients reset it
+    LimitFault(Limit),
+    /// Stopped because the encoder fell behind the steps sent
+    Stalled,
+    /// Halted by an error, only a fault reset is accepted
+    Faulted(String),
 }
 
 #[derive(Clone)]
 pub struct GatemanRef {
     pub sender: mpsc::Sender<Command>,
+    status: StatusBus,
+    calibration: Arc<Mutex<Calibration>>,
+    flow_meter: bool,
+    level_sensor: bool,
+    delivery: Opening,
+    hydraulics: HydraulicsConfig,
+    recipes: Arc<BTreeMap<String, Recipe>>,
+    client_timeout: Duration,
+    failsafe: Failsafe,
+    limits: SoftLimits,
+    interlock: Interlock,
+    /// Websocket clients connected to the gate
+    clients: Arc<AtomicUsize>,
 }
 
 impl GatemanRef {
-    pub fn new(driver: Drive) -> Self {
+    /// Start the actor of a gate, whose control loops are left to `control`
+    pub fn new(
+        driver: Drive,
+        sensors: &Sensors,
+        config: &GateConfig,
+        recipes: &BTreeMap<String, Recipe>,
+        interlock: Interlock,
+    ) -> Self {
         let (tx, rx) = mpsc::channel(10);
-        let actor = Gateman::new(driver, rx);
+        let status = StatusBus::new(Snapshot {
+            state: Stopped(driver.position()),
+            position: driver.position(),
+            verified: driver.verified(),
+            mode: Mode::Manual,
+            flow: None,
+            delivery: None,
+            level: None,
+            downstream: None,
+            recipe: None,
+        });
+        let calibration = Arc::new(Mutex::new(config.calibration.clone()));
+        let (recipe_tx, recipe_rx) = watch::channel(None);
+        let actor = Gateman::new(
+            driver,
+            rx,
+            status.clone(),
+            calibration.clone(),
+            recipe_tx,
+            interlock.clone(),
+            config,
+        );
         tokio::spawn(execute(actor));
-        GatemanRef { sender: tx }
+        let gm = GatemanRef {
+            sender: tx,
+            status,
+            calibration,
+            flow_meter: sensors.flow.is_some(),
+            level_sensor: sensors.level.is_some(),
+            delivery: config.delivery.opening,
+            hydraulics: config.hydraulics.clone(),
+            recipes: Arc::new(recipes.clone()),
+            client_timeout: config.safety.client(),
+            failsafe: config.safety.failsafe,
+            limits: config.limits,
+            interlock,
+            clients: Arc::new(AtomicUsize::new(0)),
+        };
+        tokio::spawn(recipe::run(gm.clone(), recipe_rx));
+        gm
+    }
+
+    /// Start the control loops on the sensors of the gate, once the interlocks
+    /// are bound, as a delivery resumed after a restart is checked against them
+    pub fn control(&self, sensors: Sensors, config: &GateConfig) {
+        if let Some(meter) = sensors.flow {
+            tokio::spawn(flow::regulate(
+                self.clone(),
+                meter,
+                config.flow.clone(),
+                Delivery::new(config.delivery.totalizer()),
+            ));
+        }
+        if let Some(gauge) = sensors.level {
+            tokio::spawn(level::hold(self.clone(), gauge, config.level.clone()));
+        }
+        if let Some(gauge) = sensors.downstream {
+            tokio::spawn(level::watch_downstream(
+                self.clone(),
+                gauge,
+                config.level.clone(),
+            ));
+        }
+    }
+
+    /// Subscribe to the status of the gate, starting with a snapshot of its current state
+    pub fn subscribe(&self) -> Subscriber {
+        self.status.subscribe()
+    }
+
+    /// The current state and position of the gate
+    pub fn snapshot(&self) -> Snapshot {
+        self.status.snapshot()
+    }
+
+    pub fn publish(&self, status: Status) {
+        self.status.publish(status)
+    }
+
+    /// How long a websocket client may be silent before it is dropped
+    pub fn client_timeout(&self) -> Duration {
+        self.client_timeout
+    }
+
+    /// Count a websocket client connecting to the gate, until it leaves
+    pub fn join(&self) {
+        self.clients.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Count a websocket client leaving the gate, true when no other is connected
+    pub fn leave(&self) -> bool {
+        self.clients.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+
+    pub fn has_flow_meter(&self) -> bool {
+        self.flow_meter
+    }
+
+    pub fn has_level_sensor(&self) -> bool {
+        self.level_sensor
+    }
+
+    /// How far the gate opens during a delivery
+    pub fn delivery_opening(&self) -> Opening {
+        self.delivery
+    }
+
+    /// The recipe to run for a request, looking up the configured recipe by name
+    /// when it has no steps, None when there is no such recipe
+    pub fn recipe(&self, recipe: &Recipe) -> Option<Recipe> {
+        if !recipe.steps.is_empty() {
+            return Some(recipe.clone());
+        }
+        let name = recipe.name.as_ref()?;
+        Some(Recipe {
+            name: Some(name.clone()),
+            steps: self.recipes.get(name)?.steps.clone(),
+        })
+    }
+
+    /// Whether the interlocks let the gate take a command, Clear for commands that do not move it
+    pub fn interlock(&self, cmd: &Command) -> Check {
+        match self.destination(cmd) {
+            Some(target) => self.interlock.check(target),
+            None => Check::Clear,
+        }
+    }
+
+    /// The target of a move outside the soft limits, None when the command
+    /// moves the gate within them or does not move it to a target
+    pub fn beyond_limits(&self, cmd: &Command) -> Option<isize> {
+        match cmd {
+            Command::Open(_) | Command::Close | Command::Deliver(_) | Command::Adjust(_) => self
+                .destination(cmd)
+                .filter(|target| !self.limits.contains(*target)),
+            _ => None,
+        }
+    }
+
+    // where a command sends the gate, None for commands that do not move it
+    fn destination(&self, cmd: &Command) -> Option<isize> {
+        let failsafe = (self.failsafe, &self.limits);
+        destination(cmd, &self.calibration, self.delivery, failsafe)
+    }
+
+    /// Encoder counts for an opening, None when the gate is not calibrated for its unit
+    pub fn counts(&self, opening: Opening) -> Option<isize> {
+        counts(&self.calibration, opening)
+    }
+
+    /// Positions control loops keep the gate between, from the soft limits and
+    /// the travel as last calibrated, None when neither bounds the opening
+    pub fn bounds(&self) -> Option<(isize, isize)> {
+        let travel = {
+            let calibration = self.calibration.lock().expect("calibration lock poisoned");
+            calibration.travel
+        };
+        Some((self.limits.min.unwrap_or(0), self.limits.max.or(travel)?))
+    }
+
+    /// Percent open at a position, None when the gate is not calibrated
+    pub fn percent(&self, position: isize) -> Option<f64> {
+        let calibration = self.calibration.lock().expect("calibration lock poisoned");
+        calibration.percent(position)
+    }
+
+    /// Opening area at a position, None without an area curve
+    pub fn area(&self, position: isize) -> Option<f64> {
+        let calibration = self.calibration.lock().expect("calibration lock poisoned");
+        calibration.area(position)
+    }
+
+    /// Litres per second estimated through the gate at a position from the last
+    /// levels read, None without a gate geometry, a stroke or an upstream head
+    pub fn estimated_flow(&self, position: isize) -> Option<f64> {
+        let hydraulics = &self.hydraulics;
+        let gate = hydraulics.gate?;
+        let millimetres = {
+            let calibration = self.calibration.lock().expect("calibration lock poisoned");
+            calibration.millimetres(position)?
+        };
+        let snapshot = self.status.snapshot();
+        let upstream = snapshot
+            .level
+            .map(|l| l - hydraulics.sill)
+            .or(hydraulics.upstream_head)?;
+        let downstream = snapshot.downstream.map(|l| l - hydraulics.sill);
+        Some(gate.discharge(millimetres / 1000.0, upstream, downstream))
     }
 }
 
 struct Gateman {
     driver: Drive,
     cmdbus: mpsc::Receiver<Command>,
-    statbus: Option<UnboundedSender<String>>,
+    statbus: StatusBus,
     state: State,
+    homing: Homing,
+    interlock: Interlock,
+    /// A command held back until the gate is still, by a move or by waiting on another gate
+    deferred: Option<Command>,
+    keepalive: KeepAlive,
+    dispatch: Dispatch,
 }
 
 impl Gateman {
-    pub fn new(driver: Drive, rx: mpsc::Receiver<Command>) -> Self {
+    pub fn new(
+        driver: Drive,
+        rx: mpsc::Receiver<Command>,
+        statbus: StatusBus,
+        calibration: Arc<Mutex<Calibration>>,
+        recipe: watch::Sender<Option<(u64, Recipe)>>,
+        interlock: Interlock,
+        config: &GateConfig,
+    ) -> Self {
+        let state = Stopped(driver.position());
         Gateman {
             driver,
             cmdbus: rx,
-            statbus: None,
-            state: Stopped(0),
+            statbus: statbus.clone(),
+            state,
+            homing: config.homing.clone(),
+            interlock,
+            deferred: None,
+            keepalive: KeepAlive {
+                timeout: config.safety.keepalive(),
+                heard: Instant::now(),
+                fired: None,
+            },
+            dispatch: Dispatch {
+                statbus,
+                mode: Mode::Manual,
+                calibration,
+                safety: config.safety.clone(),
+                limits: config.limits,
+                delivery: config.delivery.opening,
+                recipe,
+                runs: 0,
+            },
         }
     }
 
     pub async fn handle(&mut self, cmd: Command) -> Result<()> {
+        if let Faulted(_) = self.state {
+            if !matches!(cmd, Command::ResetFault | Command::Nop) {
+                eprintln!("ignoring {:?} while faulted", cmd);
+                return Ok(());
+            }
+        }
+        if cmd.takes_control() {
+            self.dispatch.set_mode(Mode::Manual);
+        }
+        if !self.cleared(&cmd).await {
+            return Ok(());
+        }
+        match self.dispatch.take(cmd) {
+            Motion::Keep => {}
+            Motion::MoveTo(target) => {
+                self.move_to(target).await?;
+                eprintln!("completed move, {:?}", self.state);
+            }
+            // a stop leaves a limit fault or a stall latched until it is reset
+            Motion::Stop if matches!(self.state, Stopped(_)) => {
+                self.set_state(Stopped(self.driver.position()))
+            }
+            Motion::Stop => {}
+            Motion::Home => {
+                eprintln!("homing");
+                self.home().await?;
+            }
+            Motion::Calibrate => {
+                eprintln!("calibrating");
+                self.calibrate().await?;
+            }
+            Motion::ResetFault => {
+                eprintln!("resetting fault, {:?}", self.state);
+                self.set_state(Stopped(self.driver.position()));
+            }
+        }
+        Ok(())
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        let mut snapshot = self.statbus.snapshot();
+        snapshot.state = self.state.clone();
+        snapshot.position = self.driver.position();
+        snapshot.verified = self.driver.verified();
+        snapshot.mode = self.dispatch.mode;
+        self.statbus.publish(Status::Snapshot(snapshot));
+    }
+
+    // check the interlocks on where a command sends the gate, first moving the
+    // gate another depends on when a rule says to, false when it is refused
+    async fn cleared(&mut self, cmd: &Command) -> bool {
+        if matches!(cmd, Command::Adjust(_)) && !self.dispatch.mode.adjustable() {
+            return true;
+        }
+        let target = match self.dispatch.destination(cmd) {
+            Some(target) => target,
+            None => return true,
+        };
+        let mut moved = false;
+        loop {
+            match self.interlock.check(target) {
+                Check::Clear => return true,
+                Check::MoveFirst { gate, opening, .. } if !moved => {
+                    eprintln!("moving {} to {} first", gate, opening);
+                    if !self.move_first(&gate, opening).await {
+                        return false;
+                    }
+                    moved = true;
+                }
+                Check::Refused(reason) | Check::MoveFirst { reason, .. } => {
+                    eprintln!("refusing {:?}, {}", cmd, reason);
+                    self.dispatch.refused();
+                    return false;
+                }
+            }
+        }
+    }
+
+    // move another gate and wait for it to stop, which any command but a
+    // keep-alive interrupts, holding the command back until the gate is still
+    async fn move_first(&mut self, gate: &str, opening: Opening) -> bool {
+        let other = match self.interlock.gate(gate) {
+            Some(other) => other,
+            None => return false,
+        };
+        let cmd = Command::Open(opening);
+        if let Check::Refused(reason) | Check::MoveFirst { reason, .. } = other.interlock(&cmd) {
+            eprintln!("cannot move {} first, {}", gate, reason);
+            return false;
+        }
+        let target = match other.counts(opening) {
+            Some(target) => target,
+            None => return false,
+        };
+        let mut status = other.subscribe();
+        if other.sender.send(cmd).await.is_err() {
+            return false;
+        }
+        let arrival = async move {
+            // the first snapshot can be from before the move
+            let mut started = false;
+            while let Some(status) = status.recv().await {
+                let snapshot = match status {
+                    Status::Snapshot(snapshot) => snapshot,
+                    _ => continue,
+                };
+                match snapshot.state {
+                    Moving(to) => started |= to == target,
+                    Stopped(at) if started || at == target => return true,
+                    Stopped(_) => {}
+                    _ => return false,
+                }
+            }
+            false
+        };
+        tokio::pin!(arrival);
+        loop {
+            select! {
+                arrived = &mut arrival => {
+                    if !arrived {
+                        eprintln!("{} did not get to {}", gate, opening);
+                    }
+                    return arrived;
+                }
+                Some(cmd) = self.cmdbus.recv() => {
+                    self.keepalive.hear(&cmd);
+                    if !matches!(cmd, Command::Nop) {
+                        eprintln!("{:?} replaces the move waiting on {}", cmd, gate);
+                        self.deferred = Some(cmd);
+                        return false;
+                    }
+                }
+                _ = tokio::time::sleep_until(self.keepalive.deadline()) => {
+                    if self.keepalive.lapsed() {
+                        eprintln!("keep-alive timeout, no longer waiting on {}", gate);
+                        self.deferred = Some(Command::Failsafe("keep-alive timeout"));
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    // run a move while continuing to service the command bus,
+    // the latest open or close replaces the target of the move
+    async fn move_to(&mut self, target: isize) -> Result<()> {
+        if !self.dispatch.limits.contains(target) {
+            eprintln!("refusing move to {}, outside the soft limits", target);
+            self.dispatch.refused();
+            return Ok(());
+        }
+        self.set_state(Moving(target));
+        self.driver.enable();
+
+        let (control, setpoint) = watch::channel(Setpoint::Target(target));
+        let result = {
+            let movement = self.driver.follow(setpoint, Some(self.statbus.clone()));
+            tokio::pin!(movement);
+            loop {
+                // a keep-alive running out during the move fails safe there and then
+                let cmd = select! {
+                    r = &mut movement => break r,
+                    Some(cmd) = self.cmdbus.recv() => {
+                        self.keepalive.hear(&cmd);
+                        cmd
+                    }
+                    _ = tokio::time::sleep_until(self.keepalive.deadline()) => {
+                        if !self.keepalive.lapsed() {
+                            continue;
+                        }
+                        Command::Failsafe("keep-alive timeout")
+                    }
+                };
+                if cmd.takes_control() {
+                    self.dispatch.set_mode(Mode::Manual);
+                }
+                // a retarget breaking an interlock is refused, or stops
+                // the move until the gate it depends on has moved
+                let retarget = match cmd {
+                    Command::Home | Command::Calibrate => None,
+                    Command::Adjust(_) if !self.dispatch.mode.adjustable() => None,
+                    _ => self.dispatch.destination(&cmd),
+                };
+                if retarget.is_some_and(|target| !self.dispatch.limits.contains(target)) {
+                    eprintln!("refusing {:?}, outside the soft limits", cmd);
+                    self.dispatch.refused();
+                    continue;
+                }
+                if let Some(target) = retarget {
+                    match self.interlock.check(target) {
+                        Check::Clear => {}
+                        // a failsafe the interlocks refuse leaves the gate where it is
+                        Check::Refused(reason) if matches!(cmd, Command::Failsafe(_)) => {
+                            eprintln!("refusing {:?}, {}, stopping instead", cmd, reason);
+                            self.deferred = None;
+                            let _ = control.send(Setpoint::Stop);
+                            continue;
+                        }
+                        Check::Refused(reason) => {
+                            eprintln!("refusing {:?}, {}", cmd, reason);
+                            self.dispatch.refused();
+                            continue;
+                        }
+                        Check::MoveFirst { gate, .. } => {
+                            eprintln!("stopping to move {} first", gate);
+                            self.deferred = Some(cmd);
+                            let _ = control.send(Setpoint::Stop);
+                            continue;
+                        }
+                    }
+                }
+                match self.dispatch.take(cmd) {
+                    Motion::MoveTo(target) => {
+                        eprintln!("retargeting to {}", target);
+                        self.deferred = None;
+                        let _ = control.send(Setpoint::Target(target));
+                        self.state = Moving(target);
+                        self.statbus.set_state(self.state.clone());
+                    }
+                    Motion::Stop => {
+                        self.deferred = None;
+                        let _ = control.send(Setpoint::Stop);
+                    }
+                    motion @ (Motion::Home | Motion::Calibrate) => {
+                        eprintln!("ignoring {:?} while moving", motion)
+                    }
+                    Motion::Keep | Motion::ResetFault => {}
+                }
+            }
+        };
+
+        if result.as_ref().is_err_and(|e| self.motion_fault(e)) {
+            return Ok(());
+        }
+        result?;
+        self.driver.disable();
+        self.set_state(Stopped(self.driver.position()));
+        Ok(())
+    }
+
+    // run the homing routine, which only a stop interrupts
+    async fn home(&mut self) -> Result<()> {
+        self.set_state(Homing);
+        self.driver.enable();
+
+        let homing = self.homing.clone();
+        let (control, stop) = watch::channel(Setpoint::Target(homing.offset));
+        let result = {
+            let routine = self.driver.home(&homing, stop, Some(self.statbus.clone()));
+            tokio::pin!(routine);
+            loop {
+                select! {
+                    r = &mut routine => break r,
+                    Some(cmd) = self.cmdbus.recv() => {
+                        self.keepalive.hear(&cmd);
+                        match cmd {
+                            Command::Stop => {
+                                eprintln!("stop requested");
+                                let _ = control.send(Setpoint::Stop);
+                            }
+                            Command::Nop => {}
+                            cmd => eprintln!("ignoring {:?} while homing", cmd),
+                        }
+                    }
+                    // stop, and fail safe once the gate is still
+                    _ = tokio::time::sleep_until(self.keepalive.deadline()) => {
+                        if self.keepalive.lapsed() {
+                            eprintln!("keep-alive timeout while homing");
+                            self.deferred = Some(Command::Failsafe("keep-alive timeout"));
+                            let _ = control.send(Setpoint::Stop);
+                        }
+                    }
+                }
+            }
+        };
+
+        if result.as_ref().is_err_and(|e| self.motion_fault(e)) {
+            return Ok(());
+        }
+        let homed = result?;
+        eprintln!("homing {}", if homed { "complete" } else { "stopped" });
+        self.driver.disable();
+        self.set_state(Stopped(self.driver.position()));
+        Ok(())
+    }
+
+    // measure the travel of the gate, which only a stop interrupts
+    async fn calibrate(&mut self) -> Result<()> {
+        self.set_state(Calibrating);
+        self.driver.enable();
+
+        let (rate, timeout) = {
+            let calibration = self
+                .dispatch
+                .calibration
+                .lock()
+                .expect("calibration lock poisoned");
+            (calibration.rate, calibration.timeout())
+        };
+        let (control, stop) = watch::channel(Setpoint::Target(0));
+        let result = {
+            let routine = self
+                .driver
+                .calibrate(rate, timeout, stop, Some(self.statbus.clone()));
+            tokio::pin!(routine);
+            loop {
+                select! {
+                    r = &mut routine => break r,
+                    Some(cmd) = self.cmdbus.recv() => {
+                        self.keepalive.hear(&cmd);
+                        match cmd {
+                            Command::Stop => {
+                                eprintln!("stop requested");
+                                let _ = control.send(Setpoint::Stop);
+                            }
+                            Command::Nop => {}
+                            cmd => eprintln!("ignoring {:?} while calibrating", cmd),
+                        }
+                    }
+                    // stop, and fail safe once the gate is still
+                    _ = tokio::time::sleep_until(self.keepalive.deadline()) => {
+                        if self.keepalive.lapsed() {
+                            eprintln!("keep-alive timeout while calibrating");
+                            self.deferred = Some(Command::Failsafe("keep-alive timeout"));
+                            let _ = control.send(Setpoint::Stop);
+                        }
+                    }
+                }
+            }
+        };
+
+        match result? {
+            Some(travel) => {
+                eprintln!("calibrated, travel {}", travel);
+                let mut calibration = self
+                    .dispatch
+                    .calibration
+                    .lock()
+                    .expect("calibration lock poisoned");
+                if let Err(e) = calibration.save(travel) {
+                    eprintln!("failed to save calibration: {}", e);
+                }
+            }
+            None => eprintln!("calibration stopped"),
+        }
+        self.driver.disable();
+        self.set_state(Stopped(self.driver.position()));
+        Ok(())
+    }
+
+    // faults that stop the gate but leave it able to move again
+    fn motion_fault(&mut self, error: &Error) -> bool {
+        let state = match error {
+            Error::LimitError(limit) => LimitFault(*limit),
+            Error::Stall { .. } => Stalled,
+            _ => return false,
+        };
+        eprintln!("{}", error);
+        self.driver.disable();
+        self.dispatch.mode = Mode::Manual;
+        self.set_state(state);
+        true
+    }
+
+    // hold the gate disabled until the fault is reset
+    fn fault(&mut self, error: Error) {
+        eprintln!("gate faulted: {}", error);
+        self.driver.halt();
+        self.dispatch.mode = Mode::Manual;
+        self.set_state(Faulted(error.to_string()));
+    }
+
+    fn faulted(&self) -> bool {
+        matches!(self.state, Faulted(_))
+    }
+}
+
+/// When the gate was last heard from, for the keep-alive failsafe, which fires
+/// once per silence wherever the actor is waiting
+struct KeepAlive {
+    timeout: Duration,
+    /// When a command last kept the gate alive, wherever it was received
+    heard: Instant,
+    /// When the gate had last been heard from as the failsafe last fired
+    fired: Option<Instant>,
+}
+
+impl KeepAlive {
+    fn hear(&mut self, cmd: &Command) {
+        if cmd.keeps_alive() {
+            self.heard = Instant::now();
+        }
+    }
+
+    // when the silence runs out, or when to look again once it has
+    fn deadline(&self) -> Instant {
+        match self.fired {
+            Some(heard) if heard == self.heard => Instant::now() + self.timeout,
+            _ => self.heard + self.timeout,
+        }
+    }
+
+    // whether the silence has run out for the first time
+    fn lapsed(&mut self) -> bool {
+        let first = self.fired != Some(self.heard);
+        self.fired = Some(self.heard);
+        first
+    }
+}
+
+/// What commands set on the gate, apart from its drive, so the actor takes
+/// them the same way whether the gate is still or a move is holding the drive
+struct Dispatch {
+    statbus: StatusBus,
+    mode: Mode,
+    calibration: Arc<Mutex<Calibration>>,
+    safety: Safety,
+    limits: SoftLimits,
+    delivery: Opening,
+    /// Recipes handed to the recipe runner, with the id of each run
+    recipe: watch::Sender<Option<(u64, Recipe)>>,
+    runs: u64,
+}
+
+impl Dispatch {
+    // where a command sends the gate, None for commands that do not move it,
+    // which takes in a failsafe held off while the gate runs unattended
+    fn destination(&self, cmd: &Command) -> Option<isize> {
+        if matches!(cmd, Command::Failsafe(_)) && self.mode.unattended() {
+            return None;
+        }
+        let failsafe = (self.safety.failsafe, &self.limits);
+        destination(cmd, &self.calibration, self.delivery, failsafe)
+    }
+
+    // take a command into the mode of the gate, returning what it asks of the
+    // motion, which is the same whether the gate is still or already moving
+    fn take(&mut self, cmd: Command) -> Motion {
         match cmd {
-            Command::Nop => {}
+            Command::Nop => Motion::Keep,
+            Command::SetFlow(setpoint) => {
+                eprintln!("holding flow at {} l/s", setpoint);
+                self.set_mode(Mode::Flow { setpoint });
+                Motion::Keep
+            }
+            Command::HoldLevel(setpoint) => {
+                eprintln!("holding level at {} m", setpoint);
+                self.set_mode(Mode::HoldLevel { setpoint });
+                Motion::Keep
+            }
+            Command::Deliver(volume) => match counts(&self.calibration, self.delivery) {
+                Some(target) => {
+                    eprintln!("delivering {} l", volume);
+                    self.set_mode(Mode::Deliver { volume });
+                    Motion::MoveTo(target)
+                }
+                None => {
+                    eprintln!("ignoring delivery, not calibrated for {}", self.delivery);
+                    Motion::Keep
+                }
+            },
+            Command::RunRecipe(recipe) => {
+                self.run_recipe(recipe);
+                Motion::Keep
+            }
+            Command::PauseRecipe if self.mode == (Mode::Recipe { paused: false }) => {
+                eprintln!("pausing recipe");
+                self.set_mode(Mode::Recipe { paused: true });
+                Motion::Stop
+            }
+            Command::ResumeRecipe if self.mode == (Mode::Recipe { paused: true }) => {
+                eprintln!("resuming recipe");
+                self.set_mode(Mode::Recipe { paused: false });
+                Motion::Keep
+            }
+            Command::AbortRecipe if matches!(self.mode, Mode::Recipe { .. }) => {
+                eprintln!("aborting recipe");
+                self.set_mode(Mode::Manual);
+                Motion::Stop
+            }
+            Command::EndRecipe(run) if run == self.runs => {
+                self.set_mode(Mode::Manual);
+                Motion::Keep
+            }
+            Command::PauseRecipe
+            | Command::ResumeRecipe
+            | Command::AbortRecipe
+            | Command::EndRecipe(_) => Motion::Keep,
+            Command::Adjust(target) if self.mode.adjustable() => Motion::MoveTo(target),
+            Command::Adjust(_) => Motion::Keep,
+            Command::Hold(Some(opening)) => match counts(&self.calibration, opening) {
+                Some(target) => {
+                    eprintln!("holding at {}", opening);
+                    self.set_mode(Mode::Scheduled);
+                    Motion::MoveTo(target)
+                }
+                None => {
+                    eprintln!("ignoring hold at {}, not calibrated", opening);
+                    Motion::Keep
+                }
+            },
+            Command::Hold(None) => {
+                eprintln!("holding where the gate stops");
+                self.set_mode(Mode::Scheduled);
+                Motion::Stop
+            }
             Command::Close => {
-                eprintln!("{:?} => Closed", self.state);
-                self.state = Moving(0);
-                self.driver.enable();
-                // todo;; error here does not disable stepper
-                self.driver.move_to(0, self.statbus.clone()).await?;
-                self.driver.disable();
-                self.state = Stopped(0)
-            }
-            Command::Open(n) => {
-                // todo;; if moving, stop?
-                eprintln!("opening to {}", n);
-                self.state = Moving(n);
-                self.driver.enable();
-                // todo;; error here does not disable stepper
-                // todo;; externalize this multiplier
-                self.driver
-                    .move_to(n as isize * 35, self.statbus.clone())
-                    .await?;
-                self.driver.disable();
-                eprintln!("completed move to {}", n);
-            }
-            Command::Connect(tx) => self.statbus = Some(tx),
+                eprintln!("closing");
+                Motion::MoveTo(0)
+            }
+            Command::Open(opening) => match counts(&self.calibration, opening) {
+                Some(target) => {
+                    eprintln!("opening to {}", opening);
+                    Motion::MoveTo(target)
+                }
+                None => {
+                    eprintln!("ignoring open to {}, not calibrated", opening);
+                    Motion::Keep
+                }
+            },
+            Command::Stop => {
+                eprintln!("stop requested");
+                Motion::Stop
+            }
+            Command::Home => Motion::Home,
+            Command::Calibrate => Motion::Calibrate,
+            Command::ResetFault => Motion::ResetFault,
+            Command::Failsafe(reason) if self.mode.unattended() => {
+                eprintln!(
+                    "failsafe on {} held off, {:?} runs unattended",
+                    reason, self.mode
+                );
+                Motion::Keep
+            }
+            Command::Failsafe(reason) => {
+                let target = failsafe(
+                    &self.safety,
+                    &self.limits,
+                    &self.calibration,
+                    &self.statbus,
+                    reason,
+                );
+                target.map_or(Motion::Stop, Motion::MoveTo)
+            }
+        }
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        if self.mode != mode {
+            self.mode = mode;
+            self.statbus.set_mode(mode);
+        }
+    }
+
+    // end a recipe or delivery whose move was refused, which would otherwise
+    // wait on the move for good, as the control loops move again on their own,
+    // and a hold, which would otherwise keep the gate from failing safe
+    fn refused(&mut self) {
+        if let Mode::Recipe { .. } | Mode::Deliver { .. } | Mode::Scheduled = self.mode {
+            eprintln!("ending {:?}, its move was refused", self.mode);
+            self.set_mode(Mode::Manual);
         }
-        Ok(())
+    }
+
+    // hand a recipe to the recipe runner, replacing any running
+    fn run_recipe(&mut self, recipe: Recipe) {
+        eprintln!("starting recipe");
+        self.set_mode(Mode::Recipe { paused: false });
+        self.runs += 1;
+        self.recipe.send_replace(Some((self.runs, recipe)));
     }
 }
 
-async fn execute(mut actor: Gateman) -> Result<()> {
+// where a command sends the gate, None for commands that do not move it
+fn destination(
+    cmd: &Command,
+    calibration: &Mutex<Calibration>,
+    delivery: Opening,
+    (failsafe, limits): (Failsafe, &SoftLimits),
+) -> Option<isize> {
+    match *cmd {
+        // homing and calibrating run the gate closed
+        Command::Close | Command::Home | Command::Calibrate => Some(0),
+        Command::Open(opening) | Command::Hold(Some(opening)) => counts(calibration, opening),
+        Command::Deliver(_) => counts(calibration, delivery),
+        Command::Adjust(target) => Some(target),
+        Command::Failsafe(_) => failsafe_target(failsafe, limits, calibration),
+        _ => None,
+    }
+}
+
+fn counts(calibration: &Mutex<Calibration>, opening: Opening) -> Option<isize> {
+    let calibration = calibration.lock().expect("calibration lock poisoned");
+    calibration.counts(opening)
+}
+
+// announce the failsafe firing, and where it sends the gate unless it holds
+fn failsafe(
+    safety: &Safety,
+    limits: &SoftLimits,
+    calibration: &Mutex<Calibration>,
+    statbus: &StatusBus,
+    reason: &'static str,
+) -> Option<isize> {
+    let action = safety.failsafe;
+    eprintln!("failsafe on {}, {}", reason, action);
+    statbus.publish(Status::Failsafe { action, reason });
+    failsafe_target(action, limits, calibration)
+}
+
+// where the failsafe sends the gate, None when it holds
+fn failsafe_target(
+    action: Failsafe,
+    limits: &SoftLimits,
+    calibration: &Mutex<Calibration>,
+) -> Option<isize> {
+    let target = match action {
+        Failsafe::Hold => None,
+        Failsafe::Close => Some(0),
+        Failsafe::OpenFully => {
+            let travel = calibration
+                .lock()
+                .expect("calibration lock poisoned")
+                .travel;
+            travel.into_iter().chain(limits.max).min()
+        }
+        Failsafe::Position(p) => Some(p),
+    };
+    // as near as the soft limits allow
+    target.map(|t| limits.clamp(t))
+}
+
+// supervise the actor, a failed command faults the gate rather than ending the task
+async fn execute(mut actor: Gateman) {
     loop {
-        let message = tokio::time::timeout(Duration::from_secs(5), actor.cmdbus.recv()).await;
-        match message {
-            Ok(Some(cmd)) => actor.handle(cmd),
-            Ok(None) => actor.handle(Command::Close),
-            Err(_) => {
-                eprintln!("keep-alive timeout, closing");
-                actor.handle(Command::Close)
+        let deadline = actor.keepalive.deadline();
+        let message = tokio::time::timeout_at(deadline, actor.cmdbus.recv()).await;
+        let cmd = match message {
+            Ok(Some(cmd)) => {
+                actor.keepalive.hear(&cmd);
+                cmd
+            }
+            Ok(None) => break,
+            // a faulted gate stays where it is
+            Err(_) if !actor.keepalive.lapsed() || actor.faulted() => continue,
+            Err(_) => Command::Failsafe("keep-alive timeout"),
+        };
+        if let Err(e) = actor.handle(cmd).await {
+            actor.fault(e);
+        }
+        // a command held back runs once the gate is still
+        while let Some(cmd) = actor.deferred.take() {
+            if let Err(e) = actor.handle(cmd).await {
+                actor.fault(e);
             }
         }
-        .await?
+    }
+
+    // every sender is gone, control loops included
+    actor.dispatch.set_mode(Mode::Manual);
+    if let Err(e) = actor.handle(Command::Failsafe("shutdown")).await {
+        actor.fault(e);
     }
 }
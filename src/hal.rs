@@ -0,0 +1,72 @@
+//! Hardware abstraction for the gate drive.
+//!
+//! The [`Drive`](crate::drive::Drive) is written against these traits so that the
+//! motion logic does not depend on a particular GPIO library. The [`rpi`] module
+//! provides the Raspberry Pi backend built on `rppal`, and [`sim`] a simulated
+//! gate for running without hardware.
+
+use tokio::sync::mpsc;
+
+use crate::Result;
+
+pub mod rpi;
+pub mod sim;
+
+/// Generates the step pulses that drive the stepper.
+pub trait StepGenerator: Send + Sync {
+    fn enable(&self) -> Result<()>;
+    fn disable(&self) -> Result<()>;
+    fn set_frequency(&self, hz: f64) -> Result<()>;
+}
+
+/// A digital output, such as the driver enable or direction line.
+pub trait OutputLine: Send {
+    fn set_high(&mut self);
+    fn set_low(&mut self);
+}
+
+/// A digital input, such as a limit or home switch.
+pub trait InputLine: Send + Sync {
+    fn is_high(&self) -> bool;
+}
+
+/// Source of encoder ticks.
+pub trait Encoder: Send + Sync {
+    /// Blocks the calling thread, sending a tick for every encoder step
+    /// until a message is received on the `kill` channel.
+    fn read(&self, tx: mpsc::Sender<EncoderSpin>, kill: mpsc::Receiver<()>) -> Result<()>;
+}
+
+/// Counts the pulses of a flow meter.
+pub trait PulseCounter: Send + Sync {
+    /// Pulses counted since the counter started
+    fn count(&self) -> u64;
+}
+
+/// Reads a water level sensor.
+pub trait LevelSensor: Send + Sync {
+    /// Blocks until a reading is taken, in the units of the sensor
+    fn read(&self) -> Result<f64>;
+}
+
+/// A switch on an input line that is active at either level
+pub struct Switch {
+    line: Box<dyn InputLine>,
+    active_high: bool,
+}
+
+impl Switch {
+    pub fn new(line: Box<dyn InputLine>, active_high: bool) -> Self {
+        Self { line, active_high }
+    }
+
+    pub fn active(&self) -> bool {
+        self.line.is_high() == self.active_high
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum EncoderSpin {
+    Cw,
+    Ccw,
+}
@@ -0,0 +1,220 @@
+//! Raspberry Pi backend using `rppal` GPIO and hardware PWM.
+
+use rppal::gpio::Level::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin, Trigger};
+use rppal::pwm::{Channel, Polarity, Pwm};
+use rppal::spi::{Bus, SlaveSelect, Spi};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::hal::EncoderSpin::*;
+use crate::hal::{
+    Encoder, EncoderSpin, InputLine, LevelSensor, OutputLine, PulseCounter, StepGenerator,
+};
+use crate::Error::{EncoderTxError, LevelError};
+use crate::Result;
+
+const DUTY_CYCLE: f64 = 0.5;
+
+/// Metres per second, in air at about 20°C
+const SPEED_OF_SOUND: f64 = 343.0;
+
+/// Longest wait for an echo, beyond the range of the sensor
+const ECHO_TIMEOUT: Duration = Duration::from_millis(40);
+
+pub fn output(pin: u8) -> Result<OutputPin> {
+    Ok(Gpio::new()?.get(pin)?.into_output_low())
+}
+
+/// Input with the pull-up enabled, for switches that pull to ground
+pub fn input(pin: u8) -> Result<InputPin> {
+    Ok(Gpio::new()?.get(pin)?.into_input_pullup())
+}
+
+/// Pulse input counted on every falling edge, for open collector flow meters
+pub struct PulseInput {
+    _pin: InputPin,
+    count: Arc<AtomicU64>,
+}
+
+impl PulseInput {
+    pub fn new(pin: u8) -> Result<Self> {
+        let mut pin = input(pin)?;
+        let count = Arc::new(AtomicU64::new(0));
+        let counter = count.clone();
+        pin.set_async_interrupt(Trigger::FallingEdge, move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        })?;
+        Ok(PulseInput { _pin: pin, count })
+    }
+}
+
+impl PulseCounter for PulseInput {
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// One channel of an MCP3008 ADC on SPI0, reading the fraction of full scale
+pub struct Mcp3008 {
+    spi: Mutex<Spi>,
+    channel: u8,
+}
+
+impl Mcp3008 {
+    pub fn new(channel: u8) -> Result<Self> {
+        let spi = Spi::new(
+            Bus::Spi0,
+            SlaveSelect::Ss0,
+            1_000_000,
+            rppal::spi::Mode::Mode0,
+        )?;
+        Ok(Mcp3008 {
+            spi: Mutex::new(spi),
+            channel,
+        })
+    }
+}
+
+impl LevelSensor for Mcp3008 {
+    fn read(&self) -> Result<f64> {
+        let spi = self.spi.lock().expect("spi lock poisoned");
+        // start bit, single ended channel, then clock out the 10 bit result
+        let mut rx = [0u8; 3];
+        spi.transfer(&mut rx, &[1, (8 + self.channel) << 4, 0])?;
+        let value = ((rx[1] & 0x03) as u16) << 8 | rx[2] as u16;
+        Ok(value as f64 / 1023.0)
+    }
+}
+
+/// HC-SR04 style ultrasonic rangefinder, reading the distance in metres
+pub struct Ultrasonic {
+    pins: Mutex<(OutputPin, InputPin)>,
+}
+
+impl Ultrasonic {
+    pub fn new(trigger_pin: u8, echo_pin: u8) -> Result<Self> {
+        let trigger = output(trigger_pin)?;
+        let echo = Gpio::new()?.get(echo_pin)?.into_input();
+        Ok(Ultrasonic {
+            pins: Mutex::new((trigger, echo)),
+        })
+    }
+}
+
+impl LevelSensor for Ultrasonic {
+    fn read(&self) -> Result<f64> {
+        let mut pins = self.pins.lock().expect("ultrasonic lock poisoned");
+        let (trigger, echo) = &mut *pins;
+        trigger.set_high();
+        std::thread::sleep(Duration::from_micros(10));
+        trigger.set_low();
+
+        // the echo line is high for the round trip of the pulse
+        let wait = |level: Level| {
+            let start = Instant::now();
+            while echo.read() != level {
+                if start.elapsed() > ECHO_TIMEOUT {
+                    return Err(LevelError("no echo".to_string()));
+                }
+            }
+            Ok(Instant::now())
+        };
+        let sent = wait(High)?;
+        let received = wait(Low)?;
+        Ok(received.duration_since(sent).as_secs_f64() * SPEED_OF_SOUND / 2.0)
+    }
+}
+
+pub fn pwm(channel: u8, frequency: f64) -> Result<Pwm> {
+    let channel = match channel {
+        0 => Channel::Pwm0,
+        _ => Channel::Pwm1,
+    };
+    Ok(Pwm::with_frequency(
+        channel,
+        frequency,
+        DUTY_CYCLE,
+        Polarity::Normal,
+        false,
+    )?)
+}
+
+impl StepGenerator for Pwm {
+    fn enable(&self) -> Result<()> {
+        Ok(Pwm::enable(self)?)
+    }
+
+    fn disable(&self) -> Result<()> {
+        Ok(Pwm::disable(self)?)
+    }
+
+    fn set_frequency(&self, hz: f64) -> Result<()> {
+        Ok(Pwm::set_frequency(self, hz, DUTY_CYCLE)?)
+    }
+}
+
+impl OutputLine for OutputPin {
+    fn set_high(&mut self) {
+        OutputPin::set_high(self)
+    }
+
+    fn set_low(&mut self) {
+        OutputPin::set_low(self)
+    }
+}
+
+impl InputLine for InputPin {
+    fn is_high(&self) -> bool {
+        InputPin::is_high(self)
+    }
+}
+
+/// Clock/data encoder read by polling two input pins.
+pub struct PinEncoder {
+    clock: InputPin,
+    data: InputPin,
+}
+
+impl PinEncoder {
+    pub fn new(clock: u8, data: u8) -> Result<Self> {
+        Ok(Self {
+            clock: Gpio::new()?.get(clock)?.into_input_pullup(),
+            data: Gpio::new()?.get(data)?.into_input_pullup(),
+        })
+    }
+}
+
+// todo;; should encoder always read
+// todo;; should encoder maintain its position
+impl Encoder for PinEncoder {
+    fn read(&self, tx: mpsc::Sender<EncoderSpin>, mut kill: mpsc::Receiver<()>) -> Result<()> {
+        let mut state: u16 = 0;
+        while let Err(TryRecvError::Empty) = kill.try_recv() {
+            let c = self.clock.read() as u16;
+            let d = self.data.read() as Level;
+
+            state = (&state << 1) | c | 0xe000;
+            if state == 0xf000 {
+                tx.blocking_send(d.into()).map_err(|_| EncoderTxError)?;
+                state = 0;
+            }
+        }
+        println!("encoder thread existing...");
+
+        Ok(())
+    }
+}
+
+impl From<Level> for EncoderSpin {
+    fn from(l: Level) -> Self {
+        match l {
+            Low => Cw,
+            High => Ccw,
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Simulated stepper-driven gate.
+//!
+//! The motor advances at the PWM frequency while the driver is enabled and the
+//! step generator is running. Encoder ticks are derived from the simulated gate
+//! travel, which is clamped to the travel limits and can be made to slip or
+//! stall. Water flows through the gate in proportion to its opening, for the
+//! simulated flow meter, out of an upstream pool filled at a steady rate, for
+//! the simulated level sensor.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+
+use crate::hal::EncoderSpin::*;
+use crate::hal::{
+    Encoder, EncoderSpin, InputLine, LevelSensor, OutputLine, PulseCounter, StepGenerator,
+};
+use crate::Error::EncoderTxError;
+use crate::Result;
+
+const TICK: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    /// Encoder counts produced by one motor step
+    pub counts_per_step: f64,
+    /// Closed end of travel in encoder counts
+    pub min: isize,
+    /// Open end of travel in encoder counts
+    pub max: isize,
+    /// Fraction of steps lost to slip, 0.0 to 1.0
+    pub slip: f64,
+    /// Position at which the gate jams
+    pub stall_at: Option<isize>,
+    /// The home switch is active at and below this position
+    pub home_at: isize,
+    /// The open end-stop trips at and above this position
+    pub open_limit_at: Option<isize>,
+    /// The close end-stop trips at and below this position
+    pub close_limit_at: Option<isize>,
+    /// Litres per second through the gate for each encoder count it is open
+    pub flow_per_count: f64,
+    /// Litres per second flowing into the pool upstream of the gate
+    pub inflow: f64,
+    /// Surface area of the upstream pool in square metres
+    pub pool_area: f64,
+    /// Starting level of the upstream pool in metres
+    pub level: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            counts_per_step: 1.0,
+            min: 0,
+            max: 9000,
+            slip: 0.0,
+            stall_at: None,
+            home_at: 0,
+            open_limit_at: None,
+            close_limit_at: None,
+            flow_per_count: 0.001,
+            inflow: 2.0,
+            pool_area: 1.0,
+            level: 1.0,
+        }
+    }
+}
+
+/// Shared state of the simulated gate, cloned into each simulated device
+#[derive(Clone)]
+pub struct SimGate {
+    inner: Arc<Mutex<Sim>>,
+}
+
+struct Sim {
+    config: SimConfig,
+    en_high: bool,
+    dir_high: bool,
+    pwm_on: bool,
+    frequency: f64,
+    position: f64,
+    reported: isize,
+    /// Litres that have flowed through the gate
+    volume: f64,
+    /// Metres of water in the upstream pool
+    level: f64,
+    last: Instant,
+}
+
+impl SimGate {
+    pub fn new(at: isize, config: SimConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Sim {
+                config: config.clone(),
+                en_high: false,
+                dir_high: false,
+                pwm_on: false,
+                frequency: 0.0,
+                position: at as f64,
+                reported: at,
+                volume: 0.0,
+                level: config.level,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// The physical position of the gate in encoder counts
+    pub fn position(&self) -> isize {
+        self.lock().position.floor() as isize
+    }
+
+    pub fn enable_line(&self) -> SimLine {
+        SimLine {
+            gate: self.clone(),
+            line: Line::Enable,
+        }
+    }
+
+    pub fn direction_line(&self) -> SimLine {
+        SimLine {
+            gate: self.clone(),
+            line: Line::Direction,
+        }
+    }
+
+    /// Home switch at the closed end, high while active
+    pub fn home_switch(&self) -> SimSwitch {
+        let at = self.lock().config.home_at;
+        self.switch(at, true)
+    }
+
+    /// Open end-stop, high when tripped
+    pub fn open_limit(&self) -> Option<SimSwitch> {
+        let at = self.lock().config.open_limit_at;
+        at.map(|at| self.switch(at, false))
+    }
+
+    /// Close end-stop, high when tripped
+    pub fn close_limit(&self) -> Option<SimSwitch> {
+        let at = self.lock().config.close_limit_at;
+        at.map(|at| self.switch(at, true))
+    }
+
+    /// Flow meter on the outflow of the gate, pulsing `k_factor` times per litre
+    pub fn flow_meter(&self, k_factor: f64) -> SimFlowMeter {
+        SimFlowMeter {
+            gate: self.clone(),
+            k_factor,
+        }
+    }
+
+    /// Level sensor in the upstream pool, reading metres
+    pub fn level_sensor(&self) -> SimLevel {
+        SimLevel { gate: self.clone() }
+    }
+
+    fn switch(&self, at: isize, below: bool) -> SimSwitch {
+        SimSwitch {
+            gate: self.clone(),
+            at: at as f64,
+            below,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Sim> {
+        let mut sim = self.inner.lock().expect("simulation lock poisoned");
+        sim.advance();
+        sim
+    }
+}
+
+impl Sim {
+    fn running(&self) -> bool {
+        !self.en_high && self.pwm_on
+    }
+
+    // integrate motor motion since the last update
+    fn advance(&mut self) {
+        let now = Instant::now();
+        let dt = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let outflow = self.position.max(0.0) * self.config.flow_per_count;
+        self.volume += outflow * dt;
+        let rise = (self.config.inflow - outflow) / 1000.0 / self.config.pool_area * dt;
+        self.level = (self.level + rise).max(0.0);
+
+        if !self.running() {
+            return;
+        }
+
+        let steps = self.frequency * dt * (1.0 - self.config.slip.clamp(0.0, 1.0));
+        let delta = steps * self.config.counts_per_step;
+        let (mut lo, mut hi) = (self.config.min as f64, self.config.max as f64);
+        if let Some(jam) = self.config.stall_at.map(|p| p as f64) {
+            if self.position <= jam {
+                hi = hi.min(jam);
+            } else {
+                lo = lo.max(jam);
+            }
+        }
+
+        let next = if self.dir_high {
+            self.position - delta
+        } else {
+            self.position + delta
+        };
+        self.position = next.clamp(lo, hi);
+    }
+
+    // the encoder ticks accumulated since the last read
+    fn take_ticks(&mut self) -> (EncoderSpin, usize) {
+        let now = self.position.floor() as isize;
+        let ticks = now - self.reported;
+        self.reported = now;
+        if ticks < 0 {
+            (Cw, ticks.unsigned_abs())
+        } else {
+            (Ccw, ticks as usize)
+        }
+    }
+}
+
+impl StepGenerator for SimGate {
+    fn enable(&self) -> Result<()> {
+        self.lock().pwm_on = true;
+        Ok(())
+    }
+
+    fn disable(&self) -> Result<()> {
+        self.lock().pwm_on = false;
+        Ok(())
+    }
+
+    fn set_frequency(&self, hz: f64) -> Result<()> {
+        self.lock().frequency = hz;
+        Ok(())
+    }
+}
+
+impl Encoder for SimGate {
+    fn read(&self, tx: mpsc::Sender<EncoderSpin>, mut kill: mpsc::Receiver<()>) -> Result<()> {
+        let send = |(spin, ticks): (EncoderSpin, usize)| -> Result<()> {
+            for _ in 0..ticks {
+                tx.blocking_send(spin).map_err(|_| EncoderTxError)?;
+            }
+            Ok(())
+        };
+        while let Err(TryRecvError::Empty) = kill.try_recv() {
+            std::thread::sleep(TICK);
+            let ticks = self.lock().take_ticks();
+            send(ticks)?;
+        }
+        // the steps made since the last poll, up to the step generator stopping
+        let ticks = self.lock().take_ticks();
+        send(ticks)?;
+        println!("encoder thread existing...");
+
+        Ok(())
+    }
+}
+
+enum Line {
+    Enable,
+    Direction,
+}
+
+/// Simulated output wired to the driver enable or direction input
+pub struct SimLine {
+    gate: SimGate,
+    line: Line,
+}
+
+impl SimLine {
+    fn write(&mut self, high: bool) {
+        let mut sim = self.gate.lock();
+        match self.line {
+            Line::Enable => sim.en_high = high,
+            Line::Direction => sim.dir_high = high,
+        }
+    }
+}
+
+impl OutputLine for SimLine {
+    fn set_high(&mut self) {
+        self.write(true)
+    }
+
+    fn set_low(&mut self) {
+        self.write(false)
+    }
+}
+
+/// Simulated switch that is high beyond a position
+pub struct SimSwitch {
+    gate: SimGate,
+    at: f64,
+    below: bool,
+}
+
+impl InputLine for SimSwitch {
+    fn is_high(&self) -> bool {
+        let position = self.gate.lock().position;
+        if self.below {
+            position <= self.at
+        } else {
+            position >= self.at
+        }
+    }
+}
+
+/// Simulated pulse output flow meter
+pub struct SimFlowMeter {
+    gate: SimGate,
+    k_factor: f64,
+}
+
+impl PulseCounter for SimFlowMeter {
+    fn count(&self) -> u64 {
+        (self.gate.lock().volume * self.k_factor) as u64
+    }
+}
+
+/// Simulated level sensor in the upstream pool
+pub struct SimLevel {
+    gate: SimGate,
+}
+
+impl LevelSensor for SimLevel {
+    fn read(&self) -> Result<f64> {
+        Ok(self.gate.lock().level)
+    }
+}
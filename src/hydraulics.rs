@@ -0,0 +1,271 @@
+//! Discharge through the gate estimated from its opening and the water on either side.
+//!
+//! Sluice gates discharge under the leaf as an orifice, until the leaf rises far
+//! enough that the sill passes less as a weir than the opening would, and the
+//! weir limits the discharge from there on. Overflow gates discharge over the
+//! leaf as a weir. Heads are in metres above the sill, and a downstream head above the
+//! opening or crest reduces the discharge of a submerged gate.
+
+use serde::Deserialize;
+
+use crate::level::{LevelConfig, LevelInput};
+
+/// Metres per second squared
+const GRAVITY: f64 = 9.81;
+
+/// Discharge coefficient of the sill once a sluice gate leaf is out of the water
+const SILL_WEIR_COEFFICIENT: f64 = 0.62;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HydraulicsConfig {
+    /// Shape of the gate, no flow estimate when not set
+    pub gate: Option<GateGeometry>,
+    /// Level of the sill in metres, in the datum of the level sensors
+    pub sill: f64,
+    /// Upstream head in metres above the sill, used when there is no level sensor
+    pub upstream_head: Option<f64>,
+    /// Level sensor downstream of the gate, free discharge when not set
+    pub downstream: Option<LevelInput>,
+}
+
+impl HydraulicsConfig {
+    /// GPIO pins taken by the downstream sensor, sharing the SPI bus with an upstream ADC
+    pub fn pins(&self, level: &LevelConfig) -> Vec<(&'static str, u8)> {
+        match (self.downstream, level.sensor) {
+            (Some(LevelInput::Adc { .. }), Some(LevelInput::Adc { .. })) => vec![],
+            (Some(sensor), _) => sensor.pins(["downstream trigger_pin", "downstream echo_pin"]),
+            (None, _) => vec![],
+        }
+    }
+}
+
+/// Gate type and dimensions in metres
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum GateGeometry {
+    /// Underflow gate, the opening is the height of the gap under the leaf
+    Sluice {
+        width: f64,
+        #[serde(default = "sluice_coefficient")]
+        discharge_coefficient: f64,
+    },
+    /// Overflow gate, the opening lowers the crest from `height` above the sill
+    Overflow {
+        width: f64,
+        height: f64,
+        #[serde(default = "weir_coefficient")]
+        discharge_coefficient: f64,
+    },
+}
+
+fn sluice_coefficient() -> f64 {
+    0.61
+}
+
+fn weir_coefficient() -> f64 {
+    0.62
+}
+
+impl GateGeometry {
+    pub fn width(&self) -> f64 {
+        match self {
+            GateGeometry::Sluice { width, .. } | GateGeometry::Overflow { width, .. } => *width,
+        }
+    }
+
+    pub fn discharge_coefficient(&self) -> f64 {
+        match self {
+            GateGeometry::Sluice {
+                discharge_coefficient,
+                ..
+            }
+            | GateGeometry::Overflow {
+                discharge_coefficient,
+                ..
+            } => *discharge_coefficient,
+        }
+    }
+
+    /// Litres per second through the gate `opening` metres open,
+    /// given the heads in metres above the sill
+    pub fn discharge(&self, opening: f64, upstream: f64, downstream: Option<f64>) -> f64 {
+        let opening = opening.max(0.0);
+        let cubic_metres = match *self {
+            GateGeometry::Sluice {
+                width,
+                discharge_coefficient,
+            } => {
+                // the regimes meet where the orifice passes as much as the weir, so
+                // the discharge does not jump as the gate opens through it
+                let under = orifice(
+                    width,
+                    discharge_coefficient,
+                    opening.min(upstream),
+                    upstream,
+                    downstream,
+                );
+                under.min(weir(width, SILL_WEIR_COEFFICIENT, upstream, downstream))
+            }
+            GateGeometry::Overflow {
+                width,
+                height,
+                discharge_coefficient,
+            } => {
+                let crest = (height - opening).max(0.0);
+                weir(
+                    width,
+                    discharge_coefficient,
+                    upstream - crest,
+                    downstream.map(|d| d - crest),
+                )
+            }
+        };
+        cubic_metres * 1000.0
+    }
+}
+
+// flow under a leaf `opening` high, driven by the difference in head once the tailwater covers it
+fn orifice(width: f64, cd: f64, opening: f64, upstream: f64, downstream: Option<f64>) -> f64 {
+    let head = match downstream {
+        Some(d) if d > opening => upstream - d,
+        _ => upstream,
+    };
+    if head <= 0.0 {
+        return 0.0;
+    }
+    cd * width * opening * (2.0 * GRAVITY * head).sqrt()
+}
+
+// flow over a sharp crest with heads measured from the crest, reduced by the
+// Villemonte relation when the tailwater is above the crest
+fn weir(width: f64, cd: f64, head: f64, tail: Option<f64>) -> f64 {
+    if head <= 0.0 {
+        return 0.0;
+    }
+    let free = cd * 2.0 / 3.0 * width * (2.0 * GRAVITY).sqrt() * head.powf(1.5);
+    match tail {
+        Some(tail) if tail > 0.0 => {
+            let ratio = (tail / head).min(1.0);
+            free * (1.0 - ratio.powf(1.5)).powf(0.385)
+        }
+        _ => free,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLUICE: GateGeometry = GateGeometry::Sluice {
+        width: 1.0,
+        discharge_coefficient: 0.61,
+    };
+
+    // litres per second over the sill as a free weir, for a head of a metre
+    fn sill_weir() -> f64 {
+        SILL_WEIR_COEFFICIENT * 2.0 / 3.0 * (2.0 * GRAVITY).sqrt() * 1000.0
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9 * b.abs().max(1.0)
+    }
+
+    #[test]
+    fn sluice_discharges_as_an_orifice_when_nearly_closed() {
+        let q = SLUICE.discharge(0.2, 1.0, None);
+        assert!(
+            close(q, 0.61 * 0.2 * (2.0 * GRAVITY).sqrt() * 1000.0),
+            "{}",
+            q
+        );
+        assert!(close(SLUICE.discharge(0.4, 1.0, None), 2.0 * q));
+        assert_eq!(SLUICE.discharge(0.0, 1.0, None), 0.0);
+        assert_eq!(SLUICE.discharge(-0.1, 1.0, None), 0.0);
+        assert_eq!(SLUICE.discharge(0.2, 0.0, None), 0.0);
+    }
+
+    #[test]
+    fn sluice_discharges_as_a_weir_when_open() {
+        assert!(close(SLUICE.discharge(1.0, 1.0, None), sill_weir()));
+        assert!(close(SLUICE.discharge(3.0, 1.0, None), sill_weir()));
+        let q = SLUICE.discharge(2.0, 0.5, None);
+        assert!(close(q, sill_weir() * 0.5f64.powf(1.5)), "{}", q);
+    }
+
+    #[test]
+    fn sluice_regimes_meet_without_a_jump() {
+        // the orifice passes as much as the weir at this share of the head
+        let ratio = 2.0 / 3.0 * SILL_WEIR_COEFFICIENT / 0.61;
+        let orifice = 0.61 * ratio * (2.0 * GRAVITY).sqrt() * 1000.0;
+        assert!(close(orifice, sill_weir()));
+        assert!(close(SLUICE.discharge(ratio, 1.0, None), sill_weir()));
+
+        for upstream in [0.3, 1.0, 2.5] {
+            let flows: Vec<f64> = (0..=2000)
+                .map(|mm| SLUICE.discharge(mm as f64 / 1000.0, upstream, None))
+                .collect();
+            for pair in flows.windows(2) {
+                assert!(pair[1] >= pair[0], "falls from {} to {}", pair[0], pair[1]);
+                assert!(
+                    pair[1] - pair[0] < 5.0,
+                    "jumps from {} to {}",
+                    pair[0],
+                    pair[1]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sluice_with_a_low_coefficient_stops_rising_once_clear_of_the_water() {
+        let sluice = GateGeometry::Sluice {
+            width: 1.0,
+            discharge_coefficient: 0.3,
+        };
+        let clear = sluice.discharge(1.0, 1.0, None);
+        assert!(clear < sill_weir());
+        assert_eq!(sluice.discharge(1.5, 1.0, None), clear);
+    }
+
+    #[test]
+    fn submerged_sluice_discharges_less() {
+        let free = SLUICE.discharge(0.2, 1.0, None);
+        // tailwater below the leaf leaves the orifice free
+        assert_eq!(SLUICE.discharge(0.2, 1.0, Some(0.1)), free);
+        // above it the difference in head drives the flow
+        let submerged = SLUICE.discharge(0.2, 1.0, Some(0.5));
+        assert!(close(submerged, free * 0.5f64.sqrt()), "{}", submerged);
+        assert!(SLUICE.discharge(0.2, 1.0, Some(0.8)) < submerged);
+        assert_eq!(SLUICE.discharge(0.2, 1.0, Some(1.0)), 0.0);
+        assert_eq!(SLUICE.discharge(0.2, 1.0, Some(1.2)), 0.0);
+
+        // tailwater over the sill drowns the weir
+        let drowned = SLUICE.discharge(2.0, 1.0, Some(0.5));
+        let villemonte = sill_weir() * (1.0 - 0.5f64.powf(1.5)).powf(0.385);
+        assert!(close(drowned, villemonte), "{}", drowned);
+        assert_eq!(SLUICE.discharge(2.0, 1.0, Some(1.0)), 0.0);
+        assert_eq!(SLUICE.discharge(2.0, 1.0, Some(0.0)), sill_weir());
+    }
+
+    #[test]
+    fn overflow_discharges_over_the_crest() {
+        let overflow = GateGeometry::Overflow {
+            width: 2.0,
+            height: 1.0,
+            discharge_coefficient: 0.62,
+        };
+        // the crest stands above the water
+        assert_eq!(overflow.discharge(0.0, 0.8, None), 0.0);
+        // lowered half a metre, leaving 0.3 m over it
+        let q = overflow.discharge(0.5, 0.8, None);
+        assert!(close(q, 2.0 * sill_weir() * 0.3f64.powf(1.5)), "{}", q);
+        // lowered past the sill
+        assert_eq!(
+            overflow.discharge(1.5, 0.8, None),
+            overflow.discharge(1.0, 0.8, None)
+        );
+        assert!(overflow.discharge(0.5, 0.8, Some(0.7)) < q);
+        assert_eq!(overflow.discharge(0.5, 0.8, Some(0.4)), q);
+    }
+}
@@ -0,0 +1,498 @@
+//! Interlocks, rules keeping a gate from moving while another is out of place.
+//!
+//! A rule covers a range of openings of one gate and requires another gate to
+//! be within a range of its own whenever the first is in it. Each gate checks
+//! the rules on where a command sends it before taking the command. A move into
+//! the covered range is refused, or waits for the required gate to be moved into
+//! place when the rule says so, and the required gate is refused any move out of
+//! place while the other is covered.
+
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, OnceLock};
+
+use serde::Deserialize;
+
+use crate::calibration::Opening;
+use crate::gate::{GatemanRef, State};
+use crate::registry::{Registry, WeakRegistry};
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The gate held back
+    pub gate: String,
+    /// Percent open below or above which the gate is covered by the rule
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    /// The gate that must be in place while the other is covered
+    pub requires: String,
+    /// Percent open the required gate must be at least or at most
+    pub at_least: Option<f64>,
+    pub at_most: Option<f64>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+/// What becomes of a command moving a gate into the range a rule covers
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Refuse the command
+    #[default]
+    Reject,
+    /// Move the required gate into place, then run the command
+    MoveFirst,
+}
+
+impl Rule {
+    /// Check the rule makes sense, whatever gates it names
+    pub fn check(&self) -> Result<(), String> {
+        if self.gate == self.requires {
+            return Err(format!("{} cannot require itself", self.gate));
+        }
+        if self.below.is_none() && self.above.is_none() {
+            return Err("needs below or above".to_string());
+        }
+        if self.at_least.is_none() && self.at_most.is_none() {
+            return Err("needs at_least or at_most".to_string());
+        }
+        for (name, percent) in [
+            ("below", self.below),
+            ("above", self.above),
+            ("at_least", self.at_least),
+            ("at_most", self.at_most),
+        ] {
+            if let Some(p) = percent.filter(|p| !(0.0..=100.0).contains(p)) {
+                return Err(format!("{} {} is outside 0..100", name, p));
+            }
+        }
+        if let (Some(least), Some(most)) = (self.at_least, self.at_most) {
+            if least > most {
+                return Err(format!("at_least {} is above at_most {}", least, most));
+            }
+        }
+        Ok(())
+    }
+
+    // whether a position of the held gate is covered, None when it is not calibrated
+    fn covers(&self, gm: &GatemanRef, position: isize) -> Option<bool> {
+        let below = bound(gm, self.below)?;
+        let above = bound(gm, self.above)?;
+        Some(below.is_some_and(|b| position < b) || above.is_some_and(|a| position > a))
+    }
+
+    // whether a position of the required gate is in place, None when it is not calibrated
+    fn allows(&self, gm: &GatemanRef, position: isize) -> Option<bool> {
+        let least = bound(gm, self.at_least)?;
+        let most = bound(gm, self.at_most)?;
+        Some(least.is_none_or(|l| position >= l) && most.is_none_or(|m| position <= m))
+    }
+
+    // where the required gate goes to be in place, from the positions it is out of place at
+    fn place(&self, gm: &GatemanRef, reach: &[isize]) -> Option<Opening> {
+        let least = bound(gm, self.at_least)?;
+        let percent = match least {
+            Some(l) if reach.iter().any(|p| *p < l) => self.at_least,
+            _ => self.at_most,
+        };
+        percent.map(Opening::Percent)
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} must be ", self.requires)?;
+        match (self.at_least, self.at_most) {
+            (Some(l), Some(m)) => write!(f, "between {}% and {}% open", l, m)?,
+            (Some(l), None) => write!(f, "at least {}% open", l)?,
+            (None, Some(m)) => write!(f, "at most {}% open", m)?,
+            (None, None) => f.write_str("in place")?,
+        }
+        write!(f, " while {} is ", self.gate)?;
+        match (self.below, self.above) {
+            (Some(b), Some(a)) => write!(f, "below {}% or above {}% open", b, a),
+            (Some(b), None) => write!(f, "below {}% open", b),
+            (None, Some(a)) => write!(f, "above {}% open", a),
+            (None, None) => f.write_str("anywhere"),
+        }
+    }
+}
+
+// counts for a percent open, Some(None) when there is no percent to convert
+fn bound(gm: &GatemanRef, percent: Option<f64>) -> Option<Option<isize>> {
+    match percent {
+        Some(p) => gm.counts(Opening::Percent(p)).map(Some),
+        None => Some(None),
+    }
+}
+
+// the positions a gate is at and heading to, None while homing or calibrating
+// takes it anywhere along its travel
+fn reach(gm: &GatemanRef) -> Option<Vec<isize>> {
+    let snapshot = gm.snapshot();
+    match snapshot.state {
+        State::Moving(target) => Some(vec![snapshot.position, target]),
+        State::Homing | State::Calibrating => None,
+        _ => Some(vec![snapshot.position]),
+    }
+}
+
+/// Whether a gate may move to a position
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    Clear,
+    /// The move breaks a rule, for the reason given
+    Refused(String),
+    /// The move breaks a rule until another gate has moved to an opening
+    MoveFirst {
+        gate: String,
+        opening: Opening,
+        reason: String,
+    },
+}
+
+/// The rules of the daemon, checked against its gates once they have all started
+#[derive(Clone, Default)]
+pub struct Interlocks {
+    rules: Arc<Vec<Rule>>,
+    // weak, as the gates hold the interlocks
+    gates: Arc<OnceLock<WeakRegistry>>,
+}
+
+impl Interlocks {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            gates: Arc::default(),
+        }
+    }
+
+    /// Check the rules against the gates from now on
+    pub fn bind(&self, gates: &Registry) {
+        let _ = self.gates.set(gates.downgrade());
+    }
+
+    /// The rules as seen by one gate
+    pub fn on(&self, gate: &str) -> Interlock {
+        Interlock {
+            gate: gate.to_string(),
+            interlocks: self.clone(),
+        }
+    }
+
+    fn get(&self, gate: &str) -> Option<GatemanRef> {
+        self.gates.get()?.upgrade()?.get(gate)
+    }
+}
+
+/// The rules a gate is held back by or holds back other gates with
+#[derive(Clone, Default)]
+pub struct Interlock {
+    gate: String,
+    interlocks: Interlocks,
+}
+
+impl Interlock {
+    /// Another gate of the daemon
+    pub fn gate(&self, name: &str) -> Option<GatemanRef> {
+        self.interlocks.get(name)
+    }
+
+    /// Whether the gate may move to a position, refused by the first rule that
+    /// refuses it else moving the first gate a rule needs moved
+    ///
+    /// The check reads the last snapshots of the other gates without holding
+    /// them, and each gate only shows where it is heading once its move has
+    /// started. Two gates of a rule checked at the same moment can both be
+    /// cleared, each before the other has started, so the rules are kept
+    /// against commands arriving one after another, not against a race
+    /// between commands sent to both gates at once.
+    pub fn check(&self, target: isize) -> Check {
+        let mut first = Check::Clear;
+        for rule in self.interlocks.rules.iter() {
+            let check = if rule.gate == self.gate {
+                self.held(rule, target)
+            } else if rule.requires == self.gate {
+                self.holding(rule, target)
+            } else {
+                continue;
+            };
+            match check {
+                Check::Refused(_) => return check,
+                Check::MoveFirst { .. } if first == Check::Clear => first = check,
+                _ => {}
+            }
+        }
+        first
+    }
+
+    // a move of the gate the rule holds back, which needs the required gate in place
+    fn held(&self, rule: &Rule, target: isize) -> Check {
+        let (gm, required) = match (self.gate(&rule.gate), self.gate(&rule.requires)) {
+            (Some(gm), Some(required)) => (gm, required),
+            _ => return Check::Refused(format!("{}, and the gates have not started", rule)),
+        };
+        match rule.covers(&gm, target) {
+            Some(true) => {}
+            Some(false) => return Check::Clear,
+            None => {
+                return Check::Refused(format!("{}, and {} is not calibrated", rule, rule.gate))
+            }
+        }
+        let reach = match reach(&required) {
+            Some(reach) => reach,
+            None => return Check::Refused(format!("{}, and {} is busy", rule, rule.requires)),
+        };
+        let allowed = reach
+            .iter()
+            .map(|p| rule.allows(&required, *p))
+            .try_fold(true, |all, allows| allows.map(|a| all && a));
+        match allowed {
+            Some(true) => Check::Clear,
+            Some(false) if rule.action == Action::MoveFirst => {
+                match rule.place(&required, &reach) {
+                    Some(opening) => Check::MoveFirst {
+                        gate: rule.requires.clone(),
+                        opening,
+                        reason: rule.to_string(),
+                    },
+                    None => Check::Refused(rule.to_string()),
+                }
+            }
+            Some(false) => Check::Refused(rule.to_string()),
+            None => Check::Refused(format!("{}, and {} is not calibrated", rule, rule.requires)),
+        }
+    }
+
+    // a move of the required gate, which must stay in place while the other is covered
+    fn holding(&self, rule: &Rule, target: isize) -> Check {
+        let (gm, held) = match (self.gate(&rule.requires), self.gate(&rule.gate)) {
+            (Some(gm), Some(held)) => (gm, held),
+            _ => return Check::Refused(format!("{}, and the gates have not started", rule)),
+        };
+        match rule.allows(&gm, target) {
+            Some(true) => return Check::Clear,
+            Some(false) => {}
+            None => {
+                return Check::Refused(format!("{}, and {} is not calibrated", rule, rule.requires))
+            }
+        }
+        // a held gate going anywhere might be covered
+        let covered = reach(&held).map_or(Some(true), |reach| {
+            reach
+                .iter()
+                .map(|p| rule.covers(&held, *p))
+                .try_fold(false, |any, covers| covers.map(|c| any || c))
+        });
+        match covered {
+            Some(false) => Check::Clear,
+            Some(true) => Check::Refused(rule.to_string()),
+            None => Check::Refused(format!("{}, and {} is not calibrated", rule, rule.gate)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::config::GateConfig;
+    use crate::drive::Drive;
+    use crate::gate::{Command, Sensors};
+    use crate::hal::sim::{SimConfig, SimGate};
+    use crate::motion::MotionProfile;
+
+    fn rule(toml: &str) -> Rule {
+        toml::from_str(toml).unwrap()
+    }
+
+    // the spill must be half open while the head gate is open past a fifth
+    fn spill_rule(action: &str) -> Rule {
+        rule(&format!(
+            r#"gate = "head"
+            above = 20
+            requires = "spill"
+            at_least = 50
+            action = "{}""#,
+            action
+        ))
+    }
+
+    // gates of 1000 counts at the positions given, bound to the rules
+    fn gates(rules: Vec<Rule>, at: &[(&str, isize)]) -> (Interlocks, Registry) {
+        let interlocks = Interlocks::new(rules);
+        let mut config = GateConfig::default();
+        config.calibration.travel = Some(1000);
+        let gates = at
+            .iter()
+            .map(|&(name, at)| {
+                let sim = SimGate::new(at, SimConfig::default());
+                let driver = Drive::simulated(&sim, MotionProfile::default());
+                let sensors = Sensors::default();
+                let interlock = interlocks.on(name);
+                let gm = GatemanRef::new(driver, &sensors, &config, &BTreeMap::new(), interlock);
+                (name.to_string(), gm)
+            })
+            .collect();
+        let registry = Registry::new(gates);
+        interlocks.bind(&registry);
+        (interlocks, registry)
+    }
+
+    #[tokio::test]
+    async fn held_gate_needs_the_required_gate_in_place() {
+        let (interlocks, _gates) = gates(vec![spill_rule("reject")], &[("head", 0), ("spill", 0)]);
+        let head = interlocks.on("head");
+        // not covered at or below a fifth open
+        assert_eq!(head.check(100), Check::Clear);
+        assert_eq!(head.check(200), Check::Clear);
+        let refused = Check::Refused(
+            "spill must be at least 50% open while head is above 20% open".to_string(),
+        );
+        assert_eq!(head.check(201), refused);
+        assert_eq!(head.check(1000), refused);
+
+        let (interlocks, _gates) =
+            gates(vec![spill_rule("reject")], &[("head", 0), ("spill", 600)]);
+        assert_eq!(interlocks.on("head").check(1000), Check::Clear);
+    }
+
+    #[tokio::test]
+    async fn move_first_moves_the_required_gate_into_place() {
+        let (interlocks, _gates) = gates(
+            vec![spill_rule("move_first")],
+            &[("head", 0), ("spill", 100)],
+        );
+        let head = interlocks.on("head");
+        assert_eq!(
+            head.check(800),
+            Check::MoveFirst {
+                gate: "spill".to_string(),
+                opening: Opening::Percent(50.0),
+                reason: "spill must be at least 50% open while head is above 20% open".to_string(),
+            }
+        );
+        assert_eq!(head.check(100), Check::Clear);
+        // the required gate itself is only ever refused
+        let spill = interlocks.on("spill");
+        assert_eq!(spill.check(0), Check::Clear);
+    }
+
+    #[tokio::test]
+    async fn move_first_picks_the_nearer_bound() {
+        let mut rule = spill_rule("move_first");
+        rule.at_most = Some(80.0);
+        let (interlocks, _gates) = gates(vec![rule], &[("head", 0), ("spill", 900)]);
+        match interlocks.on("head").check(800) {
+            Check::MoveFirst { opening, .. } => assert_eq!(opening, Opening::Percent(80.0)),
+            check => panic!("{:?}", check),
+        }
+    }
+
+    #[tokio::test]
+    async fn required_gate_is_held_while_the_other_is_covered() {
+        let rules = vec![spill_rule("move_first")];
+        let (interlocks, _gates) = gates(rules.clone(), &[("head", 500), ("spill", 600)]);
+        let spill = interlocks.on("spill");
+        assert_eq!(spill.check(700), Check::Clear);
+        assert_eq!(spill.check(500), Check::Clear);
+        assert!(matches!(spill.check(499), Check::Refused(_)));
+        assert!(matches!(spill.check(0), Check::Refused(_)));
+
+        let (interlocks, _gates) = gates(rules, &[("head", 100), ("spill", 600)]);
+        assert_eq!(interlocks.on("spill").check(0), Check::Clear);
+    }
+
+    #[tokio::test]
+    async fn a_refusal_outranks_moving_first() {
+        let mut level = spill_rule("reject");
+        level.requires = "bypass".to_string();
+        let rules = vec![spill_rule("move_first"), level];
+        let (interlocks, _gates) = gates(rules, &[("head", 0), ("spill", 0), ("bypass", 0)]);
+        let check = interlocks.on("head").check(800);
+        assert!(
+            matches!(check, Check::Refused(ref r) if r.starts_with("bypass")),
+            "{:?}",
+            check
+        );
+    }
+
+    #[tokio::test]
+    async fn the_failsafe_is_checked_where_it_sends_the_gate() {
+        let (interlocks, _gates) = gates(vec![spill_rule("reject")], &[("head", 0), ("spill", 0)]);
+        // the default failsafe closes the spill, away from the open head
+        let spill = interlocks.on("head").gate("spill").unwrap();
+        assert_eq!(spill.interlock(&Command::Failsafe("test")), Check::Clear);
+
+        let (interlocks, _gates) =
+            gates(vec![spill_rule("reject")], &[("head", 500), ("spill", 600)]);
+        let spill = interlocks.on("head").gate("spill").unwrap();
+        assert!(matches!(
+            spill.interlock(&Command::Failsafe("test")),
+            Check::Refused(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn gates_out_of_other_rules_are_clear() {
+        let (interlocks, _gates) = gates(
+            vec![spill_rule("reject")],
+            &[("head", 0), ("spill", 0), ("other", 0)],
+        );
+        assert_eq!(interlocks.on("other").check(1000), Check::Clear);
+    }
+
+    #[test]
+    fn refused_until_the_gates_are_bound() {
+        let interlocks = Interlocks::new(vec![spill_rule("reject")]);
+        let check = interlocks.on("head").check(1000);
+        assert!(matches!(check, Check::Refused(r) if r.ends_with("have not started")));
+    }
+
+    #[tokio::test]
+    async fn interlocks_do_not_keep_the_gates_alive() {
+        let (interlocks, gates) = gates(vec![spill_rule("reject")], &[("head", 0), ("spill", 0)]);
+        assert!(interlocks.on("head").gate("spill").is_some());
+        drop(gates);
+        assert!(interlocks.on("head").gate("spill").is_none());
+    }
+
+    #[test]
+    fn rules_are_checked() {
+        assert!(spill_rule("reject").check().is_ok());
+        let bad = [
+            (
+                "gate = \"a\"\nrequires = \"a\"\nabove = 1\nat_least = 1",
+                "cannot require itself",
+            ),
+            (
+                "gate = \"a\"\nrequires = \"b\"\nat_least = 1",
+                "needs below or above",
+            ),
+            (
+                "gate = \"a\"\nrequires = \"b\"\nabove = 1",
+                "needs at_least or at_most",
+            ),
+            (
+                "gate = \"a\"\nrequires = \"b\"\nabove = 120\nat_least = 1",
+                "above 120 is outside",
+            ),
+            (
+                "gate = \"a\"\nrequires = \"b\"\nbelow = 1\nat_least = 60\nat_most = 40",
+                "above at_most",
+            ),
+        ];
+        for (toml, error) in bad {
+            let result = rule(toml).check();
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{:?}",
+                result
+            );
+        }
+        assert!(
+            toml::from_str::<Rule>("gate = \"a\"\nrequires = \"b\"\naction = \"ask\"").is_err()
+        );
+    }
+}
@@ -0,0 +1,201 @@
+//! Crash-safe journal of the encoder position.
+//!
+//! Each record replaces the journal atomically: it is written to a temporary
+//! file, synced, and renamed over the previous record. Records are written on
+//! a thread of their own, so a move never waits on the disk.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error::JournalError;
+use crate::Result;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Entry {
+    pub position: isize,
+    /// Set while a move is in progress
+    pub moving: bool,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    interval: Duration,
+    writer: Writer,
+}
+
+impl Journal {
+    /// Journal at `path`, recorded every `interval` during a move
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        let path = path.into();
+        Self {
+            writer: Writer::new(path.clone()),
+            path,
+            interval,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The last recorded entry, None if nothing has been recorded yet
+    pub fn load(&self) -> Result<Option<Entry>> {
+        match fs::read_to_string(&self.path) {
+            Ok(text) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|e| self.error(e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    /// Hand an entry to the writer, replacing any it has not written yet
+    pub fn record(&self, entry: Entry) -> Result<()> {
+        let json = serde_json::to_vec(&entry).map_err(|e| self.error(e))?;
+        self.writer.replace(json);
+        Ok(())
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> crate::Error {
+        JournalError(format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// Replaces or removes a file on a thread of its own. Only the latest change
+/// handed over is kept, so changes made faster than the disk takes them
+/// replace each other and whoever makes them never waits. Dropping the writer
+/// waits for the last change to be made.
+pub(crate) struct Writer {
+    shared: Arc<(Mutex<Pending>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Pending {
+    change: Option<Change>,
+    closed: bool,
+}
+
+enum Change {
+    Replace(Vec<u8>),
+    Remove,
+}
+
+impl Writer {
+    pub fn new(path: PathBuf) -> Self {
+        let shared = Arc::new((Mutex::new(Pending::default()), Condvar::new()));
+        let pending = shared.clone();
+        let thread = std::thread::spawn(move || write(&path, &pending));
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Replace the file atomically with `contents`
+    pub fn replace(&self, contents: Vec<u8>) {
+        self.hand_over(Change::Replace(contents));
+    }
+
+    /// Remove the file, if there is one
+    pub fn remove(&self) {
+        self.hand_over(Change::Remove);
+    }
+
+    fn hand_over(&self, change: Change) {
+        let (pending, ready) = &*self.shared;
+        pending.lock().expect("writer lock poisoned").change = Some(change);
+        ready.notify_one();
+    }
+}
+
+impl Drop for Writer {
+    // wait for the thread to write the last change and end
+    fn drop(&mut self) {
+        let (pending, ready) = &*self.shared;
+        pending.lock().expect("writer lock poisoned").closed = true;
+        ready.notify_one();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("journal writer thread panicked");
+            }
+        }
+    }
+}
+
+// make each change handed over, until the writer is dropped
+fn write(path: &Path, shared: &(Mutex<Pending>, Condvar)) {
+    let (pending, ready) = shared;
+    loop {
+        let change = {
+            let mut pending = pending.lock().expect("writer lock poisoned");
+            loop {
+                if let Some(change) = pending.change.take() {
+                    break change;
+                }
+                if pending.closed {
+                    return;
+                }
+                pending = ready.wait(pending).expect("writer lock poisoned");
+            }
+        };
+        let result = match change {
+            Change::Replace(contents) => replace(path, &contents),
+            Change::Remove => match fs::remove_file(path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            },
+        };
+        if let Err(e) = result {
+            eprintln!("{}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Atomically replace the contents of the file at `path`
+pub(crate) fn replace(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    // appended rather than swapped for the extension, so files sharing a stem do not collide
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // sync the directory so the rename itself survives a power cut
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir).and_then(|d| d.sync_all())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_last_record_is_written_before_the_journal_is_dropped() {
+        let path = std::env::temp_dir().join(format!("gateman-journal-{}", std::process::id()));
+        let journal = Journal::new(&path, Duration::from_millis(100));
+        for position in 0..100 {
+            let moving = position < 99;
+            journal.record(Entry { position, moving }).unwrap();
+        }
+        drop(journal);
+
+        let entry = Journal::new(&path, Duration::from_millis(100)).load();
+        let _ = fs::remove_file(&path);
+        let entry = entry.unwrap().unwrap();
+        assert_eq!(entry.position, 99);
+        assert!(!entry.moving);
+    }
+}
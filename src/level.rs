@@ -0,0 +1,395 @@
+//! Water level measured upstream of the gate, and holding that level by moving the gate.
+//!
+//! In level mode the gate is moved in steps sized to the level error, letting the
+//! pool settle between moves, so the motor only runs when the level has drifted
+//! beyond the deadband.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::gate::{Command, GatemanRef, Mode, State};
+use crate::hal::rpi::{Mcp3008, Ultrasonic};
+use crate::hal::LevelSensor;
+use crate::status::Status;
+use crate::Result;
+
+/// SPI0 pins used by the ADC
+const SPI_PINS: [(&str, u8); 4] = [
+    ("adc ce0", 8),
+    ("adc miso", 9),
+    ("adc mosi", 10),
+    ("adc sclk", 11),
+];
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LevelConfig {
+    /// Sensor the level is read from, no level sensor when not set
+    pub sensor: Option<LevelInput>,
+    /// Milliseconds between level readings
+    pub interval: u64,
+    /// Readings averaged into the level
+    pub samples: usize,
+    /// Metres the level may drift from the setpoint before the gate moves
+    pub deadband: f64,
+    /// Encoder counts the gate moves per metre of level error, negative to hold a level downstream
+    pub gain: f64,
+    /// Encoder counts the gate moves per metre per second the level is changing,
+    /// so a level already heading back to the setpoint is not chased
+    pub damping: f64,
+    /// Smallest move in encoder counts worth running the motor for
+    pub min_move: isize,
+    /// Seconds the pool is left to settle after a move
+    pub settle: u64,
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        Self {
+            sensor: None,
+            interval: 1000,
+            samples: 5,
+            deadband: 0.01,
+            gain: 2000.0,
+            damping: 60000.0,
+            min_move: 10,
+            settle: 30,
+        }
+    }
+}
+
+impl LevelConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_secs(self.settle)
+    }
+
+    /// GPIO pins taken by the sensor
+    pub fn pins(&self) -> Vec<(&'static str, u8)> {
+        self.sensor
+            .map(|s| s.pins(["level trigger_pin", "level echo_pin"]))
+            .unwrap_or_default()
+    }
+}
+
+/// How the level sensor is wired
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LevelInput {
+    /// Analog sensor on a channel of an MCP3008 on SPI0,
+    /// the level is `offset` plus `scale` times the fraction of full scale
+    Adc {
+        channel: u8,
+        scale: f64,
+        offset: f64,
+    },
+    /// Ultrasonic rangefinder looking down at the water from `mount_height` metres
+    Ultrasonic {
+        trigger_pin: u8,
+        echo_pin: u8,
+        mount_height: f64,
+    },
+}
+
+impl LevelInput {
+    /// GPIO pins taken by the sensor, giving the names of the ultrasonic pins
+    pub fn pins(&self, names: [&'static str; 2]) -> Vec<(&'static str, u8)> {
+        match *self {
+            LevelInput::Adc { .. } => SPI_PINS.to_vec(),
+            LevelInput::Ultrasonic {
+                trigger_pin,
+                echo_pin,
+                ..
+            } => vec![(names[0], trigger_pin), (names[1], echo_pin)],
+        }
+    }
+
+    /// Gauge reading the sensor in metres, on the Raspberry Pi
+    pub fn gauge(&self) -> Result<LevelGauge> {
+        Ok(match *self {
+            LevelInput::Adc {
+                channel,
+                scale,
+                offset,
+            } => LevelGauge::linear(Arc::new(Mcp3008::new(channel)?), scale, offset),
+            LevelInput::Ultrasonic {
+                trigger_pin,
+                echo_pin,
+                mount_height,
+            } => LevelGauge::linear(
+                Arc::new(Ultrasonic::new(trigger_pin, echo_pin)?),
+                -1.0,
+                mount_height,
+            ),
+        })
+    }
+}
+
+/// Level in metres from a sensor reading
+#[derive(Clone)]
+pub struct LevelGauge {
+    sensor: Arc<dyn LevelSensor>,
+    scale: f64,
+    offset: f64,
+}
+
+impl LevelGauge {
+    /// Gauge for a sensor that reads the level in metres
+    pub fn new(sensor: Arc<dyn LevelSensor>) -> Self {
+        Self::linear(sensor, 1.0, 0.0)
+    }
+
+    /// Gauge reading `offset + scale * reading`
+    pub fn linear(sensor: Arc<dyn LevelSensor>, scale: f64, offset: f64) -> Self {
+        Self {
+            sensor,
+            scale,
+            offset,
+        }
+    }
+
+    /// Level in metres, blocking until the sensor has been read
+    pub fn read(&self) -> Result<f64> {
+        Ok(self.offset + self.scale * self.sensor.read()?)
+    }
+}
+
+/// Running average of the last readings of a gauge
+struct Readings {
+    values: VecDeque<f64>,
+    samples: usize,
+    interval: Duration,
+}
+
+impl Readings {
+    fn new(config: &LevelConfig) -> Self {
+        Self {
+            values: VecDeque::with_capacity(config.samples),
+            samples: config.samples,
+            interval: config.interval(),
+        }
+    }
+
+    // read the gauge off the runtime, None when the sensor failed
+    async fn read(&mut self, gauge: &LevelGauge) -> Option<f64> {
+        let sensor = gauge.clone();
+        let reading = match tokio::task::spawn_blocking(move || sensor.read()).await {
+            Ok(Ok(level)) => level,
+            Ok(Err(e)) => {
+                eprintln!("{}", e);
+                return None;
+            }
+            Err(e) => {
+                eprintln!("level sensor read failed: {}", e);
+                return None;
+            }
+        };
+        if self.values.len() == self.samples {
+            self.values.pop_front();
+        }
+        self.values.push_back(reading);
+        Some(self.values.iter().sum::<f64>() / self.values.len() as f64)
+    }
+
+    fn full(&self) -> bool {
+        self.values.len() == self.samples
+    }
+
+    // metres per second across the readings averaged
+    fn trend(&self) -> f64 {
+        match (self.values.front(), self.values.back()) {
+            (Some(first), Some(last)) if self.values.len() > 1 => {
+                let span = self.interval.as_secs_f64() * (self.values.len() - 1) as f64;
+                (last - first) / span
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Read the level for as long as the gate is up, publishing the average of the
+/// last `samples` readings. While the gate is in level mode a level beyond the
+/// deadband moves the gate by `gain` times the error plus `damping` times the
+/// trend of the level, kept within the bounds of the gate, when the move is at
+/// least `min_move` and the pool has settled since the last.
+pub async fn hold(gm: GatemanRef, gauge: LevelGauge, config: LevelConfig) {
+    let mut interval = tokio::time::interval(config.interval());
+    let mut readings = Readings::new(&config);
+    let mut moved: Option<Instant> = None;
+
+    while !gm.sender.is_closed() {
+        interval.tick().await;
+        let level = match readings.read(&gauge).await {
+            Some(level) => level,
+            None => continue,
+        };
+        gm.publish(Status::Level(level));
+
+        let snapshot = gm.snapshot();
+        let (setpoint, (lo, hi)) = match (snapshot.mode, gm.bounds()) {
+            (Mode::HoldLevel { setpoint }, Some(bounds)) => (setpoint, bounds),
+            _ => {
+                moved = None;
+                continue;
+            }
+        };
+        if !readings.full() || moved.is_some_and(|t| t.elapsed() < config.settle()) {
+            continue;
+        }
+
+        let error = level - setpoint;
+        if error.abs() <= config.deadband {
+            continue;
+        }
+        let position = match snapshot.state {
+            State::Moving(target) => target,
+            _ => snapshot.position,
+        };
+        let step = (config.gain * error + config.damping * readings.trend()).round() as isize;
+        let target = (position + step).clamp(lo, hi);
+        if (target - position).abs() < config.min_move {
+            continue;
+        }
+        eprintln!("level {:.3} m, moving to {}", level, target);
+        moved = Some(Instant::now());
+        if gm.sender.send(Command::Adjust(target)).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Read the level downstream of the gate for as long as the gate is up,
+/// publishing the average of the last `samples` readings
+pub async fn watch_downstream(gm: GatemanRef, gauge: LevelGauge, config: LevelConfig) {
+    let mut interval = tokio::time::interval(config.interval());
+    let mut readings = Readings::new(&config);
+
+    while !gm.sender.is_closed() {
+        interval.tick().await;
+        if let Some(level) = readings.read(&gauge).await {
+            gm.publish(Status::Downstream(level));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::gate::Sensors;
+    use crate::testing::{self, until};
+
+    /// Level sensor reading whatever level it is set to
+    struct Pool(Mutex<f64>);
+
+    impl Pool {
+        fn set(&self, level: f64) {
+            *self.0.lock().unwrap() = level;
+        }
+    }
+
+    impl LevelSensor for Pool {
+        fn read(&self) -> Result<f64> {
+            Ok(*self.0.lock().unwrap())
+        }
+    }
+
+    // a gate of 4000 counts at 1000 holding the level of the pool, reading it
+    // every 50 ms with no averaging or damping and settling for a second
+    fn start(pool: &Arc<Pool>) -> GatemanRef {
+        let mut config = testing::config();
+        config.level = LevelConfig {
+            interval: 50,
+            samples: 1,
+            deadband: 0.05,
+            gain: 2000.0,
+            damping: 0.0,
+            min_move: 10,
+            settle: 1,
+            ..Default::default()
+        };
+        let (gm, _gate) = testing::start(1000, &config, &BTreeMap::new());
+        let sensors = Sensors {
+            level: Some(LevelGauge::new(pool.clone())),
+            ..Default::default()
+        };
+        gm.control(sensors, &config);
+        gm
+    }
+
+    #[test]
+    fn gauge_scales_the_reading() {
+        let pool = Arc::new(Pool(Mutex::new(0.5)));
+        assert_eq!(LevelGauge::new(pool.clone()).read().unwrap(), 0.5);
+        assert_eq!(LevelGauge::linear(pool, 2.0, 0.1).read().unwrap(), 1.1);
+    }
+
+    #[tokio::test]
+    async fn readings_are_averaged_with_their_trend() {
+        let pool = Arc::new(Pool(Mutex::new(1.0)));
+        let gauge = LevelGauge::new(pool.clone());
+        let config = LevelConfig {
+            interval: 500,
+            samples: 3,
+            ..Default::default()
+        };
+        let mut readings = Readings::new(&config);
+        assert_eq!(readings.read(&gauge).await, Some(1.0));
+        assert_eq!(readings.trend(), 0.0);
+        pool.set(1.3);
+        assert_eq!(readings.read(&gauge).await, Some(1.15));
+        assert!(!readings.full());
+        pool.set(1.2);
+        readings.read(&gauge).await;
+        assert!(readings.full());
+        // the oldest reading drops out
+        pool.set(1.5);
+        let average = readings.read(&gauge).await.unwrap();
+        assert!((average - 4.0 / 3.0).abs() < 1e-9, "{}", average);
+        // 0.2 m over the second between the first and last readings kept
+        assert!((readings.trend() - 0.2).abs() < 1e-9);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn holds_the_level_at_the_setpoint() {
+        let pool = Arc::new(Pool(Mutex::new(1.2)));
+        let gm = start(&pool);
+        // the level is only read, not held, outside level mode
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(gm.snapshot().position, 1000);
+
+        // a high pool opens the gate by the gain times the error
+        gm.sender.send(Command::HoldLevel(1.0)).await.unwrap();
+        until(&gm, |s| matches!(s.state, State::Moving(1400))).await;
+        pool.set(1.02);
+        let snapshot = until(&gm, |s| matches!(s.state, State::Stopped(_))).await;
+        assert!((snapshot.position - 1400).abs() <= 2, "{:?}", snapshot);
+        assert_eq!(snapshot.mode, Mode::HoldLevel { setpoint: 1.0 });
+
+        // within the deadband the gate is left be
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(matches!(gm.snapshot().state, State::Stopped(_)));
+
+        // and a low pool closes it, once the last move has settled
+        pool.set(0.9);
+        until(
+            &gm,
+            |s| matches!(s.state, State::Moving(t) if (t - 1200).abs() <= 2),
+        )
+        .await;
+        pool.set(1.0);
+        until(&gm, |s| matches!(s.state, State::Stopped(_))).await;
+
+        // but never past the ends of the travel
+        pool.set(3.0);
+        until(&gm, |s| matches!(s.state, State::Moving(4000))).await;
+    }
+}
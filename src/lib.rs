@@ -1,6 +1,24 @@
 pub use error::{Error, Result};
 
+pub mod calibration;
 pub mod cli;
+pub mod config;
+pub mod delivery;
 pub mod drive;
 mod error;
+pub mod flow;
 pub mod gate;
+pub mod hal;
+pub mod hydraulics;
+pub mod interlock;
+pub mod journal;
+pub mod level;
+pub mod motion;
+pub mod protocol;
+pub mod recipe;
+pub mod registry;
+pub mod schedule;
+pub mod server;
+pub mod status;
+#[cfg(test)]
+mod testing;
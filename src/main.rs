@@ -1,111 +1,149 @@
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use clap::Parser;
-use futures_util::{SinkExt, TryFutureExt};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use warp::filters::ws::{Message, WebSocket};
-use warp::Filter;
 
 use gateman::cli::Opts;
+use gateman::config::{Config, GateConfig};
 use gateman::drive::Drive;
-use gateman::gate;
-use gateman::gate::Command::Connect;
-use gateman::gate::GatemanRef;
-use gateman::Error;
+use gateman::flow::FlowMeter;
+use gateman::gate::{Command, GatemanRef, Sensors};
+use gateman::hal::rpi::PulseInput;
+use gateman::hal::sim::SimGate;
+use gateman::interlock::{Interlock, Interlocks};
+use gateman::journal::Journal;
+use gateman::level::LevelGauge;
+use gateman::recipe::Recipe;
+use gateman::registry::Registry;
+use gateman::schedule::Scheduler;
+use gateman::{server, Error};
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let opts: Opts = Opts::parse();
+    let mut config = Config::load(opts.config.as_deref())?.merge(&opts);
+    // pins, a position or a journal on the command line can only be right for one gate
+    let single = opts.single_gate();
+    if !single.is_empty() && config.gates.len() > 1 {
+        let message = format!(
+            "{} cannot be used with more than one gate configured",
+            single.join(", ")
+        );
+        return Err(Error::ConfigError(message));
+    }
+    config.validate(opts.simulate)?;
+    for gate in config.gates.values_mut() {
+        gate.calibration.load()?;
+    }
+    let scheduler = Scheduler::load(&config.schedule)?;
 
-    let driver = Drive::new(
-        opts.at,
-        opts.en_pin,
-        opts.dir_pin,
-        opts.clock_pin,
-        opts.data_pin,
-    )?;
-    let gm = GatemanRef::new(driver);
-    let gate = warp::any().map(move || gm.clone());
-
-    let routes = warp::path("gate")
-        .and(warp::ws())
-        .and(gate)
-        .map(|ws: warp::ws::Ws, tx| ws.on_upgrade(|websocket| router(websocket, tx)));
+    let interlocks = Interlocks::new(config.interlocks.clone());
+    let mut gates = BTreeMap::new();
+    let mut sensors = BTreeMap::new();
+    for (name, gate) in &config.gates {
+        if config.gates.len() > 1 {
+            eprintln!("starting gate {}", name);
+        }
+        let (gm, s) = start(gate, &config.recipes, interlocks.on(name), &opts)?;
+        gates.insert(name.clone(), gm);
+        sensors.insert(name.clone(), s);
+    }
+    let registry = Registry::new(gates);
+    interlocks.bind(&registry);
+    // control loops wait for the interlocks too, a resumed delivery is checked against them
+    for (name, s) in sensors {
+        if let Some(gm) = registry.get(&name) {
+            gm.control(s, &config.gates[&name]);
+        }
+    }
+    // homing waits for the interlocks, which need every gate started
+    for (name, gm) in registry.iter() {
+        if config.gates[name].homing.on_startup {
+            gm.sender.send(Command::Home).await.ok();
+        }
+    }
+    tokio::spawn(scheduler.clone().run(registry.clone()));
+    let routes = server::routes(registry, scheduler);
 
     eprintln!(
         "websocket starting on {:?} port {}",
-        opts.address, opts.port
+        config.network.address, config.network.port
     );
-    let address: [u8; 4] = opts.address.into();
-    warp::serve(routes).run((address, opts.port)).await;
+    let address: [u8; 4] = config.network.address.into();
+    warp::serve(routes)
+        .run((address, config.network.port))
+        .await;
 
     Ok(())
 }
 
-// handles the routing of messages to and from the websocket connection
-async fn router(websocket: WebSocket, gm: GatemanRef) {
-    use futures_util::StreamExt;
-
-    let (mut ws_tx, mut from_client) = websocket.split();
-    let (to_client, rx) = mpsc::unbounded_channel();
+// build the drive and sensors of a gate and start its actor, leaving the
+// sensors for its control loops
+fn start(
+    config: &GateConfig,
+    recipes: &BTreeMap<String, Recipe>,
+    interlock: Interlock,
+    opts: &Opts,
+) -> Result<(GatemanRef, Sensors), Error> {
+    let journal = config.journal.journal();
+    let (at, verified) = starting_position(opts.at, journal.as_ref())?;
 
-    // we overwrite the stats channel on new connection
-    gm.sender.send(Connect(to_client.clone())).await.unwrap();
-
-    eprintln!("connected");
+    let k_factor = config.flow.k_factor;
+    let (mut driver, sensors) = if opts.simulate {
+        eprintln!("simulating gate");
+        let gate = SimGate::new(at, config.simulation.clone());
+        let meter = FlowMeter::new(Arc::new(gate.flow_meter(k_factor)), k_factor);
+        let sensors = Sensors {
+            flow: Some(meter),
+            level: Some(LevelGauge::new(Arc::new(gate.level_sensor()))),
+            downstream: None,
+        };
+        (Drive::simulated(&gate, config.motion.clone()), sensors)
+    } else {
+        let flow = match config.flow.pin {
+            Some(pin) => Some(FlowMeter::new(Arc::new(PulseInput::new(pin)?), k_factor)),
+            None => None,
+        };
+        let level = config.level.sensor.map(|s| s.gauge()).transpose()?;
+        let downstream = config
+            .hydraulics
+            .downstream
+            .map(|s| s.gauge())
+            .transpose()?;
+        let driver = Drive::new(at, &config.hardware, config.motion.clone())?;
+        (
+            driver,
+            Sensors {
+                flow,
+                level,
+                downstream,
+            },
+        )
+    };
+    driver.set_verified(verified);
+    driver.set_soft_limits(config.limits);
+    driver.set_stall_detection(config.stall);
+    if let Some(journal) = journal {
+        driver.set_journal(journal);
+    }
+    let gm = GatemanRef::new(driver, &sensors, config, recipes, interlock);
+    Ok((gm, sensors))
+}
 
-    let mut rx = UnboundedReceiverStream::new(rx);
-    // todo;; there is a pipe function available
-    let h = tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            ws_tx
-                .send(Message::text(message))
-                .unwrap_or_else(|e| {
-                    eprintln!("websocket send error: {}", e);
-                })
-                .await;
+// the position given on the command line, else the last journaled position
+fn starting_position(at: Option<isize>, journal: Option<&Journal>) -> Result<(isize, bool), Error> {
+    if let Some(at) = at {
+        return Ok((at, true));
+    }
+    match journal.map(Journal::load).transpose()?.flatten() {
+        Some(entry) => {
+            eprintln!(
+                "restored position {}{}",
+                entry.position,
+                if entry.moving { ", unverified" } else { "" }
+            );
+            Ok((entry.position, !entry.moving))
         }
-    });
-
-    // fire off an initial message to the client
-    // to_client
-    //     .send(Message::text("hello"))
-    //     .expect("failed to init");
-
-    // receive messages from the ws client and hand them off to the gateman
-    while let Ok(result) = tokio::time::timeout(Duration::from_secs(50), from_client.next()).await {
-        match result {
-            Some(Ok(msg)) if msg.is_text() => {
-                let t = msg.to_str().unwrap().trim();
-                match t {
-                    "ping" => {
-                        gm.sender.send(gate::Command::Nop).await.unwrap();
-                    }
-                    "close" => {
-                        println!("cmd: closing");
-                        to_client.send("closing:0".to_string()).unwrap();
-                    }
-                    v => {
-                        let to: u8 = v.parse().unwrap();
-                        println!("cmd: open to {}", to);
-                        gm.sender.send(gate::Command::Open(to)).await.unwrap();
-                        to_client.send(format!("moving:{}", to)).unwrap();
-                    }
-                }
-            }
-            Some(Ok(msg)) if msg.is_close() => {
-                gm.sender.send(gate::Command::Close).await.unwrap();
-                break;
-            }
-            err => {
-                println!("--- unsupported message {:?} ---", err);
-                gm.sender.send(gate::Command::Close).await.unwrap();
-                // gate.send("[e]close".to_string()).unwrap();
-                break;
-            }
-        };
+        None => Ok((0, true)),
     }
-    drop(h);
-    eprintln!("shutting down")
 }
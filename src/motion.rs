@@ -0,0 +1,188 @@
+//! Motion profiles for ramping the step rate up and down over a move.
+
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// Shape of the acceleration and deceleration ramps
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ramp {
+    /// Constant acceleration, linear velocity ramp
+    Trapezoidal,
+    /// Smoothed velocity ramp with gradual changes in acceleration
+    SCurve,
+}
+
+impl FromStr for Ramp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trapezoidal" => Ok(Ramp::Trapezoidal),
+            "s-curve" => Ok(Ramp::SCurve),
+            unsupported => Err(format!("{} is not a valid ramp", unsupported)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionProfile {
+    /// Step rate in Hz at the start and end of a move
+    pub start_rate: f64,
+    /// Cruising step rate in Hz
+    pub max_rate: f64,
+    /// Acceleration in steps/s²
+    pub acceleration: f64,
+    /// Motor steps per encoder count
+    pub steps_per_count: f64,
+    pub ramp: Ramp,
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        Self {
+            start_rate: 100.0,
+            max_rate: 500.0,
+            acceleration: 500.0,
+            steps_per_count: 1.0,
+            ramp: Ramp::Trapezoidal,
+        }
+    }
+}
+
+impl MotionProfile {
+    /// Profile that runs the whole move at a single rate
+    pub fn constant(rate: f64) -> Self {
+        Self {
+            start_rate: rate,
+            max_rate: rate,
+            ..Default::default()
+        }
+    }
+
+    /// The step rate for a move that has travelled `done` encoder counts
+    /// and has `remaining` counts left to go
+    pub fn rate(&self, done: isize, remaining: isize) -> f64 {
+        let accelerating = self.ramp_rate(done.unsigned_abs());
+        let decelerating = self.ramp_rate(remaining.unsigned_abs());
+        accelerating.min(decelerating)
+    }
+
+    // rate reached after ramping over a number of encoder counts
+    fn ramp_rate(&self, counts: usize) -> f64 {
+        let start = self.start_rate.min(self.max_rate);
+        let steps = counts as f64 * self.steps_per_count;
+        if self.acceleration <= 0.0 {
+            return self.max_rate;
+        }
+
+        match self.ramp {
+            Ramp::Trapezoidal => (start.powi(2) + 2.0 * self.acceleration * steps)
+                .sqrt()
+                .min(self.max_rate),
+            Ramp::SCurve => {
+                let length = self.ramp_length();
+                let x = if length > 0.0 {
+                    (steps / length).min(1.0)
+                } else {
+                    1.0
+                };
+                start + (self.max_rate - start) * x * x * (3.0 - 2.0 * x)
+            }
+        }
+    }
+
+    // steps needed to ramp from the start rate to the max rate
+    fn ramp_length(&self) -> f64 {
+        let start = self.start_rate.min(self.max_rate);
+        (self.max_rate.powi(2) - start.powi(2)) / (2.0 * self.acceleration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(ramp: Ramp) -> MotionProfile {
+        MotionProfile {
+            ramp,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn trapezoid_ramps_up_cruises_and_ramps_down() {
+        let p = profile(Ramp::Trapezoidal);
+        // (500² - 100²) / (2 * 500) = 240 steps to reach the max rate
+        assert_eq!(p.rate(0, 1000), 100.0);
+        assert_eq!(
+            p.rate(10, 1000),
+            (100f64.powi(2) + 2.0 * 500.0 * 10.0).sqrt()
+        );
+        assert_eq!(p.rate(240, 760), 500.0);
+        assert_eq!(p.rate(500, 500), 500.0);
+        assert_eq!(p.rate(990, 10), p.rate(10, 990));
+        assert_eq!(p.rate(1000, 0), 100.0);
+    }
+
+    #[test]
+    fn trapezoid_rate_never_falls_as_a_ramp_goes_on() {
+        let p = profile(Ramp::Trapezoidal);
+        let rates: Vec<f64> = (0..300).map(|done| p.rate(done, 1000)).collect();
+        assert!(rates.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn s_curve_eases_in_and_out() {
+        let p = profile(Ramp::SCurve);
+        assert_eq!(p.rate(0, 1000), 100.0);
+        assert_eq!(p.rate(120, 1000), 300.0);
+        assert_eq!(p.rate(240, 1000), 500.0);
+        // slower than the trapezoid early on, faster toward the end of the ramp
+        let trapezoid = profile(Ramp::Trapezoidal);
+        assert!(p.rate(20, 1000) < trapezoid.rate(20, 1000));
+        assert!(p.rate(230, 1000) > trapezoid.rate(230, 1000));
+        let rates: Vec<f64> = (0..300).map(|done| p.rate(done, 1000)).collect();
+        assert!(rates.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn short_move_peaks_below_the_max_rate() {
+        for ramp in [Ramp::Trapezoidal, Ramp::SCurve] {
+            let p = profile(ramp);
+            let peak = (0..=100)
+                .map(|done| p.rate(done, 100 - done))
+                .fold(0.0, f64::max);
+            assert_eq!(peak, p.rate(50, 50));
+            assert!(peak < 500.0);
+            assert!(peak > 100.0);
+        }
+    }
+
+    #[test]
+    fn rate_ignores_the_direction_of_the_move() {
+        let p = profile(Ramp::Trapezoidal);
+        assert_eq!(p.rate(-10, -990), p.rate(10, 990));
+    }
+
+    #[test]
+    fn constant_profile_runs_at_one_rate() {
+        let p = MotionProfile::constant(250.0);
+        assert_eq!(p.rate(0, 1000), 250.0);
+        assert_eq!(p.rate(500, 500), 250.0);
+        assert_eq!(p.rate(1000, 0), 250.0);
+    }
+
+    #[test]
+    fn steps_per_count_lengthens_the_ramp() {
+        let p = MotionProfile {
+            steps_per_count: 2.0,
+            ..Default::default()
+        };
+        // 240 steps take 120 counts
+        assert_eq!(p.rate(120, 1000), 500.0);
+        assert!(p.rate(100, 1000) < 500.0);
+    }
+}
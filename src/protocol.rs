@@ -0,0 +1,402 @@
+//! Versioned JSON message schema for the websocket interface.
+//!
+//! A client opts in by sending `{"type":"hello","version":1}` as its first
+//! message. Clients that do not are served the legacy text protocol.
+
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::Opening;
+use crate::config::Failsafe;
+use crate::delivery::Progress;
+use crate::drive::Limit;
+use crate::gate::{Command, GatemanRef, Mode, State};
+use crate::interlock::Check;
+use crate::recipe::{self, Recipe, RunState, Step};
+use crate::status::{Snapshot, Status};
+
+pub const VERSION: u32 = 1;
+
+/// A message from the client, with an optional id echoed in the reply
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub body: RequestBody,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RequestBody {
+    Hello {
+        version: u32,
+    },
+    Ping,
+    /// `target` in the `unit` given, defaulting to legacy units
+    Open(Opening),
+    Close,
+    Stop,
+    Home,
+    Calibrate,
+    ResetFault,
+    /// Hold a flow `rate` in litres per second
+    SetFlow {
+        rate: f64,
+    },
+    Deliver {
+        volume: f64,
+    },
+    HoldLevel {
+        setpoint: f64,
+    },
+    /// The `steps` given, or the configured recipe `name`d
+    RunRecipe(Recipe),
+    PauseRecipe,
+    ResumeRecipe,
+    AbortRecipe,
+}
+
+impl RequestBody {
+    /// The gate command carried by the request, if any
+    pub fn command(&self) -> Option<Command> {
+        match self {
+            RequestBody::Hello { .. } => None,
+            RequestBody::Ping => Some(Command::Nop),
+            RequestBody::Open(opening) => Some(Command::Open(*opening)),
+            RequestBody::Close => Some(Command::Close),
+            RequestBody::Stop => Some(Command::Stop),
+            RequestBody::Home => Some(Command::Home),
+            RequestBody::Calibrate => Some(Command::Calibrate),
+            RequestBody::ResetFault => Some(Command::ResetFault),
+            RequestBody::SetFlow { rate } => Some(Command::SetFlow(*rate)),
+            RequestBody::Deliver { volume } => Some(Command::Deliver(*volume)),
+            RequestBody::HoldLevel { setpoint } => Some(Command::HoldLevel(*setpoint)),
+            RequestBody::RunRecipe(recipe) => Some(Command::RunRecipe(recipe.clone())),
+            RequestBody::PauseRecipe => Some(Command::PauseRecipe),
+            RequestBody::ResumeRecipe => Some(Command::ResumeRecipe),
+            RequestBody::AbortRecipe => Some(Command::AbortRecipe),
+        }
+    }
+}
+
+/// Why the gate cannot take a command, checked before it is sent
+pub fn refusal(cmd: &Command, gm: &GatemanRef) -> Option<(ErrorCode, String)> {
+    let fault = match gm.snapshot().state {
+        State::LimitFault(limit) => Some(format!("gate stopped at the {} limit", limit)),
+        State::Stalled => Some("gate stalled".to_string()),
+        State::Faulted(error) => Some(format!("gate faulted: {}", error)),
+        _ => None,
+    };
+    let beyond = gm.beyond_limits(cmd);
+    match cmd {
+        Command::ResetFault
+        | Command::Stop
+        | Command::Hold(None)
+        | Command::Failsafe(_)
+        | Command::Nop => None,
+        _ if fault.is_some() => {
+            fault.map(|f| (ErrorCode::Faulted, format!("{}, reset the fault first", f)))
+        }
+        _ if beyond.is_some() => beyond.map(|target| {
+            let message = format!("target {} is outside the soft limits", target);
+            (ErrorCode::OutOfRange, message)
+        }),
+        Command::Open(opening) | Command::Hold(Some(opening)) if gm.counts(*opening).is_none() => {
+            Some((
+                ErrorCode::NotCalibrated,
+                format!("gate is not calibrated for {}", opening),
+            ))
+        }
+        Command::SetFlow(_) | Command::Deliver(_) if !gm.has_flow_meter() => {
+            Some((ErrorCode::Unavailable, "gate has no flow meter".to_string()))
+        }
+        Command::SetFlow(rate) if !(rate.is_finite() && *rate >= 0.0) => Some((
+            ErrorCode::InvalidRequest,
+            format!("flow rate {} must be 0 or more", rate),
+        )),
+        Command::Deliver(volume) if !(volume.is_finite() && *volume > 0.0) => Some((
+            ErrorCode::InvalidRequest,
+            format!("volume {} must be above 0", volume),
+        )),
+        Command::Deliver(_) if gm.counts(gm.delivery_opening()).is_none() => Some((
+            ErrorCode::NotCalibrated,
+            format!("gate is not calibrated for {}", gm.delivery_opening()),
+        )),
+        Command::HoldLevel(_) if !gm.has_level_sensor() => Some((
+            ErrorCode::Unavailable,
+            "gate has no level sensor".to_string(),
+        )),
+        Command::HoldLevel(level) if !(level.is_finite() && *level >= 0.0) => Some((
+            ErrorCode::InvalidRequest,
+            format!("level {} must be 0 or more", level),
+        )),
+        Command::SetFlow(_) | Command::HoldLevel(_) if gm.bounds().is_none() => Some((
+            ErrorCode::NotCalibrated,
+            "gate needs a calibrated travel or a max soft limit to regulate".to_string(),
+        )),
+        Command::RunRecipe(recipe) => recipe_refusal(recipe, gm),
+        Command::PauseRecipe if gm.snapshot().mode != (Mode::Recipe { paused: false }) => {
+            Some((ErrorCode::NotRunning, "no recipe is running".to_string()))
+        }
+        Command::ResumeRecipe if gm.snapshot().mode != (Mode::Recipe { paused: true }) => {
+            Some((ErrorCode::NotRunning, "no recipe is paused".to_string()))
+        }
+        Command::AbortRecipe if !matches!(gm.snapshot().mode, Mode::Recipe { .. }) => {
+            Some((ErrorCode::NotRunning, "no recipe is running".to_string()))
+        }
+        _ => match gm.interlock(cmd) {
+            Check::Refused(reason) => Some((ErrorCode::Interlocked, reason)),
+            _ => None,
+        },
+    }
+}
+
+// why the gate cannot run a recipe, from the steps it takes
+fn recipe_refusal(recipe: &Recipe, gm: &GatemanRef) -> Option<(ErrorCode, String)> {
+    let recipe = match (gm.recipe(recipe), &recipe.name) {
+        (Some(recipe), _) => recipe,
+        (None, Some(name)) => {
+            return Some((ErrorCode::NotFound, format!("no recipe named {}", name)));
+        }
+        (None, None) => {
+            return Some((ErrorCode::InvalidRequest, "recipe has no steps".to_string()));
+        }
+    };
+    if let Err(e) = recipe.check() {
+        return Some((ErrorCode::InvalidRequest, e));
+    }
+    recipe.steps.iter().find_map(|step| match step {
+        Step::Move(opening) if gm.counts(*opening).is_none() => Some((
+            ErrorCode::NotCalibrated,
+            format!("gate is not calibrated for {}", opening),
+        )),
+        Step::Move(opening) => gm.beyond_limits(&Command::Open(*opening)).map(|target| {
+            let message = format!("{} at {} is outside the soft limits", opening, target);
+            (ErrorCode::OutOfRange, message)
+        }),
+        Step::Wait { until, .. } if until.on_flow() && !gm.has_flow_meter() => {
+            Some((ErrorCode::Unavailable, "gate has no flow meter".to_string()))
+        }
+        Step::Wait { until, .. } if !until.on_flow() && !gm.has_level_sensor() => Some((
+            ErrorCode::Unavailable,
+            "gate has no level sensor".to_string(),
+        )),
+        _ => None,
+    })
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    Hello {
+        version: u32,
+    },
+    Ack {
+        id: Option<u64>,
+    },
+    Error {
+        id: Option<u64>,
+        code: ErrorCode,
+        message: String,
+    },
+    Status {
+        state: GateState,
+        position: isize,
+        verified: bool,
+        target: Option<isize>,
+        limit: Option<Limit>,
+        fault: Option<String>,
+        percent: Option<f64>,
+        area: Option<f64>,
+        mode: Mode,
+        flow: Option<f64>,
+        delivery: Option<Progress>,
+        level: Option<f64>,
+        downstream: Option<f64>,
+        recipe: Option<recipe::Progress>,
+        estimated_flow: Option<f64>,
+    },
+    Position {
+        position: isize,
+        percent: Option<f64>,
+        area: Option<f64>,
+        estimated_flow: Option<f64>,
+    },
+    MoveStarted,
+    MoveFinished,
+    Flow {
+        rate: f64,
+    },
+    Delivery {
+        volume: f64,
+        delivered: f64,
+        remaining: f64,
+    },
+    Level {
+        level: f64,
+    },
+    Downstream {
+        level: f64,
+    },
+    Recipe {
+        name: Option<String>,
+        step: usize,
+        steps: usize,
+        state: RunState,
+    },
+    Failsafe {
+        action: Failsafe,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    UnsupportedVersion,
+    Busy,
+    Unavailable,
+    NotCalibrated,
+    NotFound,
+    NotRunning,
+    /// An interlock with another gate holds the gate back
+    Interlocked,
+    /// The gate takes nothing but a stop or a fault reset until it is reset
+    Faulted,
+    /// The target is outside the soft limits of the gate
+    OutOfRange,
+}
+
+#[derive(Serialize, Debug, Copy, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum GateState {
+    Stopped,
+    Moving,
+    Homing,
+    Calibrating,
+    LimitFault,
+    Stalled,
+    Faulted,
+}
+
+impl Reply {
+    pub fn error(id: Option<u64>, code: ErrorCode, message: impl Into<String>) -> Self {
+        Reply::Error {
+            id,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// The reply for a status event, giving positions as openings of the gate
+    /// and the flow estimated through them too
+    pub fn status(status: &Status, gm: &GatemanRef) -> Self {
+        let mut reply = Reply::from(status);
+        if let Reply::Status {
+            position,
+            percent,
+            area,
+            estimated_flow,
+            ..
+        }
+        | Reply::Position {
+            position,
+            percent,
+            area,
+            estimated_flow,
+        } = &mut reply
+        {
+            *percent = gm.percent(*position);
+            *area = gm.area(*position);
+            *estimated_flow = gm.estimated_flow(*position);
+        }
+        reply
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reply serializes")
+    }
+}
+
+/// A reply about one of several gates, naming the gate
+#[derive(Serialize, Debug)]
+pub struct GateReply<'a> {
+    pub gate: &'a str,
+    #[serde(flatten)]
+    pub reply: Reply,
+}
+
+impl GateReply<'_> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reply serializes")
+    }
+}
+
+impl From<&Snapshot> for Reply {
+    fn from(snapshot: &Snapshot) -> Self {
+        let (state, target, limit) = match snapshot.state {
+            State::Stopped(_) => (GateState::Stopped, None, None),
+            State::Moving(target) => (GateState::Moving, Some(target), None),
+            State::Homing => (GateState::Homing, None, None),
+            State::Calibrating => (GateState::Calibrating, None, None),
+            State::LimitFault(limit) => (GateState::LimitFault, None, Some(limit)),
+            State::Stalled => (GateState::Stalled, None, None),
+            State::Faulted(_) => (GateState::Faulted, None, None),
+        };
+        let fault = match &snapshot.state {
+            State::Faulted(error) => Some(error.clone()),
+            _ => None,
+        };
+        Reply::Status {
+            state,
+            position: snapshot.position,
+            verified: snapshot.verified,
+            target,
+            limit,
+            fault,
+            percent: None,
+            area: None,
+            mode: snapshot.mode,
+            flow: snapshot.flow,
+            delivery: snapshot.delivery,
+            level: snapshot.level,
+            downstream: snapshot.downstream,
+            recipe: snapshot.recipe.clone(),
+            estimated_flow: None,
+        }
+    }
+}
+
+impl From<&Status> for Reply {
+    fn from(status: &Status) -> Self {
+        match status {
+            Status::Snapshot(snapshot) => snapshot.into(),
+            Status::Begin => Reply::MoveStarted,
+            Status::Position(position) => Reply::Position {
+                position: *position,
+                percent: None,
+                area: None,
+                estimated_flow: None,
+            },
+            Status::Done => Reply::MoveFinished,
+            Status::Flow(rate) => Reply::Flow { rate: *rate },
+            Status::Level(level) => Reply::Level { level: *level },
+            Status::Downstream(level) => Reply::Downstream { level: *level },
+            Status::Delivery(progress) => Reply::Delivery {
+                volume: progress.volume,
+                delivered: progress.delivered,
+                remaining: progress.remaining(),
+            },
+            Status::Recipe(progress) => Reply::Recipe {
+                name: progress.name.clone(),
+                step: progress.step,
+                steps: progress.steps,
+                state: progress.state,
+            },
+            Status::Failsafe { action, reason } => Reply::Failsafe {
+                action: *action,
+                reason: reason.to_string(),
+            },
+        }
+    }
+}
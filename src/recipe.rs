@@ -0,0 +1,601 @@
+//! Recipes, sequences of moves, dwells and waits run on the gate step by step.
+//!
+//! The actor hands each recipe it is asked to run to the runner, and pauses,
+//! resumes and aborts it by changing the mode, while any other motion command
+//! ends it. Progress through the steps is published on the
+//! status bus.
+
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::calibration::Opening;
+use crate::gate::{Command, GatemanRef, Mode, State};
+use crate::interlock::Check;
+use crate::status::{Snapshot, Status};
+
+/// How often a dwell, a wait or a pause is checked on
+const TICK: Duration = Duration::from_millis(250);
+
+/// How long a move waits for the gate to set off, which may first wait on
+/// another gate an interlock needs moved
+const START: Duration = Duration::from_secs(60);
+
+/// Ordered steps run on the gate
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Recipe {
+    /// Name of a configured recipe to run when no steps are given, else a label for the steps
+    pub name: Option<String>,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    /// Move to an opening, waiting for the gate to get there
+    Move(Opening),
+    /// Hold still for a number of seconds
+    Dwell { seconds: f64 },
+    /// Hold still until a condition is met, ending the recipe after `timeout` seconds
+    Wait {
+        until: Condition,
+        timeout: Option<f64>,
+    },
+    /// Go back to step `to`, `times` more times or forever when not set
+    Loop { to: usize, times: Option<u32> },
+}
+
+/// Sensor reading a wait step waits for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// Upstream level in metres
+    LevelAbove(f64),
+    LevelBelow(f64),
+    /// Flow rate in litres per second
+    FlowAbove(f64),
+    FlowBelow(f64),
+}
+
+impl Condition {
+    /// Whether the condition reads the flow meter rather than the level sensor
+    pub fn on_flow(&self) -> bool {
+        matches!(self, Condition::FlowAbove(_) | Condition::FlowBelow(_))
+    }
+
+    fn value(&self) -> f64 {
+        match *self {
+            Condition::LevelAbove(v)
+            | Condition::LevelBelow(v)
+            | Condition::FlowAbove(v)
+            | Condition::FlowBelow(v) => v,
+        }
+    }
+
+    // never met before the sensor has been read
+    fn met(&self, snapshot: &Snapshot) -> bool {
+        match *self {
+            Condition::LevelAbove(v) => snapshot.level.is_some_and(|l| l > v),
+            Condition::LevelBelow(v) => snapshot.level.is_some_and(|l| l < v),
+            Condition::FlowAbove(v) => snapshot.flow.is_some_and(|r| r > v),
+            Condition::FlowBelow(v) => snapshot.flow.is_some_and(|r| r < v),
+        }
+    }
+}
+
+impl Recipe {
+    /// Check the steps make sense, whatever gate they run on
+    pub fn check(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err("recipe has no steps".to_string());
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            match *step {
+                Step::Dwell { seconds } if !(seconds.is_finite() && seconds >= 0.0) => {
+                    return Err(format!("step {} dwell {} must be 0 or more", i, seconds));
+                }
+                Step::Wait {
+                    timeout: Some(timeout),
+                    ..
+                } if !(timeout.is_finite() && timeout >= 0.0) => {
+                    return Err(format!("step {} timeout {} must be 0 or more", i, timeout));
+                }
+                Step::Wait { until, .. } if !until.value().is_finite() => {
+                    return Err(format!("step {} waits for {:?}", i, until));
+                }
+                Step::Loop { to, .. } if to >= i => {
+                    return Err(format!("step {} can only loop back, not to step {}", i, to));
+                }
+                Step::Loop { times: Some(0), .. } => {
+                    return Err(format!("step {} loops 0 times", i));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("unnamed")
+    }
+}
+
+/// How far through a recipe the gate is
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub name: Option<String>,
+    /// Index of the step running, or of the step the recipe ended on
+    pub step: usize,
+    pub steps: usize,
+    pub state: RunState,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState {
+    Running,
+    Paused,
+    Finished,
+    Aborted,
+}
+
+impl Display for RunState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RunState::Running => "running",
+            RunState::Paused => "paused",
+            RunState::Finished => "finished",
+            RunState::Aborted => "aborted",
+        })
+    }
+}
+
+// how a step ended
+enum Outcome {
+    Done,
+    /// Paused partway, the step runs again on resume
+    Paused,
+    /// The gate left recipe mode
+    Ended,
+    /// The recipe cannot go on, for the reason given
+    Failed(String),
+}
+
+/// Run the recipes the gate hands over for as long as it is up, a new recipe
+/// replacing the one running
+pub async fn run(gm: GatemanRef, mut recipes: watch::Receiver<Option<(u64, Recipe)>>) {
+    while recipes.changed().await.is_ok() {
+        let mut next = recipes.borrow_and_update().clone();
+        while let Some((id, recipe)) = next.take() {
+            let recipe = match gm.recipe(&recipe) {
+                Some(recipe) => recipe,
+                None => {
+                    eprintln!("no recipe named {}", recipe.label());
+                    let _ = gm.sender.send(Command::EndRecipe(id)).await;
+                    continue;
+                }
+            };
+            let mut run = Run::new(&gm, &recipe);
+            select! {
+                _ = run.follow(id, &recipe) => {}
+                changed = recipes.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    eprintln!("recipe {} replaced", recipe.label());
+                    next = recipes.borrow_and_update().clone();
+                }
+            }
+        }
+    }
+}
+
+/// A recipe being run, with its progress as last published
+struct Run<'a> {
+    gm: &'a GatemanRef,
+    progress: Progress,
+}
+
+impl<'a> Run<'a> {
+    fn new(gm: &'a GatemanRef, recipe: &Recipe) -> Self {
+        Self {
+            gm,
+            progress: Progress {
+                name: recipe.name.clone(),
+                step: 0,
+                steps: recipe.steps.len(),
+                state: RunState::Running,
+            },
+        }
+    }
+
+    // step through the recipe until it ends, returning the gate to manual
+    // unless it has already left recipe mode
+    async fn follow(&mut self, id: u64, recipe: &Recipe) {
+        eprintln!("running recipe {}", recipe.label());
+        self.publish();
+        // passes made by each loop step since it last fell through
+        let mut passes: HashMap<usize, u32> = HashMap::new();
+        let outcome = loop {
+            let step = self.progress.step;
+            let outcome = match recipe.steps.get(step) {
+                None => break Outcome::Done,
+                Some(Step::Move(opening)) => match self.gm.counts(*opening) {
+                    Some(target) => self.travel(target).await,
+                    None => Outcome::Failed(format!("gate is not calibrated for {}", opening)),
+                },
+                Some(Step::Dwell { seconds }) => self.hold(Some(*seconds), None).await,
+                Some(Step::Wait { until, timeout }) => self.hold(*timeout, Some(*until)).await,
+                Some(Step::Loop { to, times }) => {
+                    let pass = passes.entry(step).or_default();
+                    let next = if times.is_none_or(|times| *pass < times) {
+                        *pass += 1;
+                        *to
+                    } else {
+                        *pass = 0;
+                        step + 1
+                    };
+                    // a loop over steps that finish at once does not spin
+                    tokio::time::sleep(TICK).await;
+                    self.progress.step = next;
+                    self.publish();
+                    continue;
+                }
+            };
+            match outcome {
+                Outcome::Done => {
+                    self.progress.step += 1;
+                    if self.progress.step < self.progress.steps {
+                        self.publish();
+                    }
+                }
+                Outcome::Paused => {}
+                outcome => break outcome,
+            }
+        };
+
+        match outcome {
+            Outcome::Done => {
+                eprintln!("recipe {} finished", recipe.label());
+                self.progress.step = self.progress.steps - 1;
+                self.report(RunState::Finished);
+            }
+            Outcome::Failed(reason) => {
+                eprintln!("recipe {} aborted, {}", recipe.label(), reason);
+                self.report(RunState::Aborted);
+            }
+            _ => {
+                eprintln!("recipe {} aborted", recipe.label());
+                self.report(RunState::Aborted);
+                return;
+            }
+        }
+        let _ = self.gm.sender.send(Command::EndRecipe(id)).await;
+    }
+
+    // move the gate to a target once it is not paused, and wait for it to stop
+    // there, failing when the gate does not set off toward it
+    async fn travel(&mut self, target: isize) -> Outcome {
+        if !self.resumed().await {
+            return Outcome::Ended;
+        }
+        let cmd = Command::Adjust(target);
+        if let Some(target) = self.gm.beyond_limits(&cmd) {
+            return Outcome::Failed(format!("{} is outside the soft limits", target));
+        }
+        if let Check::Refused(reason) = self.gm.interlock(&cmd) {
+            return Outcome::Failed(reason);
+        }
+        let mut status = self.gm.subscribe();
+        if self.gm.sender.send(cmd).await.is_err() {
+            return Outcome::Ended;
+        }
+        // the first snapshot can be from before the move
+        let mut started = false;
+        let deadline = Instant::now() + START;
+        loop {
+            let status = if started {
+                status.recv().await
+            } else {
+                match tokio::time::timeout_at(deadline, status.recv()).await {
+                    Ok(status) => status,
+                    Err(_) => return Outcome::Failed(format!("gate did not move to {}", target)),
+                }
+            };
+            let snapshot = match status {
+                Some(Status::Snapshot(snapshot)) => snapshot,
+                Some(_) => continue,
+                None => return Outcome::Ended,
+            };
+            match snapshot.mode {
+                Mode::Recipe { paused: false } => {}
+                Mode::Recipe { paused: true } => return Outcome::Paused,
+                _ => return Outcome::Ended,
+            }
+            match snapshot.state {
+                State::Moving(to) => started |= to == target,
+                State::Stopped(at) if started || at == target => return Outcome::Done,
+                State::Stopped(_) => {}
+                _ => return Outcome::Ended,
+            }
+        }
+    }
+
+    // hold still for `limit` seconds or until the condition is met, not
+    // counting the time spent paused, failing a wait that runs out of time
+    async fn hold(&mut self, limit: Option<f64>, until: Option<Condition>) -> Outcome {
+        let mut held = Duration::ZERO;
+        let mut last = Instant::now();
+        loop {
+            let snapshot = self.gm.snapshot();
+            let now = Instant::now();
+            match snapshot.mode {
+                Mode::Recipe { paused: false } => {
+                    held += now - last;
+                    self.report(RunState::Running);
+                }
+                Mode::Recipe { paused: true } => self.report(RunState::Paused),
+                _ => return Outcome::Ended,
+            }
+            last = now;
+            if self.progress.state == RunState::Running {
+                if until.is_some_and(|c| c.met(&snapshot)) {
+                    return Outcome::Done;
+                }
+                if limit.is_some_and(|l| held.as_secs_f64() >= l) {
+                    return match until {
+                        Some(c) => Outcome::Failed(format!("timed out waiting for {:?}", c)),
+                        None => Outcome::Done,
+                    };
+                }
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+
+    // wait out a pause, false once the gate has left recipe mode
+    async fn resumed(&mut self) -> bool {
+        loop {
+            match self.gm.snapshot().mode {
+                Mode::Recipe { paused: false } => {
+                    self.report(RunState::Running);
+                    return true;
+                }
+                Mode::Recipe { paused: true } => self.report(RunState::Paused),
+                _ => return false,
+            }
+            tokio::time::sleep(TICK).await;
+        }
+    }
+
+    fn report(&mut self, state: RunState) {
+        if self.progress.state != state {
+            self.progress.state = state;
+            self.publish();
+        }
+    }
+
+    fn publish(&self) {
+        self.gm.publish(Status::Recipe(self.progress.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::protocol::{refusal, ErrorCode};
+    use crate::testing::{self, until};
+
+    fn recipe(toml: &str) -> Recipe {
+        toml::from_str(toml).unwrap()
+    }
+
+    // a closed gate of 4000 counts with a soft limit at 3000, and the named recipes
+    fn start(recipes: &[(&str, Recipe)]) -> GatemanRef {
+        let mut config = testing::config();
+        config.limits.max = Some(3000);
+        let recipes = recipes
+            .iter()
+            .map(|(name, recipe)| (name.to_string(), recipe.clone()))
+            .collect::<BTreeMap<_, _>>();
+        testing::start(0, &config, &recipes).0
+    }
+
+    fn ended(state: RunState) -> impl Fn(&Snapshot) -> bool {
+        move |s| s.mode == Mode::Manual && s.recipe.as_ref().is_some_and(|p| p.state == state)
+    }
+
+    fn at(target: isize) -> impl Fn(&Snapshot) -> bool {
+        move |s| matches!(s.state, State::Stopped(_)) && (s.position - target).abs() <= 2
+    }
+
+    #[test]
+    fn steps_are_read_as_written() {
+        let flush = recipe(
+            r#"steps = [
+                { type = "move", target = 50, unit = "percent" },
+                { type = "dwell", seconds = 1.5 },
+                { type = "wait", until = { level_above = 1.2 }, timeout = 60 },
+                { type = "loop", to = 0, times = 2 },
+            ]"#,
+        );
+        assert!(flush.check().is_ok());
+        assert!(matches!(
+            flush.steps[..],
+            [
+                Step::Move(Opening::Percent(p)),
+                Step::Dwell { seconds },
+                Step::Wait {
+                    until: Condition::LevelAbove(level),
+                    timeout: Some(timeout),
+                },
+                Step::Loop {
+                    to: 0,
+                    times: Some(2)
+                },
+            ] if p == 50.0 && seconds == 1.5 && level == 1.2 && timeout == 60.0
+        ));
+        assert!(toml::from_str::<Recipe>("steps = [{ type = \"jump\" }]").is_err());
+    }
+
+    #[test]
+    fn steps_are_checked() {
+        assert!(recipe("")
+            .check()
+            .is_err_and(|e| e == "recipe has no steps"));
+        let bad = [
+            (
+                "{ type = \"dwell\", seconds = -1 }",
+                "step 0 dwell -1 must be 0 or more",
+            ),
+            (
+                "{ type = \"wait\", until = { flow_below = 1 }, timeout = -1 }",
+                "step 0 timeout -1 must be 0 or more",
+            ),
+            (
+                "{ type = \"wait\", until = { flow_below = nan } }",
+                "step 0 waits for FlowBelow(NaN)",
+            ),
+            (
+                "{ type = \"loop\", to = 0 }",
+                "step 0 can only loop back, not to step 0",
+            ),
+            (
+                "{ type = \"dwell\", seconds = 0 }, { type = \"loop\", to = 0, times = 0 }",
+                "step 1 loops 0 times",
+            ),
+        ];
+        for (steps, error) in bad {
+            let result = recipe(&format!("steps = [{}]", steps)).check();
+            assert!(result.as_ref().is_err_and(|e| e == error), "{:?}", result);
+        }
+    }
+
+    #[tokio::test]
+    async fn recipes_the_gate_cannot_run_are_refused() {
+        let gm = start(&[]);
+        let refused = |toml: &str| refusal(&Command::RunRecipe(recipe(toml)), &gm);
+        assert!(matches!(
+            refused(r#"steps = [{ type = "move", target = 3500, unit = "counts" }]"#),
+            Some((ErrorCode::OutOfRange, reason)) if reason.contains("outside the soft limits")
+        ));
+        assert!(matches!(
+            refused("steps = [{ type = \"wait\", until = { flow_above = 1 } }]"),
+            Some((ErrorCode::Unavailable, _))
+        ));
+        assert!(matches!(
+            refused("name = \"missing\""),
+            Some((ErrorCode::NotFound, _))
+        ));
+        assert!(
+            refused(r#"steps = [{ type = "move", target = 2500, unit = "counts" }]"#).is_none()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_the_steps_in_order() {
+        let steps = r#"steps = [
+            { type = "move", target = 600, unit = "counts" },
+            { type = "dwell", seconds = 0.3 },
+            { type = "move", target = 300, unit = "counts" },
+            { type = "loop", to = 0, times = 1 },
+            { type = "move", target = 900, unit = "counts" },
+        ]"#;
+        let gm = start(&[("shuttle", recipe(steps))]);
+        // the steps run are published as they start, the loop going back once
+        let mut status = gm.subscribe();
+        let steps = tokio::spawn(async move {
+            let mut steps = vec![];
+            while let Some(status) = status.recv().await {
+                if let Status::Recipe(progress) = status {
+                    if steps.last() != Some(&progress.step) {
+                        steps.push(progress.step);
+                    }
+                    if progress.state == RunState::Finished {
+                        return steps;
+                    }
+                }
+            }
+            steps
+        });
+        let named = Recipe {
+            name: Some("shuttle".to_string()),
+            steps: vec![],
+        };
+        gm.sender.send(Command::RunRecipe(named)).await.unwrap();
+        let snapshot = until(&gm, ended(RunState::Finished)).await;
+        assert!(at(900)(&snapshot), "{:?}", snapshot);
+        let steps = steps.await.unwrap();
+        assert_eq!(steps, [0, 1, 2, 3, 0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pauses_and_resumes() {
+        let gm = start(&[]);
+        let steps = recipe(
+            r#"steps = [
+                { type = "move", target = 2000, unit = "counts" },
+                { type = "move", target = 100, unit = "counts" },
+            ]"#,
+        );
+        gm.sender.send(Command::RunRecipe(steps)).await.unwrap();
+        until(&gm, |s| s.position > 500).await;
+        gm.sender.send(Command::PauseRecipe).await.unwrap();
+        let paused = until(&gm, |s| {
+            matches!(s.state, State::Stopped(_))
+                && s.recipe
+                    .as_ref()
+                    .is_some_and(|p| p.state == RunState::Paused)
+        })
+        .await;
+        assert!(paused.position < 2000, "{:?}", paused);
+        assert_eq!(paused.mode, Mode::Recipe { paused: true });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(gm.snapshot().position, paused.position);
+
+        // the move runs again from where the gate stopped
+        gm.sender.send(Command::ResumeRecipe).await.unwrap();
+        until(&gm, |s| matches!(s.state, State::Moving(2000))).await;
+        assert!(at(100)(&until(&gm, ended(RunState::Finished)).await));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_manual_command_ends_the_recipe() {
+        let gm = start(&[]);
+        let steps = recipe(
+            r#"steps = [
+                { type = "move", target = 2000, unit = "counts" },
+                { type = "dwell", seconds = 60 },
+            ]"#,
+        );
+        gm.sender.send(Command::RunRecipe(steps)).await.unwrap();
+        until(&gm, |s| s.position > 500).await;
+        gm.sender.send(Command::Close).await.unwrap();
+        let snapshot = until(&gm, ended(RunState::Aborted)).await;
+        assert_eq!(snapshot.recipe.unwrap().step, 0);
+        assert!(at(0)(&until(&gm, at(0)).await));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_wait_that_times_out_aborts_the_recipe() {
+        let gm = start(&[]);
+        let steps = recipe(
+            r#"steps = [
+                { type = "wait", until = { level_above = 1.0 }, timeout = 0.5 },
+                { type = "move", target = 2000, unit = "counts" },
+            ]"#,
+        );
+        gm.sender.send(Command::RunRecipe(steps)).await.unwrap();
+        let snapshot = until(&gm, ended(RunState::Aborted)).await;
+        assert_eq!(snapshot.recipe.unwrap().step, 0);
+        assert_eq!(snapshot.position, 0);
+    }
+}
@@ -0,0 +1,53 @@
+//! Gates driven by the daemon, by name.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+
+use crate::gate::GatemanRef;
+
+/// Every gate of the daemon, shared by the network interface and the schedule
+#[derive(Clone, Default)]
+pub struct Registry {
+    gates: Arc<BTreeMap<String, GatemanRef>>,
+}
+
+impl Registry {
+    pub fn new(gates: BTreeMap<String, GatemanRef>) -> Self {
+        Self {
+            gates: Arc::new(gates),
+        }
+    }
+
+    /// A reference to the gates that does not keep them running, for the gates to hold
+    pub fn downgrade(&self) -> WeakRegistry {
+        WeakRegistry(Arc::downgrade(&self.gates))
+    }
+
+    pub fn get(&self, name: &str) -> Option<GatemanRef> {
+        self.gates.get(name).cloned()
+    }
+
+    /// The gates in order of name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &GatemanRef)> {
+        self.gates.iter().map(|(name, gm)| (name.as_str(), gm))
+    }
+
+    /// The name of the gate when there is only one
+    pub fn only(&self) -> Option<&str> {
+        match self.gates.len() {
+            1 => self.gates.keys().next().map(String::as_str),
+            _ => None,
+        }
+    }
+}
+
+/// The gates of the daemon for as long as they run
+#[derive(Clone, Default)]
+pub struct WeakRegistry(Weak<BTreeMap<String, GatemanRef>>);
+
+impl WeakRegistry {
+    /// The gates, None once they have stopped
+    pub fn upgrade(&self) -> Option<Registry> {
+        self.0.upgrade().map(|gates| Registry { gates })
+    }
+}
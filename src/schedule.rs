@@ -0,0 +1,761 @@
+//! Gate commands run at set times, from cron-like rules and one-shot entries.
+//!
+//! The schedule is saved whenever it changes, along with the time it was last
+//! checked, so entries that fell due while the daemon was down can be caught up
+//! on at startup according to the catch-up policy. Times are local.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::Opening;
+use crate::gate::Command;
+use crate::journal;
+use crate::protocol::refusal;
+use crate::registry::Registry;
+use crate::Error::ScheduleError;
+use crate::Result;
+
+/// How often the schedule is checked
+const TICK: Duration = Duration::from_secs(1);
+
+/// How often the time the schedule was last checked is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Years searched for the next time a rule fires, covering leap days
+const SEARCH_YEARS: i64 = 8;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScheduleConfig {
+    /// File the schedule is saved to, kept in memory only when not set
+    pub path: Option<PathBuf>,
+    /// What to do with entries that fell due while the daemon was down
+    pub catch_up: CatchUp,
+    /// Minutes after which a missed entry is no longer caught up on
+    pub max_late: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            catch_up: CatchUp::Latest,
+            max_late: 720,
+        }
+    }
+}
+
+/// Handling of entries missed while the daemon was down
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CatchUp {
+    /// Drop missed entries
+    Skip,
+    /// Run the last missed entry, leaving the gate where the schedule would have
+    Latest,
+    /// Run every missed entry in order
+    All,
+}
+
+/// A scheduled command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// Gate the entry runs on, which may be left out when there is only one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gate: Option<String>,
+    #[serde(flatten)]
+    pub when: When,
+    pub action: Action,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum When {
+    /// Minute, hour, day of month, month and day of week
+    Cron(Cron),
+    /// Once, at a date and time
+    At(NaiveDateTime),
+}
+
+impl When {
+    /// The first time after `after` the entry is due, None once it never will be
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            When::Cron(cron) => cron.next_after(after),
+            When::At(at) => Some(*at).filter(|at| *at > after),
+        }
+    }
+}
+
+/// What a scheduled entry does to the gate
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Open(Opening),
+    Close,
+    Stop,
+    SetFlow { rate: f64 },
+    Deliver { volume: f64 },
+    HoldLevel { setpoint: f64 },
+}
+
+impl Action {
+    /// The command run for the action, which holds the gate where it is left
+    /// with no client needed to keep it alive
+    pub fn command(&self) -> Command {
+        match *self {
+            Action::Open(opening) => Command::Hold(Some(opening)),
+            Action::Close => Command::Hold(Some(Opening::Counts(0))),
+            Action::Stop => Command::Hold(None),
+            Action::SetFlow { rate } => Command::SetFlow(rate),
+            Action::Deliver { volume } => Command::Deliver(volume),
+            Action::HoldLevel { setpoint } => Command::HoldLevel(setpoint),
+        }
+    }
+}
+
+/// Cron rule, with the usual `*`, lists, ranges and steps in each field
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cron {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // a restricted day of month and day of week match either, not both
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = bit(&self.days, date.day());
+        let weekday = bit(&self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first minute after `after` the rule fires
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let limit = t + chrono::Duration::days(366 * SEARCH_YEARS);
+        while t < limit {
+            let date = t.date();
+            if !bit(&self.months, date.month()) {
+                let (y, m) = match date.month() {
+                    12 => (date.year() + 1, 1),
+                    m => (date.year(), m + 1),
+                };
+                t = NaiveDate::from_ymd_opt(y, m, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                t = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !bit(&self.hours, t.hour()) {
+                t = t.with_minute(0)? + chrono::Duration::hours(1);
+            } else if !bit(&self.minutes, t.minute()) {
+                t += chrono::Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+fn bit(set: &u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+// the values of one field as a set of bits, with whether it was `*`
+fn field(text: &str, name: &str, min: u32, max: u32) -> std::result::Result<(u64, bool), String> {
+    let mut set = 0u64;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let number = |s: &str| {
+            s.parse::<u32>()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| format!("{} `{}` is not a number from {} to {}", name, s, min, max))
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        let step = match step {
+            Some(s) => s
+                .parse::<u32>()
+                .ok()
+                .filter(|s| *s > 0)
+                .ok_or_else(|| format!("{} step `{}` is not a positive number", name, s))?,
+            None => 1,
+        };
+        if from > to {
+            return Err(format!("{} range `{}` is backwards", name, range));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok((set, text == "*"))
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "cron rule `{}` needs minute, hour, day, month and weekday",
+                s
+            ));
+        };
+        let (mut weekdays, any_weekday) = field(weekday, "weekday", 0, 7)?;
+        // sunday is both 0 and 7
+        if bit(&weekdays, 7) {
+            weekdays |= 1;
+        }
+        let (days, any_day) = field(day, "day", 1, 31)?;
+        let cron = Cron {
+            source: fields.join(" "),
+            minutes: field(minute, "minute", 0, 59)?.0,
+            hours: field(hour, "hour", 0, 23)?.0,
+            days,
+            months: field(month, "month", 1, 12)?.0,
+            weekdays,
+            any_day,
+            any_weekday,
+        };
+        if cron.next_after(Local::now().naive_local()).is_none() {
+            return Err(format!("cron rule `{}` never fires", s));
+        }
+        Ok(cron)
+    }
+}
+
+impl Display for Cron {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl TryFrom<String> for Cron {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<Cron> for String {
+    fn from(cron: Cron) -> Self {
+        cron.source
+    }
+}
+
+/// What is saved to disk
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    entries: BTreeMap<u64, Entry>,
+    next_id: u64,
+    /// When the schedule was last checked, entries due after it have not run
+    checked: Option<NaiveDateTime>,
+}
+
+/// The schedule, shared between the API and the task running it
+#[derive(Clone)]
+pub struct Scheduler {
+    saved: Arc<Mutex<Saved>>,
+    config: ScheduleConfig,
+}
+
+impl Scheduler {
+    /// The schedule saved at the configured path, empty if there is none
+    pub fn load(config: &ScheduleConfig) -> Result<Self> {
+        let saved = match &config.path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text)
+                    .map_err(|e| ScheduleError(format!("{}: {}", path.display(), e)))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Saved::default(),
+                Err(e) => return Err(ScheduleError(format!("{}: {}", path.display(), e))),
+            },
+            None => Saved::default(),
+        };
+        if !saved.entries.is_empty() {
+            eprintln!("loaded {} schedule entries", saved.entries.len());
+        }
+        Ok(Self {
+            saved: Arc::new(Mutex::new(saved)),
+            config: config.clone(),
+        })
+    }
+
+    pub fn entries(&self) -> Vec<(u64, Entry)> {
+        let saved = self.lock();
+        saved
+            .entries
+            .iter()
+            .map(|(id, e)| (*id, e.clone()))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<Entry> {
+        self.lock().entries.get(&id).cloned()
+    }
+
+    /// Add an entry, returning its id
+    pub fn insert(&self, entry: Entry) -> Result<u64> {
+        let mut saved = self.lock();
+        let id = saved.next_id;
+        saved.next_id += 1;
+        saved.entries.insert(id, entry);
+        self.save(&saved)?;
+        Ok(id)
+    }
+
+    /// Replace an entry, false if there is none with the id
+    pub fn replace(&self, id: u64, entry: Entry) -> Result<bool> {
+        let mut saved = self.lock();
+        match saved.entries.get_mut(&id) {
+            Some(e) => *e = entry,
+            None => return Ok(false),
+        }
+        self.save(&saved)?;
+        Ok(true)
+    }
+
+    /// Remove an entry, false if there is none with the id
+    pub fn remove(&self, id: u64) -> Result<bool> {
+        let mut saved = self.lock();
+        if saved.entries.remove(&id).is_none() {
+            return Ok(false);
+        }
+        self.save(&saved)?;
+        Ok(true)
+    }
+
+    /// Run the schedule for as long as the gates are up, first catching up on
+    /// entries missed while it was down
+    pub async fn run(self, gates: Registry) {
+        let now = Local::now().naive_local();
+        for (id, entry) in self.missed(now, &gates) {
+            eprintln!("catching up on schedule entry {}", id);
+            run(&gates, &entry).await;
+        }
+
+        let mut interval = tokio::time::interval(TICK);
+        let mut saved_at = tokio::time::Instant::now();
+        while gates.iter().any(|(_, gm)| !gm.sender.is_closed()) {
+            interval.tick().await;
+            let now = Local::now().naive_local();
+            let due = self.due(now);
+            for (id, entry) in &due {
+                eprintln!("schedule entry {} due", id);
+                run(&gates, entry).await;
+            }
+            if !due.is_empty() || saved_at.elapsed() >= SAVE_INTERVAL {
+                saved_at = tokio::time::Instant::now();
+                if let Err(e) = self.save(&self.lock()) {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+    }
+
+    // entries due between the last check and now, marking the schedule checked
+    fn due(&self, now: NaiveDateTime) -> Vec<(u64, Entry)> {
+        let mut saved = self.lock();
+        let checked = saved.checked.filter(|c| *c <= now).unwrap_or(now);
+        let due = saved
+            .entries
+            .iter()
+            .filter(|(_, e)| e.enabled && e.when.next_after(checked).is_some_and(|t| t <= now))
+            .map(|(id, e)| (*id, e.clone()))
+            .collect();
+        saved.checked = Some(now);
+        expire(&mut saved, now);
+        due
+    }
+
+    // entries that fell due while the daemon was down, by the catch-up policy
+    fn missed(&self, now: NaiveDateTime, gates: &Registry) -> Vec<(u64, Entry)> {
+        let mut saved = self.lock();
+        let checked = match saved.checked {
+            Some(checked) if checked < now => checked,
+            _ => return vec![],
+        };
+        let earliest = now - chrono::Duration::minutes(self.config.max_late as i64);
+        let mut missed = vec![];
+        for (id, entry) in saved.entries.iter().filter(|(_, e)| e.enabled) {
+            let mut after = checked;
+            while let Some(t) = entry.when.next_after(after).filter(|t| *t <= now) {
+                if t >= earliest {
+                    missed.push((t, *id, entry.clone()));
+                }
+                after = t;
+            }
+        }
+        missed.sort_by_key(|(t, id, _)| (*t, *id));
+        if !missed.is_empty() {
+            eprintln!(
+                "{} schedule entries missed since {}, catch up {:?}",
+                missed.len(),
+                checked,
+                self.config.catch_up
+            );
+        }
+        let missed = match self.config.catch_up {
+            CatchUp::Skip => vec![],
+            CatchUp::Latest => {
+                // the last missed entry of each gate
+                let mut latest = BTreeMap::new();
+                for (t, id, entry) in missed {
+                    latest.insert(gate(gates, &entry).map(str::to_string), (t, id, entry));
+                }
+                let mut latest: Vec<_> = latest.into_values().collect();
+                latest.sort_by_key(|(t, id, _)| (*t, *id));
+                latest
+            }
+            CatchUp::All => missed,
+        };
+
+        saved.checked = Some(now);
+        expire(&mut saved, now);
+        if let Err(e) = self.save(&saved) {
+            eprintln!("{}", e);
+        }
+        missed
+            .into_iter()
+            .map(|(_, id, entry)| (id, entry))
+            .collect()
+    }
+
+    fn save(&self, saved: &Saved) -> Result<()> {
+        let path = match &self.config.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let error = |e: &dyn Display| ScheduleError(format!("{}: {}", path.display(), e));
+        let json = serde_json::to_vec(saved).map_err(|e| error(&e))?;
+        journal::replace(path, &json).map_err(|e| error(&e))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Saved> {
+        self.saved.lock().expect("schedule lock poisoned")
+    }
+}
+
+// drop enabled one-shot entries that have passed
+fn expire(saved: &mut Saved, now: NaiveDateTime) {
+    saved
+        .entries
+        .retain(|_, e| !(e.enabled && matches!(e.when, When::At(at) if at <= now)));
+}
+
+/// The name of the gate an entry runs on, None when there is no such gate
+pub fn gate<'a>(gates: &'a Registry, entry: &'a Entry) -> Option<&'a str> {
+    let name = entry.gate.as_deref().or_else(|| gates.only())?;
+    gates.iter().map(|(name, _)| name).find(|n| *n == name)
+}
+
+// send the command of an entry, unless the gate would refuse it
+async fn run(gates: &Registry, entry: &Entry) {
+    let gm = match gate(gates, entry).and_then(|name| gates.get(name)) {
+        Some(gm) => gm,
+        None => {
+            eprintln!("scheduled {:?} has no gate", entry.action);
+            return;
+        }
+    };
+    let cmd = entry.action.command();
+    if let Some((_, message)) = refusal(&cmd, &gm) {
+        eprintln!("scheduled {:?} refused: {}", entry.action, message);
+        return;
+    }
+    let _ = gm.sender.send(cmd).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 is a monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .and_then(|d| d.and_hms_opt(hour, minute, 0))
+            .unwrap()
+    }
+
+    fn cron(rule: &str) -> Cron {
+        rule.parse().unwrap()
+    }
+
+    // the first few times a rule fires after a time
+    fn fires(rule: &str, after: NaiveDateTime, n: usize) -> Vec<NaiveDateTime> {
+        let cron = cron(rule);
+        std::iter::successors(cron.next_after(after), |t| cron.next_after(*t))
+            .take(n)
+            .collect()
+    }
+
+    fn entry(when: When) -> Entry {
+        Entry {
+            gate: None,
+            when,
+            action: Action::Close,
+            enabled: true,
+        }
+    }
+
+    fn scheduler(path: Option<PathBuf>, catch_up: CatchUp, max_late: u64) -> Scheduler {
+        let config = ScheduleConfig {
+            path,
+            catch_up,
+            max_late,
+        };
+        Scheduler::load(&config).unwrap()
+    }
+
+    #[test]
+    fn fires_on_the_next_minute_matching_every_field() {
+        assert_eq!(
+            fires("* * * * *", at(1, 8, 0), 2),
+            [at(1, 8, 1), at(1, 8, 2)]
+        );
+        assert_eq!(
+            fires("30 6 * * *", at(1, 6, 30), 2),
+            [at(2, 6, 30), at(3, 6, 30)]
+        );
+        // seconds are dropped, so a rule never fires twice in a minute
+        let after = at(1, 6, 29) + chrono::Duration::seconds(59);
+        assert_eq!(cron("30 6 * * *").next_after(after), Some(at(1, 6, 30)));
+    }
+
+    #[test]
+    fn ranges_steps_and_lists() {
+        assert_eq!(
+            fires("*/20 8-9 * * *", at(1, 7, 0), 7),
+            [
+                at(1, 8, 0),
+                at(1, 8, 20),
+                at(1, 8, 40),
+                at(1, 9, 0),
+                at(1, 9, 20),
+                at(1, 9, 40),
+                at(2, 8, 0)
+            ]
+        );
+        assert_eq!(
+            fires("5-20/5 12 * * *", at(1, 0, 0), 5),
+            [
+                at(1, 12, 5),
+                at(1, 12, 10),
+                at(1, 12, 15),
+                at(1, 12, 20),
+                at(2, 12, 5)
+            ]
+        );
+        // a start with a step runs to the end of the field
+        assert_eq!(
+            fires("10/20 0 * * *", at(1, 0, 0), 3),
+            [at(1, 0, 10), at(1, 0, 30), at(1, 0, 50)]
+        );
+        assert_eq!(
+            fires("0 6,18 * * *", at(1, 7, 0), 3),
+            [at(1, 18, 0), at(2, 6, 0), at(2, 18, 0)]
+        );
+        assert_eq!(
+            fires("0,15,40-45/5 0 * * *", at(1, 0, 0), 4),
+            [at(1, 0, 15), at(1, 0, 40), at(1, 0, 45), at(2, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // weekdays only, skipping the weekend
+        assert_eq!(
+            fires("0 7 * * 1-5", at(5, 8, 0), 2),
+            [at(8, 7, 0), at(9, 7, 0)]
+        );
+        // sunday is both 0 and 7
+        assert_eq!(fires("0 7 * * 0", at(1, 0, 0), 1), [at(7, 7, 0)]);
+        assert_eq!(fires("0 7 * * 7", at(1, 0, 0), 1), [at(7, 7, 0)]);
+        // a day of month alone
+        assert_eq!(fires("0 0 15 * *", at(1, 0, 0), 2)[1].month(), 2);
+        // both restricted match either, the fridays and the 13th
+        assert_eq!(
+            fires("0 0 13 * 5", at(1, 0, 0), 4),
+            [at(5, 0, 0), at(12, 0, 0), at(13, 0, 0), at(19, 0, 0)]
+        );
+        // one restricted must match alongside the other `*`
+        assert_eq!(
+            fires("0 0 1-31/2 * *", at(1, 0, 0), 2),
+            [at(3, 0, 0), at(5, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn months_and_leap_days() {
+        let june = NaiveDate::from_ymd_opt(2024, 6, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0);
+        assert_eq!(cron("0 0 1 6 *").next_after(at(1, 0, 0)), june);
+        let leap = NaiveDate::from_ymd_opt(2028, 2, 29)
+            .unwrap()
+            .and_hms_opt(0, 0, 0);
+        let march = NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0);
+        assert_eq!(cron("0 0 29 2 *").next_after(march.unwrap()), leap);
+        // the 31st skips the shorter months
+        assert_eq!(fires("0 0 31 * *", at(1, 0, 0), 2)[1].month(), 3);
+    }
+
+    #[test]
+    fn invalid_fields_are_refused() {
+        for rule in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * 32 * *",
+            "* * * 0 *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "*/x * * * *",
+            "20-10 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+            "-1 * * * *",
+        ] {
+            assert!(rule.parse::<Cron>().is_err(), "`{}` parsed", rule);
+        }
+        let error = "* 24 * * *".parse::<Cron>().unwrap_err();
+        assert_eq!(error, "hour `24` is not a number from 0 to 23");
+    }
+
+    #[test]
+    fn rules_that_never_fire_are_refused() {
+        for rule in ["0 0 30 2 *", "0 0 31 4 *", "0 0 31 2,4,6,9,11 *"] {
+            let error = rule.parse::<Cron>().unwrap_err();
+            assert!(error.ends_with("never fires"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn keeps_the_rule_as_written() {
+        assert_eq!(
+            cron("  */5   8-17 *  * 1-5 ").to_string(),
+            "*/5 8-17 * * 1-5"
+        );
+        let json = serde_json::to_string(&cron("0 6 * * *")).unwrap();
+        assert_eq!(json, r#""0 6 * * *""#);
+    }
+
+    #[test]
+    fn one_shot_entries_fire_once() {
+        let when = When::At(at(2, 12, 0));
+        assert_eq!(when.next_after(at(1, 0, 0)), Some(at(2, 12, 0)));
+        assert_eq!(when.next_after(at(2, 12, 0)), None);
+    }
+
+    #[test]
+    fn due_entries_run_once_per_check() {
+        let scheduler = scheduler(None, CatchUp::Latest, 720);
+        scheduler
+            .insert(entry(When::Cron(cron("0 * * * *"))))
+            .unwrap();
+        assert!(scheduler.due(at(1, 7, 59)).is_empty());
+        assert_eq!(scheduler.due(at(1, 8, 0)).len(), 1);
+        assert!(scheduler.due(at(1, 8, 0)).is_empty());
+        assert!(scheduler.due(at(1, 8, 30)).is_empty());
+    }
+
+    #[test]
+    fn catch_up_by_policy() {
+        let gates = Registry::default();
+        let missed = |catch_up, max_late| {
+            let scheduler = scheduler(None, catch_up, max_late);
+            scheduler
+                .insert(entry(When::Cron(cron("0 * * * *"))))
+                .unwrap();
+            scheduler
+                .insert(entry(When::Cron(cron("30 8 * * *"))))
+                .unwrap();
+            scheduler.lock().checked = Some(at(1, 6, 30));
+            let missed = scheduler.missed(at(1, 9, 45), &gates);
+            missed.into_iter().map(|(id, _)| id).collect::<Vec<_>>()
+        };
+        assert_eq!(missed(CatchUp::All, 720), [0, 0, 1, 0]);
+        assert_eq!(missed(CatchUp::Latest, 720), [0]);
+        assert!(missed(CatchUp::Skip, 720).is_empty());
+        // entries later than max_late are dropped
+        assert_eq!(missed(CatchUp::All, 90), [1, 0]);
+        assert!(missed(CatchUp::All, 30).is_empty());
+    }
+
+    #[test]
+    fn nothing_is_missed_on_a_first_start() {
+        let scheduler = scheduler(None, CatchUp::All, 720);
+        scheduler
+            .insert(entry(When::Cron(cron("* * * * *"))))
+            .unwrap();
+        assert!(scheduler
+            .missed(at(1, 9, 45), &Registry::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn catches_up_after_a_restart() {
+        let path = std::env::temp_dir().join(format!("gateman-schedule-{}", std::process::id()));
+        let before = scheduler(Some(path.clone()), CatchUp::All, 720);
+        before.insert(entry(When::Cron(cron("0 * * * *")))).unwrap();
+        before.insert(entry(When::At(at(1, 9, 0)))).unwrap();
+        before.insert(entry(When::At(at(2, 9, 0)))).unwrap();
+        let mut disabled = entry(When::Cron(cron("* * * * *")));
+        disabled.enabled = false;
+        before.insert(disabled).unwrap();
+        before.due(at(1, 7, 30));
+        before.save(&before.lock()).unwrap();
+        drop(before);
+
+        let after = scheduler(Some(path.clone()), CatchUp::All, 720);
+        let missed = after.missed(at(1, 9, 30), &Registry::default());
+        let ids: Vec<_> = missed.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [0, 0, 1]);
+        // the passed one-shot entry is gone, the one still to come is kept
+        let kept: Vec<_> = after.entries().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(kept, [0, 2, 3]);
+        drop(after);
+
+        // caught up entries do not run again on the next start
+        let again = scheduler(Some(path.clone()), CatchUp::All, 720);
+        assert!(again.missed(at(1, 9, 30), &Registry::default()).is_empty());
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! Network interface to the gates.
+
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+use warp::reply::{json, with_status};
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::DEFAULT_GATE;
+use crate::gate::GatemanRef;
+use crate::protocol::{GateReply, Reply as GateStatus};
+use crate::registry::Registry;
+use crate::schedule::Scheduler;
+use crate::status::Status;
+
+mod rest;
+mod schedule;
+mod ws;
+
+/// Routes for each gate under `/gates/<name>`, and under `/gate` for the default gate,
+/// with a listing of the gates and a combined status stream at `/gates`, and the schedule
+pub fn routes(
+    registry: Registry,
+    scheduler: Scheduler,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let gates = warp::any().map(move || registry.clone());
+    let schedule = warp::any().map(move || scheduler.clone());
+
+    let gate: BoxedFilter<(GatemanRef,)> = warp::path("gates")
+        .and(warp::path::param())
+        .or(warp::path(DEFAULT_GATE).map(|| DEFAULT_GATE.to_string()))
+        .unify()
+        .and(gates.clone())
+        .and_then(|name: String, gates: Registry| async move {
+            gates
+                .get(&name)
+                .ok_or_else(|| warp::reject::custom(UnknownGate))
+        })
+        .boxed();
+
+    let websocket = gate.clone().and(warp::path::end()).and(warp::ws()).map(
+        |gm: GatemanRef, ws: warp::ws::Ws| {
+            let timeout = gm.client_timeout();
+            ws.on_upgrade(move |websocket| ws::router(websocket, gm, timeout))
+        },
+    );
+
+    let combined =
+        warp::path!("gates")
+            .and(warp::ws())
+            .and(gates.clone())
+            .map(|ws: warp::ws::Ws, gates| {
+                ws.on_upgrade(move |websocket| ws::combined(websocket, gates))
+            });
+
+    let list = warp::get()
+        .and(warp::path!("gates"))
+        .and(gates.clone())
+        .map(|gates: Registry| {
+            let statuses: Vec<_> = gates
+                .iter()
+                .map(|(name, gm)| GateReply {
+                    gate: name,
+                    reply: GateStatus::status(&Status::Snapshot(gm.snapshot()), gm),
+                })
+                .collect();
+            json(&statuses)
+        });
+
+    websocket
+        .or(combined)
+        .or(list)
+        .or(rest::routes(gate))
+        .or(schedule::routes(schedule, gates))
+        .recover(unknown_gate)
+}
+
+/// A path naming a gate the daemon does not drive
+#[derive(Debug)]
+struct UnknownGate;
+
+impl Reject for UnknownGate {}
+
+// answer not found for a gate that does not exist, which warp would otherwise
+// report as a method another route does not allow
+async fn unknown_gate(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<UnknownGate>() {
+        Some(_) => Ok(with_status("no such gate", StatusCode::NOT_FOUND)),
+        None => Err(rejection),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use serde_json::Value;
+    use warp::test::request;
+
+    use super::*;
+    use crate::gate::State;
+    use crate::schedule::ScheduleConfig;
+    use crate::testing;
+
+    // closed simulated gates of 4000 counts under the given names
+    fn gates(names: &[&str]) -> Registry {
+        let gates = names.iter().map(|name| {
+            let (gm, _gate) = testing::start(0, &testing::config(), &BTreeMap::new());
+            (name.to_string(), gm)
+        });
+        Registry::new(gates.collect())
+    }
+
+    fn server(
+        registry: &Registry,
+    ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone + 'static {
+        let scheduler = Scheduler::load(&ScheduleConfig::default()).unwrap();
+        routes(registry.clone(), scheduler)
+    }
+
+    // open a gate part way through its path, returning the response status
+    async fn open(registry: &Registry, path: &str) -> StatusCode {
+        request()
+            .method("POST")
+            .path(&format!("{}/open", path))
+            .json(&serde_json::json!({ "target": 1000, "unit": "counts" }))
+            .reply(&server(registry))
+            .await
+            .status()
+    }
+
+    // the positions of the gates once they have stopped moving
+    async fn settled(registry: &Registry) -> Vec<(String, isize)> {
+        for _ in 0..1000 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let snapshots: Vec<_> = registry
+                .iter()
+                .map(|(name, gm)| (name.to_string(), gm.snapshot()))
+                .collect();
+            if snapshots
+                .iter()
+                .all(|(_, s)| matches!(s.state, State::Stopped(_)))
+            {
+                return snapshots
+                    .into_iter()
+                    .map(|(name, s)| (name, (s.position + 5) / 10 * 10))
+                    .collect();
+            }
+        }
+        panic!("gates still moving");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commands_go_to_the_gate_they_name() {
+        let registry = gates(&["sluice", "spill"]);
+        assert_eq!(open(&registry, "/gates/spill").await, StatusCode::ACCEPTED);
+        assert_eq!(
+            settled(&registry).await,
+            [("sluice".to_string(), 0), ("spill".to_string(), 1000)]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn the_default_gate_is_also_under_gate() {
+        let registry = gates(&[DEFAULT_GATE, "spill"]);
+        assert_eq!(open(&registry, "/gate").await, StatusCode::ACCEPTED);
+        assert_eq!(
+            settled(&registry).await,
+            [(DEFAULT_GATE.to_string(), 1000), ("spill".to_string(), 0)]
+        );
+
+        // without a gate of that name, /gate names nothing
+        let registry = gates(&["sluice", "spill"]);
+        assert_eq!(open(&registry, "/gate").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_gates_are_not_found() {
+        let registry = gates(&["sluice", "spill"]);
+        assert_eq!(open(&registry, "/gates/weir").await, StatusCode::NOT_FOUND);
+        let status = request()
+            .path("/gates/weir")
+            .reply(&server(&registry))
+            .await;
+        assert_eq!(status.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn gates_are_listed_by_name() {
+        let registry = gates(&["spill", "sluice"]);
+        let list = request().path("/gates").reply(&server(&registry)).await;
+        assert_eq!(list.status(), StatusCode::OK);
+        let list: Vec<Value> = serde_json::from_slice(list.body()).unwrap();
+        let names: Vec<_> = list.iter().map(|gate| &gate["gate"]).collect();
+        assert_eq!(names, ["sluice", "spill"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn only_the_last_client_leaving_fails_safe() {
+        let mut config = testing::config();
+        config.safety.keepalive_timeout = 60;
+        let (gm, _gate) = testing::start(1000, &config, &BTreeMap::new());
+        let registry = Registry::new(BTreeMap::from([(DEFAULT_GATE.to_string(), gm.clone())]));
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let mut client = warp::test::ws()
+                .path("/gate")
+                .handshake(server(&registry))
+                .await
+                .unwrap();
+            client.send_text("stop").await;
+            assert_eq!(client.recv().await.unwrap().to_str(), Ok("stopping"));
+            clients.push(client);
+        }
+
+        drop(clients.pop());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let snapshot = gm.snapshot();
+        assert!(
+            matches!(snapshot.state, State::Stopped(1000)),
+            "{:?}",
+            snapshot
+        );
+
+        drop(clients.pop());
+        testing::until(&gm, |s| matches!(s.state, State::Stopped(0))).await;
+    }
+
+    #[tokio::test]
+    async fn legacy_commands_are_refused_with_a_reason() {
+        let mut config = testing::config();
+        config.limits.max = Some(3000);
+        let (gm, _gate) = testing::start(0, &config, &BTreeMap::new());
+        let registry = Registry::new(BTreeMap::from([(DEFAULT_GATE.to_string(), gm)]));
+        let mut client = warp::test::ws()
+            .path("/gate")
+            .handshake(server(&registry))
+            .await
+            .unwrap();
+        // 100 units of 35 counts is beyond the soft limit
+        client.send_text("100").await;
+        let reply = client.recv().await.unwrap();
+        assert_eq!(
+            reply.to_str(),
+            Ok("error:target 3500 is outside the soft limits")
+        );
+        // while one within it is taken, its reply racing the status of the move
+        client.send_text("50").await;
+        loop {
+            let message = client.recv().await.unwrap();
+            match message.to_str().unwrap() {
+                "moving:50" => break,
+                status => assert!(status == "begin" || status.parse::<isize>().is_ok()),
+            }
+        }
+    }
+}
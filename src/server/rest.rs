@@ -0,0 +1,163 @@
+//! HTTP interface for scripts and other clients that do not hold a connection.
+
+use serde::Deserialize;
+use tokio::sync::mpsc::error::TrySendError;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::reply::{json, with_status, WithStatus};
+use warp::{Filter, Rejection, Reply as WarpReply};
+
+use crate::gate::{Command, GatemanRef};
+use crate::protocol::{refusal, ErrorCode, Reply};
+use crate::status::Status;
+
+/// Routes under the path of a gate, which `gate` takes up
+pub fn routes(
+    gate: BoxedFilter<(GatemanRef,)>,
+) -> impl Filter<Extract = impl WarpReply, Error = Rejection> + Clone {
+    let status = warp::get()
+        .and(gate.clone())
+        .and(warp::path::end())
+        .map(|gm: GatemanRef| json(&Reply::status(&Status::Snapshot(gm.snapshot()), &gm)));
+
+    let open = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("open"))
+        .and(warp::body::json())
+        .map(|gm, opening| dispatch(gm, Command::Open(opening)));
+
+    let close = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("close"))
+        .map(|gm| dispatch(gm, Command::Close));
+
+    let stop = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("stop"))
+        .map(|gm| dispatch(gm, Command::Stop));
+
+    let home = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("home"))
+        .map(|gm| dispatch(gm, Command::Home));
+
+    let calibrate = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("calibrate"))
+        .map(|gm| dispatch(gm, Command::Calibrate));
+
+    let reset = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("reset"))
+        .map(|gm| dispatch(gm, Command::ResetFault));
+
+    let flow = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("flow"))
+        .and(warp::body::json())
+        .map(|gm, body: FlowRate| dispatch(gm, Command::SetFlow(body.rate)));
+
+    let deliver = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("deliver"))
+        .and(warp::body::json())
+        .map(|gm, body: Volume| dispatch(gm, Command::Deliver(body.volume)));
+
+    let level = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("level"))
+        .and(warp::body::json())
+        .map(|gm, body: Level| dispatch(gm, Command::HoldLevel(body.setpoint)));
+
+    let recipe = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("recipe"))
+        .and(warp::body::json())
+        .map(|gm, recipe| dispatch(gm, Command::RunRecipe(recipe)));
+
+    let pause = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("recipe" / "pause"))
+        .map(|gm| dispatch(gm, Command::PauseRecipe));
+
+    let resume = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("recipe" / "resume"))
+        .map(|gm| dispatch(gm, Command::ResumeRecipe));
+
+    let abort = warp::post()
+        .and(gate.clone())
+        .and(warp::path!("recipe" / "abort"))
+        .map(|gm| dispatch(gm, Command::AbortRecipe));
+
+    // keeps the gate alive for clients that do not hold a websocket open
+    let ping = warp::post()
+        .and(gate)
+        .and(warp::path!("ping"))
+        .map(|gm| dispatch(gm, Command::Nop));
+
+    status
+        .or(open)
+        .or(close)
+        .or(stop)
+        .or(home)
+        .or(calibrate)
+        .or(reset)
+        .or(flow)
+        .or(deliver)
+        .or(level)
+        .or(recipe)
+        .or(pause)
+        .or(resume)
+        .or(abort)
+        .or(ping)
+}
+
+/// Body of a flow setpoint request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlowRate {
+    /// Litres per second
+    rate: f64,
+}
+
+/// Body of a delivery request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Volume {
+    /// Litres
+    volume: f64,
+}
+
+/// Body of a level setpoint request
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Level {
+    /// Metres
+    setpoint: f64,
+}
+
+// hand the command to the gate without waiting on a full command bus
+fn dispatch(gm: GatemanRef, cmd: Command) -> WithStatus<warp::reply::Json> {
+    if let Some((code, message)) = refusal(&cmd, &gm) {
+        let status = match code {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::OutOfRange => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::CONFLICT,
+        };
+        return with_status(json(&Reply::error(None, code, message)), status);
+    }
+    let (code, reply) = match gm.sender.try_send(cmd) {
+        Ok(_) => (StatusCode::ACCEPTED, Reply::Ack { id: None }),
+        Err(TrySendError::Full(_)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Reply::error(None, ErrorCode::Busy, "gate is busy"),
+        ),
+        Err(TrySendError::Closed(_)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Reply::error(None, ErrorCode::Unavailable, "gate unavailable"),
+        ),
+    };
+    with_status(json(&reply), code)
+}
@@ -0,0 +1,124 @@
+//! HTTP interface for adding, changing and removing schedule entries.
+
+use chrono::Local;
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reply::{json, with_status, Json, WithStatus};
+use warp::{Filter, Rejection, Reply as WarpReply};
+
+use crate::protocol::{ErrorCode, Reply};
+use crate::registry::Registry;
+use crate::schedule::{gate, Entry, Scheduler};
+use crate::Result;
+
+pub fn routes(
+    schedule: impl Filter<Extract = (Scheduler,), Error = std::convert::Infallible> + Clone + Send,
+    gates: impl Filter<Extract = (Registry,), Error = std::convert::Infallible> + Clone + Send,
+) -> impl Filter<Extract = impl WarpReply, Error = Rejection> + Clone {
+    let list = warp::get()
+        .and(warp::path!("schedule"))
+        .and(schedule.clone())
+        .map(|s: Scheduler| {
+            let entries: Vec<Listed> = s
+                .entries()
+                .into_iter()
+                .map(|(id, entry)| Listed { id, entry })
+                .collect();
+            json(&entries)
+        });
+
+    let add = warp::post()
+        .and(warp::path!("schedule"))
+        .and(warp::body::json())
+        .and(schedule.clone())
+        .and(gates.clone())
+        .map(|entry: Entry, s: Scheduler, gates| {
+            if let Some(reply) = rejected(&entry, &gates) {
+                return reply;
+            }
+            saved(s.insert(entry.clone()), |id| {
+                with_status(json(&Listed { id, entry }), StatusCode::CREATED)
+            })
+        });
+
+    let get = warp::get()
+        .and(warp::path!("schedule" / u64))
+        .and(schedule.clone())
+        .map(|id, s: Scheduler| match s.get(id) {
+            Some(entry) => with_status(json(&Listed { id, entry }), StatusCode::OK),
+            None => not_found(id),
+        });
+
+    let replace = warp::put()
+        .and(warp::path!("schedule" / u64))
+        .and(warp::body::json())
+        .and(schedule.clone())
+        .and(gates)
+        .map(|id, entry: Entry, s: Scheduler, gates| {
+            if let Some(reply) = rejected(&entry, &gates) {
+                return reply;
+            }
+            saved(s.replace(id, entry.clone()), |found| match found {
+                true => with_status(json(&Listed { id, entry }), StatusCode::OK),
+                false => not_found(id),
+            })
+        });
+
+    let remove = warp::delete()
+        .and(warp::path!("schedule" / u64))
+        .and(schedule)
+        .map(|id, s: Scheduler| {
+            saved(s.remove(id), |found| match found {
+                true => with_status(json(&Reply::Ack { id: None }), StatusCode::OK),
+                false => not_found(id),
+            })
+        });
+
+    list.or(add).or(get).or(replace).or(remove)
+}
+
+/// An entry with the id it is listed under
+#[derive(Serialize)]
+struct Listed {
+    id: u64,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+// refuse an entry for no gate, or enabled but never falling due
+fn rejected(entry: &Entry, gates: &Registry) -> Option<WithStatus<Json>> {
+    let now = Local::now().naive_local();
+    let message = if gate(gates, entry).is_none() {
+        match &entry.gate {
+            Some(name) => format!("no gate named {}", name),
+            None => "entry must name a gate".to_string(),
+        }
+    } else if entry.enabled && entry.when.next_after(now).is_none() {
+        "entry is never due".to_string()
+    } else {
+        return None;
+    };
+    let reply = Reply::error(None, ErrorCode::InvalidRequest, message);
+    Some(with_status(json(&reply), StatusCode::BAD_REQUEST))
+}
+
+fn not_found(id: u64) -> WithStatus<Json> {
+    let reply = Reply::error(
+        None,
+        ErrorCode::NotFound,
+        format!("no schedule entry {}", id),
+    );
+    with_status(json(&reply), StatusCode::NOT_FOUND)
+}
+
+// the reply once the schedule has been saved
+fn saved<T>(result: Result<T>, reply: impl FnOnce(T) -> WithStatus<Json>) -> WithStatus<Json> {
+    match result {
+        Ok(value) => reply(value),
+        Err(e) => {
+            eprintln!("{}", e);
+            let reply = Reply::error(None, ErrorCode::Unavailable, e.to_string());
+            with_status(json(&reply), StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
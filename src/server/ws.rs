@@ -0,0 +1,257 @@
+//! Websocket interface, speaking either the JSON or the legacy text protocol.
+
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use warp::filters::ws::{Message, WebSocket};
+
+use crate::calibration::Opening;
+use crate::gate::{Command, GatemanRef};
+use crate::protocol::{refusal, ErrorCode, GateReply, Reply, Request, RequestBody, VERSION};
+use crate::registry::Registry;
+use crate::status::Status;
+
+/// Protocol spoken on a websocket connection
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Protocol {
+    Legacy,
+    Json,
+}
+
+// handles the routing of messages to and from the websocket connection
+pub async fn router(websocket: WebSocket, gm: GatemanRef, timeout: Duration) {
+    let (mut ws_tx, mut from_client) = websocket.split();
+    let (to_client, rx) = mpsc::unbounded_channel();
+    let (protocol_tx, protocol) = watch::channel(Protocol::Legacy);
+
+    // every connection gets its own subscription to the gate status
+    let mut status = gm.subscribe();
+
+    gm.join();
+    eprintln!("connected");
+
+    let mut rx = UnboundedReceiverStream::new(rx);
+    let gate = gm.clone();
+    // todo;; there is a pipe function available
+    let h = tokio::task::spawn(async move {
+        loop {
+            let message = select! {
+                Some(message) = rx.next() => message,
+                Some(status) = status.recv() => match *protocol.borrow() {
+                    Protocol::Legacy => match status.legacy() {
+                        Some(message) => message,
+                        None => continue,
+                    },
+                    Protocol::Json => Reply::status(&status, &gate).to_json(),
+                },
+                else => break,
+            };
+            ws_tx
+                .send(Message::text(message))
+                .unwrap_or_else(|e| {
+                    eprintln!("websocket send error: {}", e);
+                })
+                .await;
+        }
+    });
+
+    // receive messages from the ws client and hand them off to the gateman,
+    // until the link to the client is lost for a reason or the gate goes away
+    let mut first = true;
+    let mut mode = Protocol::Legacy;
+    let lost = loop {
+        let result = match tokio::time::timeout(timeout, from_client.next()).await {
+            Ok(result) => result,
+            Err(_) => {
+                eprintln!("client timed out");
+                break Some("client timeout");
+            }
+        };
+        match result {
+            Some(Ok(msg)) if msg.is_text() => {
+                let t = msg.to_str().unwrap_or_default().trim();
+
+                // the first message negotiates the protocol
+                if first {
+                    first = false;
+                    if let Ok(Request {
+                        body: RequestBody::Hello { version },
+                        ..
+                    }) = serde_json::from_str(t)
+                    {
+                        if version == VERSION {
+                            mode = Protocol::Json;
+                            let _ = protocol_tx.send(mode);
+                            let _ = to_client.send(Reply::Hello { version }.to_json());
+                            let snapshot = Status::Snapshot(gm.snapshot());
+                            let _ = to_client.send(Reply::status(&snapshot, &gm).to_json());
+                        } else {
+                            let message = format!("protocol version {} is not supported", version);
+                            let reply = Reply::error(None, ErrorCode::UnsupportedVersion, message);
+                            let _ = to_client.send(reply.to_json());
+                        }
+                        continue;
+                    }
+                }
+
+                let handled = match mode {
+                    Protocol::Legacy => legacy(t, &gm, &to_client).await,
+                    Protocol::Json => json(t, &gm, &to_client).await,
+                };
+                if !handled {
+                    break None;
+                }
+            }
+            Some(Ok(msg)) if msg.is_close() => break Some("client disconnected"),
+            err => {
+                println!("--- unsupported message {:?} ---", err);
+                break Some("client disconnected");
+            }
+        };
+    };
+    // the failsafe waits on the last client, the keep-alive watches the others
+    match lost {
+        Some(reason) if gm.leave() => {
+            let _ = gm.sender.send(Command::Failsafe(reason)).await;
+        }
+        Some(reason) => eprintln!("{}, other clients remain connected", reason),
+        None => {
+            gm.leave();
+        }
+    }
+    h.abort();
+    eprintln!("shutting down")
+}
+
+// stream the status of every gate in the json protocol, each message naming
+// its gate, until the client goes away
+pub async fn combined(websocket: WebSocket, gates: Registry) {
+    let (mut ws_tx, mut from_client) = websocket.split();
+    let (to_client, mut rx) = mpsc::unbounded_channel();
+
+    let forwarders: Vec<_> = gates
+        .iter()
+        .map(|(name, gm)| {
+            let (name, gm, to_client) = (name.to_string(), gm.clone(), to_client.clone());
+            tokio::task::spawn(async move {
+                let mut status = gm.subscribe();
+                while let Some(status) = status.recv().await {
+                    let reply = GateReply {
+                        gate: &name,
+                        reply: Reply::status(&status, &gm),
+                    };
+                    if to_client.send(reply.to_json()).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(to_client);
+
+    let h = tokio::task::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if let Err(e) = ws_tx.send(Message::text(message)).await {
+                eprintln!("websocket send error: {}", e);
+                break;
+            }
+        }
+    });
+
+    // the stream only watches, so messages from the client are ignored
+    while let Some(Ok(msg)) = from_client.next().await {
+        if msg.is_close() {
+            break;
+        }
+    }
+    for forwarder in forwarders {
+        forwarder.abort();
+    }
+    h.abort();
+}
+
+// handle a message of the legacy text protocol, false if the gate is unavailable,
+// answering a command the gate would refuse with why in place of its reply
+async fn legacy(t: &str, gm: &GatemanRef, to_client: &mpsc::UnboundedSender<String>) -> bool {
+    let (cmd, reply) = match t {
+        "ping" => (Some(Command::Nop), None),
+        "stop" => {
+            println!("cmd: stop");
+            (Some(Command::Stop), Some("stopping".to_string()))
+        }
+        "home" => {
+            println!("cmd: home");
+            (Some(Command::Home), Some("homing".to_string()))
+        }
+        "calibrate" => {
+            println!("cmd: calibrate");
+            (Some(Command::Calibrate), Some("calibrating".to_string()))
+        }
+        "reset" => {
+            println!("cmd: reset");
+            (Some(Command::ResetFault), Some("resetting".to_string()))
+        }
+        "close" => {
+            println!("cmd: closing");
+            (None, Some("closing:0".to_string()))
+        }
+        v => match v.parse::<u8>() {
+            Ok(to) => {
+                println!("cmd: open to {}", to);
+                (
+                    Some(Command::Open(Opening::Units(to))),
+                    Some(format!("moving:{}", to)),
+                )
+            }
+            Err(e) => (None, Some(format!("error:{}: {}", v, e))),
+        },
+    };
+
+    if let Some(cmd) = cmd {
+        if let Some((_, message)) = refusal(&cmd, gm) {
+            let _ = to_client.send(format!("error:{}", message));
+            return true;
+        }
+        if gm.sender.send(cmd).await.is_err() {
+            let _ = to_client.send("error:gate unavailable".to_string());
+            return false;
+        }
+    }
+    if let Some(reply) = reply {
+        let _ = to_client.send(reply);
+    }
+    true
+}
+
+// handle a json request, false if the gate is unavailable
+async fn json(t: &str, gm: &GatemanRef, to_client: &mpsc::UnboundedSender<String>) -> bool {
+    let request: Request = match serde_json::from_str(t) {
+        Ok(r) => r,
+        Err(e) => {
+            let reply = Reply::error(None, ErrorCode::InvalidRequest, e.to_string());
+            let _ = to_client.send(reply.to_json());
+            return true;
+        }
+    };
+
+    let id = request.id;
+    let reply = match request.body.command() {
+        Some(cmd) => match refusal(&cmd, gm) {
+            Some((code, message)) => Reply::error(id, code, message),
+            None => match gm.sender.send(cmd).await {
+                Ok(_) => Reply::Ack { id },
+                Err(_) => {
+                    let reply = Reply::error(id, ErrorCode::Unavailable, "gate unavailable");
+                    let _ = to_client.send(reply.to_json());
+                    return false;
+                }
+            },
+        },
+        None => Reply::error(id, ErrorCode::InvalidRequest, "protocol already negotiated"),
+    };
+    let _ = to_client.send(reply.to_json());
+    true
+}
@@ -0,0 +1,161 @@
+//! Status bus shared by every client, logger and integration watching a gate.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use crate::config::Failsafe;
+use crate::delivery::Progress;
+use crate::gate::{Mode, State};
+use crate::recipe;
+
+/// Events buffered per subscriber before it starts lagging
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub enum Status {
+    /// The current state and position of the gate
+    Snapshot(Snapshot),
+    Begin,
+    Position(isize),
+    Done,
+    /// Litres per second through the flow meter
+    Flow(f64),
+    /// Metres of water upstream of the gate
+    Level(f64),
+    /// Metres of water downstream of the gate
+    Downstream(f64),
+    /// Litres delivered so far toward the volume of a delivery
+    Delivery(Progress),
+    /// Progress through the running or last recipe
+    Recipe(recipe::Progress),
+    /// The failsafe fired, taking the action for the reason given
+    Failsafe {
+        action: Failsafe,
+        reason: &'static str,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub state: State,
+    pub position: isize,
+    /// False when the position was restored from a move that did not finish
+    pub verified: bool,
+    pub mode: Mode,
+    /// Last flow rate in litres per second, None without a flow meter
+    pub flow: Option<f64>,
+    /// Progress of the running or last delivery
+    pub delivery: Option<Progress>,
+    /// Last upstream level in metres, None without a level sensor
+    pub level: Option<f64>,
+    /// Last downstream level in metres, None without a downstream sensor
+    pub downstream: Option<f64>,
+    /// Progress through the running or last recipe
+    pub recipe: Option<recipe::Progress>,
+}
+
+impl Status {
+    /// The message of the legacy text protocol, which only tells of moves
+    /// starting, their positions and their end, None for any other status
+    pub fn legacy(&self) -> Option<String> {
+        match self {
+            Status::Begin => Some("begin".to_string()),
+            Status::Position(p) => Some(p.to_string()),
+            Status::Done => Some("done".to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Fan-out of status events to any number of subscribers
+#[derive(Clone)]
+pub struct StatusBus {
+    events: broadcast::Sender<Status>,
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+impl StatusBus {
+    pub fn new(snapshot: Snapshot) -> Self {
+        let (events, _) = broadcast::channel(CAPACITY);
+        Self {
+            events,
+            snapshot: Arc::new(Mutex::new(snapshot)),
+        }
+    }
+
+    pub fn publish(&self, status: Status) {
+        let mut snapshot = self.snapshot.lock().expect("status lock poisoned");
+        match &status {
+            Status::Snapshot(s) => *snapshot = s.clone(),
+            Status::Position(p) => snapshot.position = *p,
+            Status::Flow(rate) => snapshot.flow = Some(*rate),
+            Status::Level(level) => snapshot.level = Some(*level),
+            Status::Downstream(level) => snapshot.downstream = Some(*level),
+            Status::Delivery(progress) => snapshot.delivery = Some(*progress),
+            Status::Recipe(progress) => snapshot.recipe = Some(progress.clone()),
+            _ => {}
+        }
+        // no subscribers is not an error
+        let _ = self.events.send(status);
+    }
+
+    /// Publish a snapshot with a new state at the last known position
+    pub fn set_state(&self, state: State) {
+        let mut snapshot = self.snapshot();
+        snapshot.state = state;
+        self.publish(Status::Snapshot(snapshot));
+    }
+
+    /// Publish a snapshot with a new mode
+    pub fn set_mode(&self, mode: Mode) {
+        let mut snapshot = self.snapshot();
+        snapshot.mode = mode;
+        self.publish(Status::Snapshot(snapshot));
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshot.lock().expect("status lock poisoned").clone()
+    }
+
+    /// Subscribe to status events, starting with a snapshot of the current state
+    pub fn subscribe(&self) -> Subscriber {
+        // hold the snapshot lock so no event is published between the two
+        let snapshot = self.snapshot.lock().expect("status lock poisoned");
+        let rx = self.events.subscribe();
+        Subscriber {
+            rx,
+            snapshot: self.snapshot.clone(),
+            next: Some(Status::Snapshot(snapshot.clone())),
+        }
+    }
+}
+
+pub struct Subscriber {
+    rx: broadcast::Receiver<Status>,
+    snapshot: Arc<Mutex<Snapshot>>,
+    next: Option<Status>,
+}
+
+impl Subscriber {
+    /// The next status event, or None once the gate has shut down.
+    /// A subscriber that falls behind receives a fresh snapshot in place of
+    /// the events it missed.
+    pub async fn recv(&mut self) -> Option<Status> {
+        if let Some(status) = self.next.take() {
+            return Some(status);
+        }
+        match self.rx.recv().await {
+            Ok(status) => Some(status),
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("status subscriber lagged by {}", n);
+                // skip the backlog, the snapshot supersedes it
+                while let Ok(_) | Err(TryRecvError::Lagged(_)) = self.rx.try_recv() {}
+                let snapshot = self.snapshot.lock().expect("status lock poisoned");
+                Some(Status::Snapshot(snapshot.clone()))
+            }
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
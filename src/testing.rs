@@ -0,0 +1,54 @@
+//! Simulated gates for the module tests.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::config::GateConfig;
+use crate::drive::Drive;
+use crate::gate::{GatemanRef, Sensors};
+use crate::hal::sim::{SimConfig, SimGate};
+use crate::interlock::Interlock;
+use crate::motion::MotionProfile;
+use crate::recipe::Recipe;
+use crate::status::Snapshot;
+
+/// The configuration of a gate calibrated to a travel of 4000 counts
+pub fn config() -> GateConfig {
+    let mut config = GateConfig::default();
+    config.calibration.travel = Some(4000);
+    config
+}
+
+/// A gate at `at` cruising at 2 kHz, with the named recipes
+pub fn start(
+    at: isize,
+    config: &GateConfig,
+    recipes: &BTreeMap<String, Recipe>,
+) -> (GatemanRef, SimGate) {
+    let gate = SimGate::new(at, SimConfig::default());
+    let profile = MotionProfile {
+        max_rate: 2000.0,
+        acceleration: 20000.0,
+        ..Default::default()
+    };
+    let gm = GatemanRef::new(
+        Drive::simulated(&gate, profile),
+        &Sensors::default(),
+        config,
+        recipes,
+        Interlock::default(),
+    );
+    (gm, gate)
+}
+
+/// Wait until the gate reaches a state, failing after thirty seconds
+pub async fn until(gm: &GatemanRef, done: impl Fn(&Snapshot) -> bool) -> Snapshot {
+    for _ in 0..3000 {
+        let snapshot = gm.snapshot();
+        if done(&snapshot) {
+            return snapshot;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("gate stuck at {:?}", gm.snapshot());
+}
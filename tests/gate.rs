@@ -0,0 +1,272 @@
+//! The gate actor driving a simulated gate.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use gateman::calibration::Opening;
+use gateman::config::GateConfig;
+use gateman::drive::Drive;
+use gateman::gate::{Command, GatemanRef, Mode, Sensors, State};
+use gateman::hal::sim::{SimConfig, SimGate};
+use gateman::interlock::Interlock;
+use gateman::motion::MotionProfile;
+use gateman::recipe::{Recipe, RunState, Step};
+use gateman::status::Snapshot;
+
+// a gate of 4000 counts at `at`, cruising at 1 kHz and slowing to 100 Hz
+// over the last few dozen counts of a move, as the default profile does
+fn start(at: isize, simulation: SimConfig, config: &str) -> (GatemanRef, SimGate) {
+    let mut config: GateConfig = toml::from_str(config).unwrap();
+    config.calibration.travel = Some(4000);
+    config.simulation = SimConfig {
+        max: 4000,
+        ..simulation
+    };
+    let gate = SimGate::new(at, config.simulation.clone());
+    let profile = MotionProfile {
+        max_rate: 1000.0,
+        acceleration: 20000.0,
+        ..Default::default()
+    };
+    let mut driver = Drive::simulated(&gate, profile);
+    driver.set_stall_detection(config.stall);
+    let gm = GatemanRef::new(
+        driver,
+        &Sensors::default(),
+        &config,
+        &BTreeMap::new(),
+        Interlock::default(),
+    );
+    (gm, gate)
+}
+
+// wait until the gate reaches a state, failing after ten seconds
+async fn until(gm: &GatemanRef, done: impl Fn(&Snapshot) -> bool) -> Snapshot {
+    for _ in 0..1000 {
+        let snapshot = gm.snapshot();
+        if done(&snapshot) {
+            return snapshot;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("gate stuck at {:?}", gm.snapshot());
+}
+
+// the encoder may run a count or two past a target
+fn near(position: isize, target: isize) -> bool {
+    (position - target).abs() <= 2
+}
+
+fn stopped(snapshot: &Snapshot) -> bool {
+    matches!(snapshot.state, State::Stopped(_))
+}
+
+fn moving(snapshot: &Snapshot) -> bool {
+    matches!(snapshot.state, State::Moving(_))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn moves_to_the_target() {
+    let (gm, gate) = start(0, SimConfig::default(), "");
+    gm.sender
+        .send(Command::Open(Opening::Counts(1000)))
+        .await
+        .unwrap();
+    until(&gm, moving).await;
+    let snapshot = until(&gm, stopped).await;
+    assert!(
+        near(snapshot.position, 1000),
+        "stopped at {}",
+        snapshot.position
+    );
+    assert!(near(gate.position(), 1000));
+
+    gm.sender.send(Command::Close).await.unwrap();
+    until(&gm, moving).await;
+    assert!(near(until(&gm, stopped).await.position, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_short_of_the_target() {
+    let (gm, gate) = start(0, SimConfig::default(), "");
+    gm.sender
+        .send(Command::Open(Opening::Counts(4000)))
+        .await
+        .unwrap();
+    until(&gm, |s| s.position > 200).await;
+    gm.sender.send(Command::Stop).await.unwrap();
+    let snapshot = until(&gm, stopped).await;
+    assert!(snapshot.position < 4000, "ran on to {}", snapshot.position);
+    // stopped at the actual encoder position, where the gate came to rest
+    assert!(matches!(snapshot.state, State::Stopped(at) if at == snapshot.position));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(gate.position(), snapshot.position);
+    assert_eq!(gm.snapshot().position, snapshot.position);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retargets_while_moving() {
+    let (gm, _gate) = start(0, SimConfig::default(), "");
+    gm.sender
+        .send(Command::Open(Opening::Counts(4000)))
+        .await
+        .unwrap();
+    until(&gm, |s| s.position > 200).await;
+    gm.sender
+        .send(Command::Open(Opening::Counts(600)))
+        .await
+        .unwrap();
+    let snapshot = until(&gm, stopped).await;
+    assert!(
+        near(snapshot.position, 600),
+        "stopped at {}",
+        snapshot.position
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn faults_when_the_gate_stalls() {
+    let jammed = SimConfig {
+        stall_at: Some(500),
+        ..Default::default()
+    };
+    let (gm, gate) = start(0, jammed, "");
+    gm.sender
+        .send(Command::Open(Opening::Counts(2000)))
+        .await
+        .unwrap();
+    let snapshot = until(&gm, |s| matches!(s.state, State::Stalled)).await;
+    assert!(snapshot.position <= 500);
+    assert!(near(gate.position(), 500));
+
+    // a stop leaves the stall latched
+    gm.sender.send(Command::Stop).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(gm.snapshot().state, State::Stalled));
+
+    // the gate can still be moved back off the jam
+    gm.sender.send(Command::ResetFault).await.unwrap();
+    gm.sender.send(Command::Close).await.unwrap();
+    until(&gm, moving).await;
+    assert!(near(until(&gm, stopped).await.position, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_safe_without_a_keep_alive() {
+    let (gm, _gate) = start(1000, SimConfig::default(), "safety.keepalive_timeout = 1");
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(gm.snapshot().position, 1000);
+    until(&gm, moving).await;
+    assert!(near(until(&gm, stopped).await.position, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alives_hold_off_the_failsafe() {
+    let (gm, _gate) = start(1000, SimConfig::default(), "safety.keepalive_timeout = 1");
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        gm.sender.send(Command::Nop).await.unwrap();
+    }
+    let snapshot = gm.snapshot();
+    assert!(
+        stopped(&snapshot) && snapshot.position == 1000,
+        "{:?}",
+        snapshot
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_hold_is_left_by_the_failsafe() {
+    let (gm, _gate) = start(1000, SimConfig::default(), "safety.keepalive_timeout = 1");
+    gm.sender
+        .send(Command::Hold(Some(Opening::Counts(600))))
+        .await
+        .unwrap();
+    let snapshot = until(&gm, |s| stopped(s) && near(s.position, 600)).await;
+    assert_eq!(snapshot.mode, Mode::Scheduled);
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    assert!(near(gm.snapshot().position, 600), "{:?}", gm.snapshot());
+
+    // a client command takes the gate back, and the failsafe with it
+    gm.sender.send(Command::Stop).await.unwrap();
+    until(&gm, moving).await;
+    let snapshot = until(&gm, stopped).await;
+    assert!(near(snapshot.position, 0), "{:?}", snapshot);
+    assert_eq!(snapshot.mode, Mode::Manual);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn keep_alives_during_a_move_count() {
+    // a move far longer than the keep-alive, pinged all the way
+    let (gm, _gate) = start(0, SimConfig::default(), "safety.keepalive_timeout = 1");
+    gm.sender
+        .send(Command::Open(Opening::Counts(4000)))
+        .await
+        .unwrap();
+    for _ in 0..10 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        gm.sender.send(Command::Nop).await.unwrap();
+        assert!(moving(&gm.snapshot()), "{:?}", gm.snapshot());
+    }
+    // and past its end, which would otherwise fire the failsafe
+    for _ in 0..20 {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        gm.sender.send(Command::Nop).await.unwrap();
+        if stopped(&gm.snapshot()) {
+            break;
+        }
+    }
+    let snapshot = gm.snapshot();
+    assert!(
+        stopped(&snapshot) && near(snapshot.position, 4000),
+        "{:?}",
+        snapshot
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fails_safe_during_a_move() {
+    // a move far longer than the keep-alive, with no client pinging
+    let (gm, _gate) = start(0, SimConfig::default(), "safety.keepalive_timeout = 1");
+    gm.sender
+        .send(Command::Open(Opening::Counts(4000)))
+        .await
+        .unwrap();
+    let snapshot = until(&gm, |s| matches!(s.state, State::Moving(0))).await;
+    assert!(snapshot.position < 2000, "{:?}", snapshot);
+    assert!(near(until(&gm, stopped).await.position, 0));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_recipe_moving_outside_the_soft_limits_ends() {
+    let (gm, _gate) = start(0, SimConfig::default(), "limits.max = 2000");
+    let recipe = |steps: Vec<Step>| Command::RunRecipe(Recipe { name: None, steps });
+    let aborted = |s: &Snapshot| {
+        s.mode == Mode::Manual
+            && s.recipe
+                .as_ref()
+                .is_some_and(|p| p.state == RunState::Aborted)
+    };
+
+    // a move step the soft limits refuse fails before the gate is sent anywhere
+    let steps = vec![Step::Move(Opening::Counts(3000))];
+    gm.sender.send(recipe(steps)).await.unwrap();
+    let snapshot = until(&gm, aborted).await;
+    assert!(
+        stopped(&snapshot) && snapshot.position == 0,
+        "{:?}",
+        snapshot
+    );
+
+    // and a move the actor refuses ends the recipe rather than leaving it waiting
+    let steps = vec![Step::Dwell { seconds: 60.0 }];
+    gm.sender.send(recipe(steps)).await.unwrap();
+    until(&gm, |s| matches!(s.mode, Mode::Recipe { .. })).await;
+    gm.sender.send(Command::Adjust(3000)).await.unwrap();
+    let snapshot = until(&gm, aborted).await;
+    assert!(
+        stopped(&snapshot) && snapshot.position == 0,
+        "{:?}",
+        snapshot
+    );
+}